@@ -0,0 +1,266 @@
+//! Measures how fast `VM::run_function` dispatches instructions, in the spirit of criterion. Each
+//! script is warmed up and timed over a number of samples, then reported as a confidence interval
+//! on its running time, along with the number of instructions it executes per second. Estimates
+//! are kept under `target/bench-baselines`, such that each run is compared with the previous one.
+//! Scripts are verified once, before being timed, such that only their execution is measured.
+//!
+//! Run with `cargo bench --bench dispatch`, followed by `-- --save-baseline <name>` to also keep
+//! the estimates under a name, or by `-- --baseline <name>` to compare with them instead. Any
+//! other argument only keeps the scripts whose name contains it.
+use mm::{Function, MMalis};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
+use std::{env, fs};
+
+// Number of untimed runs of each script, then of timed ones
+const WARM_UP_RUNS: usize = 3;
+const SAMPLES: usize = 30;
+
+// Relative change in running time under which two estimates are deemed the same
+const NOISE_THRESHOLD: f64 = 0.02;
+
+// Baseline each run compares with and then replaces, unless told otherwise
+const DEFAULT_BASELINE: &str = "base";
+
+const SCRIPTS: [(&str, &str); 6] = [
+    (
+        "counting loop",
+        "var total = 0;
+         for (var i = 0; i < 300000; i = i + 1) {
+           var j = i + 1;
+           total = total + j;
+         }",
+    ),
+    (
+        "nested loops",
+        "var total = 0;
+         for (var i = 0; i < 400; i = i + 1) {
+           for (var j = 0; j < 400; j = j + 1) {
+             var k = i + 2;
+             total = total + k * j;
+           }
+         }",
+    ),
+    (
+        "recursive calls",
+        "fun fib(n) {
+           if (n < 2) return n;
+           return fib(n - 1) + fib(n - 2);
+         }
+         var result = fib(22);",
+    ),
+    (
+        "globals",
+        "var i = 0;
+         var total = 0;
+         while (i < 200000) {
+           total = total + i * 2;
+           i = i + 1;
+         }",
+    ),
+    (
+        "branches",
+        "var even = 0;
+         var odd = 0;
+         for (var i = 0; i < 200000; i = i + 1) {
+           var flip = i / 2;
+           if (!(flip == 0) and i > 3) even = even + 1; else odd = odd + 1;
+         }",
+    ),
+    (
+        "string concatenation",
+        "var text = \"\";
+         for (var i = 0; i < 20000; i = i + 1) {
+           var piece = \"ab\" + \"cd\";
+           text = piece + \"!\";
+         }",
+    ),
+];
+
+// Running time of a script, in nanoseconds
+struct Estimate {
+    mean: f64,
+    // Bounds of the 95% confidence interval around the mean
+    low: f64,
+    high: f64,
+}
+
+impl Estimate {
+    fn from_samples(samples: &[f64]) -> Self {
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f64>()
+            / (count - 1.0);
+        let margin = 1.96 * (variance / count).sqrt();
+        Self {
+            mean,
+            low: mean - margin,
+            high: mean + margin,
+        }
+    }
+}
+
+// Number of instructions `script` executes, which does not depend on how they are dispatched
+fn count_instructions(script: &Rc<Function>) -> u64 {
+    let mut malis = MMalis::new();
+    malis.vm().set_count_instructions(true);
+    malis
+        .vm()
+        .run_function(Rc::clone(script))
+        .expect("Benchmarks run");
+    malis.vm().instruction_count()
+}
+
+fn measure(script: &Rc<Function>) -> Estimate {
+    let run = || {
+        let mut malis = MMalis::new();
+        let script = Rc::clone(black_box(script));
+        let start = Instant::now();
+        malis.vm().run_function(script).expect("Benchmarks run");
+        start.elapsed().as_nanos() as f64
+    };
+    for _ in 0..WARM_UP_RUNS {
+        run();
+    }
+    let samples: Vec<f64> = (0..SAMPLES).map(|_| run()).collect();
+    Estimate::from_samples(&samples)
+}
+
+fn format_time(nanos: f64) -> String {
+    match nanos {
+        nanos if nanos >= 1e9 => format!("{:.3} s", nanos / 1e9),
+        nanos if nanos >= 1e6 => format!("{:.3} ms", nanos / 1e6),
+        nanos if nanos >= 1e3 => format!("{:.3} µs", nanos / 1e3),
+        nanos => format!("{nanos:.3} ns"),
+    }
+}
+
+// Instructions per second when executing `count` of them in `nanos`, in millions
+fn format_throughput(count: u64, nanos: f64) -> String {
+    format!("{:.2} Minstr/s", count as f64 / nanos * 1e3)
+}
+
+fn baseline_path(name: &str) -> PathBuf {
+    let target = env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target"));
+    target
+        .join("bench-baselines")
+        .join("dispatch")
+        .join(format!("{name}.tsv"))
+}
+
+// Mean running time of each script in the baseline, if it was saved
+fn load_baseline(name: &str) -> HashMap<String, f64> {
+    let Ok(contents) = fs::read_to_string(baseline_path(name)) else {
+        return HashMap::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (script, mean) = line.split_once('\t')?;
+            Some((script.to_string(), mean.parse().ok()?))
+        })
+        .collect()
+}
+
+// Updates the scripts that ran in the baseline, keeping the others as they were
+fn save_baseline(name: &str, estimates: &[(&str, Estimate)]) {
+    let mut baseline = load_baseline(name);
+    for (script, estimate) in estimates {
+        baseline.insert(script.to_string(), estimate.mean);
+    }
+    let mut lines: Vec<String> = baseline
+        .iter()
+        .map(|(script, mean)| format!("{script}\t{mean}\n"))
+        .collect();
+    lines.sort();
+
+    let path = baseline_path(name);
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, lines.concat()));
+    if let Err(err) = saved {
+        eprintln!("Could not save baseline to {}: {err}", path.display());
+    }
+}
+
+fn main() {
+    let mut save = None;
+    let mut compare = None;
+    let mut filter = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save-baseline" => save = args.next(),
+            "--baseline" => compare = args.next(),
+            // Cargo hands `--bench` to benchmarks not using the default harness
+            arg if arg.starts_with("--") => {}
+            _ => filter = Some(arg),
+        }
+    }
+    // Comparing with a named baseline leaves the default one alone
+    let (compare, save) = match compare {
+        Some(compare) => (compare, save),
+        None => (
+            DEFAULT_BASELINE.to_string(),
+            Some(save.unwrap_or_else(|| DEFAULT_BASELINE.to_string())),
+        ),
+    };
+    let baseline = load_baseline(&compare);
+
+    let mut estimates = Vec::new();
+    for (name, source) in SCRIPTS {
+        if filter
+            .as_ref()
+            .is_some_and(|filter| !name.contains(filter.as_str()))
+        {
+            continue;
+        }
+        let sequence = MMalis::compile(source.as_bytes()).expect("Benchmarks compile");
+        // Counting the instructions also verifies the script, which is then kept along with it
+        let script = Rc::new(Function::new(None, 0, sequence));
+        let count = count_instructions(&script);
+        let estimate = measure(&script);
+
+        println!("{name}");
+        println!(
+            "    time:   [{} {} {}]",
+            format_time(estimate.low),
+            format_time(estimate.mean),
+            format_time(estimate.high)
+        );
+        // A longer time means fewer instructions per second, so the bounds swap
+        println!(
+            "    thrpt:  [{} {} {}]",
+            format_throughput(count, estimate.high),
+            format_throughput(count, estimate.mean),
+            format_throughput(count, estimate.low)
+        );
+        if let Some(base) = baseline.get(name) {
+            let change = estimate.mean / base - 1.0;
+            let verdict = match change {
+                change if change.abs() < NOISE_THRESHOLD => "No change in performance detected.",
+                change if change < 0.0 => "Performance has improved.",
+                _ => "Performance has regressed.",
+            };
+            println!(
+                "    change: {:+.2}% time, {:+.2}% thrpt ({compare}). {verdict}",
+                change * 100.0,
+                (base / estimate.mean - 1.0) * 100.0
+            );
+        }
+        estimates.push((name, estimate));
+    }
+
+    if let Some(save) = save {
+        save_baseline(&save, &estimates);
+    }
+}
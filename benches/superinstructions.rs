@@ -0,0 +1,77 @@
+//! Compares running loop-heavy scripts optimized without superinstructions against the same scripts
+//! using them, in the plain `VM::run_function` loop. Both are optimized the same way otherwise, so
+//! the difference comes from the superinstructions. Run with `cargo bench --bench superinstructions`.
+use mm::{Function, MMalis, Sequence};
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Number of timed runs of each script, the median of which is reported
+const RUNS: usize = 15;
+
+const SCRIPTS: [(&str, &str); 3] = [
+    (
+        "counting loop",
+        "var total = 0;
+         for (var i = 0; i < 200000; i = i + 1) {
+           var j = i + 1;
+           total = total + j;
+         }",
+    ),
+    (
+        "nested loops",
+        "var total = 0;
+         for (var i = 0; i < 300; i = i + 1) {
+           for (var j = 0; j < 300; j = j + 1) {
+             var k = i + 2;
+             total = total + k * j;
+           }
+         }",
+    ),
+    (
+        "function calls",
+        "fun sum(n) {
+           var total = 0;
+           for (var i = 0; i < n; i = i + 1) total = total + i;
+           return total;
+         }
+         var total = 0;
+         for (var i = 0; i < 2000; i = i + 1) total = total + sum(50);",
+    ),
+];
+
+// Median time it takes to run `sequence` in a fresh session. The script is verified by an untimed
+// first run, and kept verified for the timed ones
+fn time(sequence: Sequence) -> Duration {
+    let script = Rc::new(Function::new(None, 0, sequence));
+    let run = || {
+        let mut malis = MMalis::new();
+        let script = Rc::clone(black_box(&script));
+        let start = Instant::now();
+        malis.vm().run_function(script).expect("Benchmarks run");
+        start.elapsed()
+    };
+    run();
+    let mut times: Vec<Duration> = (0..RUNS).map(|_| run()).collect();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    println!(
+        "{:<16} {:>12} {:>12} {:>8}",
+        "script", "plain", "fused", "speedup"
+    );
+    for (name, source) in SCRIPTS {
+        let plain = MMalis::compile_without_superinstructions(source.as_bytes())
+            .expect("Benchmarks compile");
+        let fused = MMalis::compile(source.as_bytes()).expect("Benchmarks compile");
+        let (plain, fused) = (time(plain), time(fused));
+        println!(
+            "{name:<16} {:>12.2?} {:>12.2?} {:>7.2}x",
+            plain,
+            fused,
+            plain.as_secs_f64() / fused.as_secs_f64()
+        );
+    }
+}
@@ -6,8 +6,11 @@ use core::{
 };
 
 /// Maximum number of bytes that `MmAllocator` can allocate until it runs out of memory.
+// Unused until the allocator is registered as the `#[global_allocator]`, see `_ALLOCATOR`
+#[allow(dead_code)]
 const MEM_POOL_SIZE: usize = 256 * 10024;
 
+#[allow(dead_code)]
 pub struct MmAllocator {
     pool: UnsafeCell<[u8; MEM_POOL_SIZE]>,
     // We use `AtomicUsize` because we need to make sure that if 2 threads are trying to allocate
@@ -0,0 +1,755 @@
+//! Assembles the textual form of bytecode into a `Sequence`, which is the inverse of
+//! `Disassembler::to_assembly`, as printed by `malis dis --asm`. Sources hold one statement per
+//! line, and `;` starts a comment:
+//!
+//! ```text
+//! .line 1
+//!     OP_CONSTANT 1.2
+//! loop:
+//!     OP_GET_GLOBAL "i"
+//!     OP_JUMP_IF_FALSE done
+//!     OP_POP
+//!     OP_LOOP loop
+//! done:
+//!     OP_RETURN
+//! ```
+//!
+//! Instructions are written with their mnemonic, followed by their operands:
+//!
+//! - constants are either a literal, which is added to the constant pool unless the pool already
+//!   holds it, or `#N` to refer to the constant found at index `N`. Literals are `nil`, `true`,
+//!   `false`, numbers, strings in double quotes and `<fn name>` to refer to the latest function
+//!   declared with `.function`
+//! - jumps are given the label of their destination, declared as `label:`
+//! - any other operand is a byte, such as a local slot or a number of arguments
+//!
+//! Directives start with a dot:
+//!
+//! - `.line LINE [COLUMN [FILE]]` sets the source location of the following bytes, which starts
+//!   at line 1 without a column
+//! - `.byte N...` emits raw bytes
+//! - `.const LITERAL` appends a constant to the pool without referring to it, even if the pool
+//!   already holds it
+//! - `.function NAME ARITY` starts the body of a function, up to `.end`. `-` stands for a
+//!   function without a name
+//!
+//! Sources starting with a `== name ==` header are instead read as the listing printed by
+//! `malis dis` and `Disassembler::write_function`, which gives back the code, its lines and the
+//! functions listed after the script. Listings leave out columns and files, along with the
+//! constants no instruction uses, which are assembled as `nil`.
+use crate::object::{Function, Obj};
+use crate::{Location, OpCode, Sequence, Value};
+use std::{collections::HashMap, error::Error, fmt, rc::Rc};
+
+pub struct Assembler;
+
+impl Assembler {
+    /// Assembles `source` into the sequence it describes
+    pub fn assemble(source: &str) -> Result<Sequence, AssembleError> {
+        let first = source.lines().find(|text| !text.trim().is_empty());
+        if first.is_some_and(|text| text.starts_with("== ")) {
+            return assemble_listing(source);
+        }
+        // Code being assembled, starting with the top level and followed by the functions being
+        // declared in it
+        let mut blocks = vec![Block::new(None)];
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx + 1;
+            let error = |message: String| AssembleError { line, message };
+            let tokens = tokenize(text).map_err(error)?;
+            let mut tokens = tokens.as_slice();
+
+            let block = blocks
+                .last_mut()
+                .expect("The top level block is never popped");
+            if let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+                let offset = block.sequence.code().len();
+                if block.labels.insert(label.to_string(), offset).is_some() {
+                    return Err(error(format!("Label '{label}' is already declared.")));
+                }
+                tokens = &tokens[1..];
+            }
+            let Some((first, operands)) = tokens.split_first() else {
+                continue;
+            };
+
+            match *first {
+                ".function" => {
+                    let [name, arity] = operands else {
+                        return Err(error(
+                            "Expect a name and an arity after '.function'.".into(),
+                        ));
+                    };
+                    let name = (*name != "-").then(|| name.to_string());
+                    let arity = arity
+                        .parse()
+                        .map_err(|_| error(format!("Invalid arity '{arity}'.")))?;
+                    blocks.push(Block::new(Some((name, arity, line))));
+                }
+                ".end" => {
+                    if !operands.is_empty() {
+                        return Err(error("Unexpected operand after '.end'.".into()));
+                    }
+                    let Some((name, arity, _)) = block.function.clone() else {
+                        return Err(error("'.end' without a '.function'.".into()));
+                    };
+                    let block = blocks.pop().expect("Checked above");
+                    let sequence = block.finish()?;
+                    let function = Rc::new(Function::new(name, arity, sequence));
+                    blocks
+                        .last_mut()
+                        .expect("Function blocks are nested in the top level block")
+                        .functions
+                        .push(function);
+                }
+                directive if directive.starts_with('.') => {
+                    block.directive(directive, operands).map_err(error)?
+                }
+                mnemonic => {
+                    let Some(opcode) = OpCode::from_mnemonic(mnemonic) else {
+                        return Err(error(format!("Unknown instruction '{mnemonic}'.")));
+                    };
+                    block.instruction(opcode, operands, line).map_err(error)?
+                }
+            }
+        }
+
+        let block = blocks.pop().expect("The top level block is never popped");
+        if let Some((_, _, line)) = block.function {
+            return Err(AssembleError {
+                line,
+                message: "Missing '.end' for the function.".into(),
+            });
+        }
+        block.finish()
+    }
+}
+
+// Code of the script or of a function, while it is assembled
+struct Block {
+    sequence: Sequence,
+    // Location the next bytes are attributed to
+    location: Location,
+    // Offset of each label declared in the code
+    labels: HashMap<String, usize>,
+    // Jumps waiting for the offset of their label, as the offset of the jump, the label and the
+    // line of the source it was written on
+    jumps: Vec<(usize, String, usize)>,
+    // Functions declared in the code, which constants can refer to
+    functions: Vec<Rc<Function>>,
+    // Name, arity and line of the `.function` directive, `None` for the top level
+    function: Option<(Option<String>, usize, usize)>,
+}
+
+impl Block {
+    fn new(function: Option<(Option<String>, usize, usize)>) -> Self {
+        Self {
+            sequence: Sequence::new(),
+            location: Location::from(1),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+            functions: Vec::new(),
+            function,
+        }
+    }
+
+    fn directive(&mut self, directive: &str, operands: &[&str]) -> Result<(), String> {
+        match (directive, operands) {
+            (".line", [_] | [_, _] | [_, _, _]) => {
+                let mut numbers = operands.iter().map(|operand| {
+                    operand
+                        .parse()
+                        .map_err(|_| format!("Invalid location '{operand}'."))
+                });
+                let line = numbers.next().expect("Matched above")?;
+                let column = numbers.next().transpose()?.unwrap_or(0);
+                self.location = Location::new(line, column);
+                if let Some(file) = numbers.next().transpose()? {
+                    self.location = self.location.with_file(file);
+                }
+            }
+            (".byte", [_, ..]) => {
+                for byte in operands {
+                    self.push(parse_byte(byte)?);
+                }
+            }
+            (".const", [literal]) => match self.constant(literal)? {
+                Constant::Literal(value) => {
+                    self.sequence.push_constant(value);
+                }
+                _ => return Err("Expect a literal after '.const'.".into()),
+            },
+            (".line" | ".byte" | ".const", _) => {
+                return Err(format!("Wrong number of operands for '{directive}'."));
+            }
+            _ => return Err(format!("Unknown directive '{directive}'.")),
+        }
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        opcode: OpCode,
+        operands: &[&str],
+        line: usize,
+    ) -> Result<(), String> {
+        let expected = match opcode {
+            OpCode::Unknown(_) => unreachable!("Mnemonics only name known instructions"),
+            OpCode::Invoke | OpCode::IncrementLocal => 2,
+            OpCode::JumpIfNotLessLocal => 3,
+            _ => opcode.operand_len().min(1),
+        };
+        if operands.len() != expected {
+            return Err(format!(
+                "'{}' takes {expected} operands, found {}.",
+                opcode.mnemonic(),
+                operands.len()
+            ));
+        }
+
+        let offset = self.sequence.code().len();
+        self.push(opcode);
+        match opcode {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::Invoke
+            | OpCode::AddConstant
+            | OpCode::IncrementLocal
+            | OpCode::JumpIfNotLessLocal => {
+                let idx = self.constant_index(operands[0])?;
+                let idx = u8::try_from(idx).map_err(|_| {
+                    format!("Constant index {idx} does not fit in 1 byte, use OP_CONSTANT_LONG.")
+                })?;
+                self.push(idx);
+                if operands.len() > 1 {
+                    self.push(parse_byte(operands[1])?);
+                }
+            }
+            OpCode::ConstantLong => {
+                let idx = self.constant_index(operands[0])?;
+                if idx > 0xff_ffff {
+                    return Err(format!("Constant index {idx} does not fit in 3 bytes."));
+                }
+                for byte in idx.to_le_bytes().iter().take(3) {
+                    self.push(*byte);
+                }
+            }
+            OpCode::Call | OpCode::GetLocal | OpCode::SetLocal => {
+                self.push(parse_byte(operands[0])?)
+            }
+            _ => {}
+        }
+        if opcode.is_jump() {
+            // The offset is patched once every label is known
+            self.push(0xff);
+            self.push(0xff);
+            let label = operands.last().expect("Jumps take a label");
+            self.jumps.push((offset, label.to_string(), line));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, byte: impl TryInto<u8>) {
+        // Bytes and opcodes are always encoded as a byte, and sources are far from holding the
+        // 4 GiB of code a sequence is limited to
+        let _ = self.sequence.push(byte, self.location);
+    }
+
+    // Index of the constant an operand refers to, adding it to the pool if it is a literal
+    fn constant_index(&mut self, operand: &str) -> Result<usize, String> {
+        match self.constant(operand)? {
+            Constant::Literal(value) => Ok(self.sequence.add_constant(value)),
+            Constant::Index(idx) if idx < self.sequence.constants().len() => Ok(idx),
+            Constant::Index(idx) => Err(format!("No constant at index {idx}.")),
+            Constant::Function(_) => unreachable!("Blocks look functions up"),
+        }
+    }
+
+    // Reads a constant operand, looking functions up among the ones declared so far
+    fn constant<'a>(&self, operand: &'a str) -> Result<Constant<'a>, String> {
+        match parse_constant(operand)? {
+            Constant::Function(name) => {
+                let function = self
+                    .functions
+                    .iter()
+                    .rev()
+                    .find(|function| function.name() == name)
+                    .ok_or_else(|| format!("No function {operand} is declared."))?;
+                let value = Value::from(Obj::Function(Rc::clone(function)));
+                Ok(Constant::Literal(value))
+            }
+            constant => Ok(constant),
+        }
+    }
+
+    // Patches the jumps now that every label is known
+    fn finish(mut self) -> Result<Sequence, AssembleError> {
+        for (offset, label, line) in &self.jumps {
+            let error = |message: String| AssembleError {
+                line: *line,
+                message,
+            };
+            let Some(&target) = self.labels.get(label) else {
+                return Err(error(format!("Unknown label '{label}'.")));
+            };
+            patch_jump(&mut self.sequence, *offset, target)
+                .map_err(|err| error(format!("Label '{label}' is {err}.")))?;
+        }
+        Ok(self.sequence)
+    }
+}
+
+// Maximum depth of functions declared in functions, which the sections of a listing are linked
+// into recursively
+const NESTING_MAX: usize = 256;
+
+// Reads the listing printed by `Disassembler::write_sequence` or `Disassembler::write_function`
+fn assemble_listing(source: &str) -> Result<Sequence, AssembleError> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let error = |message: String| AssembleError { line, message };
+        // The source lines shown above their instructions are indented, unlike anything else
+        if text.trim().is_empty() || text.starts_with(char::is_whitespace) {
+            continue;
+        }
+        if let Some(header) = text
+            .strip_prefix("== ")
+            .and_then(|text| text.strip_suffix(" =="))
+        {
+            sections.push(Section::new(header, line).map_err(error)?);
+            continue;
+        }
+        let section = sections
+            .last_mut()
+            .ok_or_else(|| error("Expect a '== name ==' header.".into()))?;
+        section.read(text, line).map_err(error)?;
+    }
+
+    let mut sections = sections.into_iter();
+    let script = sections.next().expect("Listings start with a header");
+    let sequence = script.link(&mut sections, 0)?;
+    // Sections are only linked to the code referring to them
+    if let Some(section) = sections.next() {
+        return Err(AssembleError {
+            line: section.line,
+            message: format!("No code refers to {}.", section.name),
+        });
+    }
+    Ok(sequence)
+}
+
+// Code of the script or of a function, as read from a listing
+struct Section {
+    // Name and arity given by the header, found on `line` of the listing. Only functions show
+    // their arity
+    name: String,
+    arity: Option<usize>,
+    line: usize,
+    // Code and lines of the listed instructions, without constants
+    sequence: Sequence,
+    // Constant pool, which the line below the header gives the size of. Constants are listed
+    // along with the instructions using them, and the others are missing
+    constants: Option<Vec<Option<Listed>>>,
+    // Location of the last instruction, which the next one keeps when it shows `|` as its line
+    location: Option<Location>,
+    // Jumps, as the offset of the jump, the offset it lands on and the line of the listing
+    jumps: Vec<(usize, isize, usize)>,
+}
+
+// Constant read from a listing
+#[derive(Clone)]
+enum Listed {
+    Value(Value),
+    // Function listed in a later section, as its name and the line referring to it
+    Function(Option<String>, usize),
+}
+
+impl Section {
+    fn new(header: &str, line: usize) -> Result<Self, String> {
+        let (name, arity) = match header
+            .strip_suffix(')')
+            .and_then(|header| header.rsplit_once(" (arity "))
+        {
+            Some((name, arity)) => {
+                let arity = arity
+                    .parse()
+                    .map_err(|_| format!("Invalid arity '{arity}'."))?;
+                (name, Some(arity))
+            }
+            None => (header, None),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            arity,
+            line,
+            sequence: Sequence::new(),
+            constants: None,
+            location: None,
+            jumps: Vec::new(),
+        })
+    }
+
+    // Reads a line found below the header: the summary of the constant pool, followed by the
+    // instructions
+    fn read(&mut self, text: &str, line: usize) -> Result<(), String> {
+        if self.constants.is_none() {
+            let size = text
+                .split_once(" constants")
+                .and_then(|(size, _)| size.parse::<usize>().ok())
+                .ok_or_else(|| format!("Expect the number of constants, found '{text}'."))?;
+            // Instructions refer to constants with at most 3 bytes
+            if size > 1 << 24 {
+                return Err(format!("Too many constants: {size}."));
+            }
+            self.constants = Some(vec![None; size]);
+            return Ok(());
+        }
+
+        // Instructions start with their offset and line, which is `|` when it is the same as
+        // the previous instruction's
+        let columns = text.split_once(' ').and_then(|(offset, rest)| {
+            let (line, instruction) = rest.trim_start().split_once(' ')?;
+            Some((offset, line, instruction.trim()))
+        });
+        let Some((offset, source_line, instruction)) = columns else {
+            return Err(format!("Expect an instruction, found '{text}'."));
+        };
+        let expected = self.sequence.code().len();
+        if offset.parse() != Ok(expected) {
+            return Err(format!("Expect offset {expected:04}, found '{offset}'."));
+        }
+        self.location = match source_line {
+            "|" => self.location,
+            _ => {
+                let source_line: u32 = source_line
+                    .parse()
+                    .map_err(|_| format!("Invalid line '{source_line}'."))?;
+                Some(Location::from(source_line))
+            }
+        };
+        if self.location.is_none() {
+            return Err("Expect the line of the first instruction.".into());
+        }
+        self.instruction(instruction, line)
+    }
+
+    // Reads an instruction as `DecodedInstruction` displays it
+    fn instruction(&mut self, text: &str, line: usize) -> Result<(), String> {
+        let (mnemonic, mut operands) = text.split_once(' ').unwrap_or((text, ""));
+        if mnemonic == "Unknown" {
+            let byte = operands
+                .strip_prefix("opcode ")
+                .ok_or_else(|| format!("Unknown instruction '{text}'."))?;
+            self.push(parse_byte(byte)?);
+            return Ok(());
+        }
+        let Some(opcode) = OpCode::from_mnemonic(mnemonic) else {
+            return Err(format!("Unknown instruction '{mnemonic}'."));
+        };
+        if operands == "<truncated>" {
+            return Err(format!("The operands of '{mnemonic}' are not listed."));
+        }
+        let invalid = || format!("Invalid operands in '{text}'.");
+
+        // Jumps end with their own offset and the one they land on, after the constant of
+        // `OP_JUMP_IF_NOT_LESS_LOCAL`
+        let mut target = None;
+        if opcode.is_jump() {
+            let (rest, landing) = operands.rsplit_once(" -> ").ok_or_else(invalid)?;
+            target = Some(landing.parse::<isize>().map_err(|_| invalid())?);
+            operands = rest.rsplit_once(", ").map_or("", |(rest, _)| rest);
+        }
+        let tokens = tokenize(operands)?;
+        // Methods show their number of arguments and superinstructions their local slot, ahead
+        // of the constant
+        let (byte, tokens) = match (opcode, tokens.as_slice()) {
+            (OpCode::Invoke, [count, "args)", tokens @ ..]) => (count.strip_prefix('('), tokens),
+            (OpCode::IncrementLocal | OpCode::JumpIfNotLessLocal, ["(slot", slot, tokens @ ..]) => {
+                (slot.strip_suffix(')'), tokens)
+            }
+            (OpCode::Invoke | OpCode::IncrementLocal | OpCode::JumpIfNotLessLocal, _) => {
+                return Err(invalid());
+            }
+            (_, tokens) => (None, tokens),
+        };
+
+        let offset = self.sequence.code().len();
+        self.push(opcode);
+        match (opcode, tokens) {
+            (
+                OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::GetGlobal
+                | OpCode::DefineGlobal
+                | OpCode::SetGlobal
+                | OpCode::Invoke
+                | OpCode::AddConstant
+                | OpCode::IncrementLocal
+                | OpCode::JumpIfNotLessLocal,
+                [idx, "->", "value:", _] | [idx, "->", "no", "constant"],
+            ) => {
+                let idx: usize = idx.parse().map_err(|_| invalid())?;
+                let len = if opcode == OpCode::ConstantLong { 3 } else { 1 };
+                if idx >= 1 << (8 * len) {
+                    return Err(format!("Constant index {idx} does not fit in {len} bytes."));
+                }
+                for byte in idx.to_le_bytes().iter().take(len) {
+                    self.push(*byte);
+                }
+                if let [_, _, "value:", value] = tokens {
+                    self.constant(idx, value, line)?;
+                }
+            }
+            (OpCode::Call | OpCode::GetLocal | OpCode::SetLocal, [byte]) => {
+                self.push(parse_byte(byte)?)
+            }
+            // Jumps have no operands but the offset they land on
+            (_, []) if opcode.operand_len() == if opcode.is_jump() { 2 } else { 0 } => {}
+            _ => return Err(invalid()),
+        }
+        if let Some(byte) = byte {
+            self.push(parse_byte(byte)?);
+        }
+        if let Some(target) = target {
+            // The offset is patched once the whole code is known
+            self.push(0xff);
+            self.push(0xff);
+            self.jumps.push((offset, target, line));
+        }
+        Ok(())
+    }
+
+    // Records the constant found at `idx` of the pool
+    fn constant(&mut self, idx: usize, operand: &str, line: usize) -> Result<(), String> {
+        let constants = self
+            .constants
+            .as_mut()
+            .expect("Instructions follow the pool size");
+        let constant = constants
+            .get_mut(idx)
+            .ok_or_else(|| format!("No constant at index {idx}."))?;
+        *constant = Some(match parse_constant(operand)? {
+            Constant::Literal(value) => Listed::Value(value),
+            Constant::Function(name) => Listed::Function(name.map(str::to_string), line),
+            Constant::Index(_) => return Err(format!("Invalid constant '{operand}'.")),
+        });
+        Ok(())
+    }
+
+    fn push(&mut self, byte: impl TryInto<u8>) {
+        let location = self.location.expect("Instructions have a line");
+        let _ = self.sequence.push(byte, location);
+    }
+
+    // Builds the sequence, along with the functions found in its constant pool. Their own code
+    // is listed in the `sections` that follow, in the order of the pool
+    fn link(
+        mut self,
+        sections: &mut impl Iterator<Item = Section>,
+        depth: usize,
+    ) -> Result<Sequence, AssembleError> {
+        let len = self.sequence.code().len();
+        for (offset, target, line) in &self.jumps {
+            let error = |message: String| AssembleError {
+                line: *line,
+                message,
+            };
+            let target = usize::try_from(*target)
+                .ok()
+                .filter(|target| *target <= len)
+                .ok_or_else(|| error(format!("Offset {target} is outside of the code.")))?;
+            patch_jump(&mut self.sequence, *offset, target)
+                .map_err(|err| error(format!("Offset {target:04} is {err}.")))?;
+        }
+
+        let mut constants = Vec::new();
+        for constant in self.constants.unwrap_or_default() {
+            let value = match constant {
+                // Constants no instruction uses are not listed, so something has to take their
+                // place
+                None => Value::nil(),
+                Some(Listed::Value(value)) => value,
+                Some(Listed::Function(name, line)) => {
+                    let error = |message: String| AssembleError { line, message };
+                    let listed = match &name {
+                        Some(name) => format!("<fn {name}>"),
+                        None => "<script>".to_string(),
+                    };
+                    if depth == NESTING_MAX {
+                        return Err(error("Functions are nested too deeply.".into()));
+                    }
+                    let section = sections
+                        .next()
+                        .filter(|section| section.name == listed)
+                        .ok_or_else(|| {
+                            error(format!("The code of {listed} is not listed next."))
+                        })?;
+                    let arity = section.arity.ok_or_else(|| AssembleError {
+                        line: section.line,
+                        message: format!("Expect the arity of {listed} in its header."),
+                    })?;
+                    let sequence = section.link(sections, depth + 1)?;
+                    Value::from(Obj::Function(Rc::new(Function::new(name, arity, sequence))))
+                }
+            };
+            constants.push(value);
+        }
+        let code = self.sequence.code().to_vec();
+        let locations = self.sequence.locations().clone();
+        Ok(Sequence::from_parts(code, locations, constants))
+    }
+}
+
+enum Constant<'a> {
+    Literal(Value),
+    Index(usize),
+    // Function written `<fn name>`, or `<script>` for one without a name
+    Function(Option<&'a str>),
+}
+
+// Reads a constant operand, leaving functions for the caller to find
+fn parse_constant(operand: &str) -> Result<Constant<'_>, String> {
+    let value = match operand {
+        "nil" => Value::nil(),
+        "true" => Value::from(true),
+        "false" => Value::from(false),
+        _ if operand.starts_with('#') => {
+            return operand[1..]
+                .parse()
+                .map(Constant::Index)
+                .map_err(|_| format!("Invalid constant index '{operand}'."));
+        }
+        _ if operand.starts_with('"') => Value::from(unescape(operand)?),
+        "<script>" => return Ok(Constant::Function(None)),
+        _ if operand.starts_with('<') => {
+            return operand
+                .strip_prefix("<fn ")
+                .and_then(|name| name.strip_suffix('>'))
+                .map(|name| Constant::Function(Some(name)))
+                .ok_or_else(|| format!("Invalid function '{operand}'."));
+        }
+        _ => match operand.parse::<f64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => return Err(format!("Invalid constant '{operand}'.")),
+        },
+    };
+    Ok(Constant::Literal(value))
+}
+
+// Patches the jump found at `offset` such that it lands on `target`. Jumps are relative to the
+// next instruction
+fn patch_jump(sequence: &mut Sequence, offset: usize, target: usize) -> Result<(), &'static str> {
+    let opcode = OpCode::from(sequence.code()[offset]);
+    let next = offset + 1 + opcode.operand_len();
+    let jump = match opcode {
+        OpCode::Loop => next.checked_sub(target),
+        _ => target.checked_sub(next),
+    };
+    let jump = jump.ok_or("in the wrong direction")?;
+    let jump = u16::try_from(jump).map_err(|_| "too far to jump to")?;
+    let [low, high] = jump.to_le_bytes();
+    sequence.patch(next - 2, low);
+    sequence.patch(next - 1, high);
+    Ok(())
+}
+
+// Splits a line into tokens separated by whitespace, up to the comment. Strings and function
+// literals are kept whole, although they may contain whitespace.
+fn tokenize(text: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() && !rest.starts_with(';') {
+        let end = if let Some(string) = rest.strip_prefix('"') {
+            let mut escaped = false;
+            let end = string.find(|c| {
+                let is_end = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                is_end
+            });
+            end.map(|end| end + 2)
+                .ok_or_else(|| "Unterminated string.".to_string())?
+        } else if rest.starts_with('<') {
+            rest.find('>')
+                .map(|end| end + 1)
+                .ok_or_else(|| "Unterminated function literal.".to_string())?
+        } else {
+            rest.find(|c: char| c.is_whitespace() || c == ';')
+                .unwrap_or(rest.len())
+        };
+        tokens.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Reads a string literal, replacing the escape sequences `Disassembler::to_assembly` writes
+fn unescape(literal: &str) -> Result<String, String> {
+    let mut string = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .and_then(|(code, _)| u32::from_str_radix(code, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("Invalid escape in {literal}."))?;
+                // Skip the braces along with the code point
+                let len = rest.find('}').expect("Found while parsing") + 1;
+                chars = rest[len..].chars();
+                code
+            }
+            _ => return Err(format!("Invalid escape in {literal}.")),
+        };
+        string.push(escaped);
+    }
+    Ok(string)
+}
+
+fn parse_byte(operand: &str) -> Result<u8, String> {
+    operand
+        .parse()
+        .map_err(|_| format!("Expect a byte operand, found '{operand}'."))
+}
+
+/// The assembly source is malformed
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    // Line of the source the error is on, starting at 1
+    line: usize,
+    message: String,
+}
+
+impl AssembleError {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for AssembleError {}
@@ -1,20 +1,281 @@
-use mm::MMalis;
+use mm::{
+    ControlFlowGraph, DapServer, Debugger, Disassembler, Function, InterpretError, MMalis,
+    MMalisError, Sequence, WriteTracer,
+};
+use std::{
+    fs,
+    io::{self, BufWriter},
+    path::Path,
+    process,
+    rc::Rc,
+};
+
+const USAGE: &str = "\
+Usage: malis [script]             run `script`, source or compiled, or start the prompt
+       malis run <script>         run `script`, even if it is named like a command, as does
+                                  `malis -- <script>`
+       malis compile <script> [-o <output>]
+                                  compile `script` to `output`, `script.msc` by default
+       malis dis [--cfg|--asm] <script>
+                                  disassemble `script`, showing the source above its bytecode,
+                                  export its control-flow graph in the DOT language, or write it
+                                  in the assembly read by `mm asm`
+       malis debug <script>       run `script` in the debugger, stopping before its first line
+       malis dap                  serve the Debug Adapter Protocol on the standard input and output
+
+Options:
+       --no-optimize              do not fold constant expressions nor optimize the bytecode
+       --trace[=<file>]           trace each instruction executed to the standard error, or to
+                                  `file`";
+
+// Exit codes, following the conventions of `sysexits.h`
+// The command line is not valid
+const EX_USAGE: i32 = 64;
+// The script does not compile
+const EX_DATAERR: i32 = 65;
+// The script failed while running
+const EX_SOFTWARE: i32 = 70;
+// An output file could not be created
+const EX_CANTCREAT: i32 = 73;
+// The script could not be read, or the prompt could not be written to
+const EX_IOERR: i32 = 74;
+
+fn exit_code(err: &MMalisError) -> i32 {
+    match err {
+        MMalisError::StdIO(_) => EX_IOERR,
+        MMalisError::InterpretError(
+            InterpretError::CompileError(_) | InterpretError::VerifyError(_),
+        )
+        | MMalisError::Format(_) => EX_DATAERR,
+        _ => EX_SOFTWARE,
+    }
+}
+
+// Reads the file at `path`, exiting if that is not possible
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("error: Cannot read `{path}`: {err}");
+        process::exit(EX_IOERR);
+    })
+}
+
+// Compiles `source`, optimizing it if `optimize` is set
+fn compile_source(source: &[u8], optimize: bool) -> Result<Sequence, MMalisError> {
+    if optimize {
+        MMalis::compile(source)
+    } else {
+        MMalis::compile_unoptimized(source)
+    }
+}
+
+// Options given on the command line
+struct Options {
+    optimize: bool,
+    // Whether to trace the execution, and to which file if not to the standard error
+    trace: Option<Option<String>>,
+}
+
+impl Options {
+    // Takes the options out of `args`, wherever they are up to `--`, after which every argument
+    // is a path
+    fn parse(args: &mut Vec<String>) -> Self {
+        let end = args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(args.len());
+        let options = &args[..end];
+        let optimize = !options.iter().any(|arg| arg == "--no-optimize");
+        let trace = options.iter().rev().find_map(|arg| match arg.as_str() {
+            "--trace" => Some(None),
+            arg => arg
+                .strip_prefix("--trace=")
+                .map(|path| Some(path.to_string())),
+        });
+        let paths = args.split_off(end);
+        args.retain(|arg| {
+            arg != "--no-optimize" && arg != "--trace" && !arg.starts_with("--trace=")
+        });
+        args.extend(paths);
+        Self { optimize, trace }
+    }
+
+    // Session running code as the options ask, exiting if the trace file cannot be created
+    fn session(&self) -> MMalis {
+        let mut malis = MMalis::new();
+        malis.set_optimize(self.optimize);
+        match &self.trace {
+            Some(Some(path)) => match WriteTracer::file(path) {
+                Ok(tracer) => malis.vm().set_tracer(tracer),
+                Err(err) => {
+                    eprintln!("error: Cannot create `{path}`: {err}");
+                    process::exit(EX_CANTCREAT);
+                }
+            },
+            Some(None) => malis.vm().set_trace_execution(true),
+            None => {}
+        }
+        malis
+    }
+}
+
+fn run(path: &str, options: &Options) {
+    let bytes = read(path);
+    let mut malis = options.session();
+    if let Err(err) = malis.execute_bytes(&bytes) {
+        eprint!("{}", err.render(path, &bytes));
+        // Dropping the session flushes the trace, which exiting would not
+        drop(malis);
+        process::exit(exit_code(&err));
+    }
+}
+
+fn compile(args: &[String], optimize: bool) {
+    let (input, output) = match args {
+        [input] => (input, Path::new(input).with_extension("msc")),
+        [input, flag, output] if flag == "-o" => (input, output.into()),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(EX_USAGE);
+        }
+    };
+    let source = read(input);
+    let sequence = compile_source(&source, optimize).unwrap_or_else(|err| {
+        eprint!("{}", err.render(input, &source));
+        process::exit(exit_code(&err));
+    });
+
+    let file = fs::File::create(&output).unwrap_or_else(|err| {
+        eprintln!("error: Cannot create `{}`: {err}", output.display());
+        process::exit(EX_CANTCREAT);
+    });
+    if let Err(err) = sequence.write_to(BufWriter::new(file)) {
+        let err = MMalisError::from(err);
+        eprintln!("error: Cannot write `{}`: {err}", output.display());
+        process::exit(exit_code(&err));
+    }
+}
+
+fn dis(args: &[String], optimize: bool) {
+    let (path, format) = match args {
+        [path] => (path, None),
+        [flag, path] | [path, flag] if flag == "--cfg" || flag == "--asm" => {
+            (path, Some(flag.as_str()))
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(EX_USAGE);
+        }
+    };
+    let bytes = read(path);
+    let fail = |err: MMalisError| -> ! {
+        eprint!("{}", err.render(path, &bytes));
+        process::exit(exit_code(&err));
+    };
+
+    // Compiled files do not hold their source, so only their bytecode is shown
+    let (sequence, source) = if Sequence::is_compiled(&bytes) {
+        let sequence = Sequence::read_from(bytes.as_slice()).unwrap_or_else(|err| fail(err.into()));
+        (sequence, None)
+    } else {
+        (
+            compile_source(&bytes, optimize).unwrap_or_else(|err| fail(err)),
+            Some(&bytes),
+        )
+    };
+    let script = Function::new(None, 0, sequence);
+
+    match format {
+        Some("--cfg") => {
+            print!("{}", ControlFlowGraph::function_to_dot(&script));
+            return;
+        }
+        Some(_) => {
+            print!("{}", Disassembler::to_assembly(script.sequence()));
+            return;
+        }
+        None => {}
+    }
+
+    let mut out = String::new();
+    // Writing to a `String` cannot fail
+    let _ = match source {
+        Some(source) => Disassembler::write_annotated(&mut out, &script, source),
+        None => Disassembler::write_function(&mut out, &script),
+    };
+    print!("{out}");
+}
+
+fn debug(args: &[String], options: &Options) {
+    // The debugger takes the place of the tracer, and always runs unoptimized code
+    let ([path], None) = (args, &options.trace) else {
+        eprintln!("{USAGE}");
+        process::exit(EX_USAGE);
+    };
+    let bytes = read(path);
+    let sequence = MMalis::compile_for_debugging(&bytes).unwrap_or_else(|err| {
+        eprint!("{}", err.render(path, &bytes));
+        process::exit(exit_code(&err));
+    });
+    let mut malis = MMalis::new();
+    let debugger = Debugger::new(io::stdin().lock(), io::stdout());
+    if Sequence::is_compiled(&bytes) {
+        malis.vm().set_tracer(debugger);
+    } else {
+        malis.vm().set_tracer(debugger.with_source(&bytes));
+    }
+
+    let script = Rc::new(Function::new(None, 0, sequence));
+    match malis.vm().run_function(script).map_err(MMalisError::from) {
+        // Quitting the debugger stops the script on purpose
+        Ok(_) | Err(MMalisError::InterpretError(InterpretError::Interrupted)) => {}
+        Err(err) => {
+            eprint!("{}", err.render(path, &bytes));
+            process::exit(exit_code(&err));
+        }
+    }
+}
+
+fn dap(args: &[String], options: &Options) {
+    // Like the debugger, the server takes the place of the tracer
+    if !args.is_empty() || options.trace.is_some() {
+        eprintln!("{USAGE}");
+        process::exit(EX_USAGE);
+    }
+    let server = DapServer::new(io::stdin().lock(), io::stdout().lock());
+    if let Err(err) = server.run() {
+        eprintln!("error: {err}");
+        process::exit(EX_IOERR);
+    }
+}
 
 fn main() {
-    let mut args = std::env::args();
     // First arguments is always the current binary's path, which we do not need
-    let _ = args.next();
-
-    match args.next() {
-        // If we do have a second argument, we execute it
-        Some(arg) => {
-            let execution = MMalis::execute(&arg);
-            if let Err(e) = execution {
-                println!("{:?}", e);
-                std::process::exit(69);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // Options may be given anywhere on the command line
+    let options = Options::parse(&mut args);
+
+    match args.as_slice() {
+        // Without arguments, we enter interactive mode in the prompt
+        [] => {
+            if let Err(err) = options.session().run_prompt() {
+                eprintln!("error: {err}");
+                if let Some(source) = std::error::Error::source(&err) {
+                    eprintln!("caused by: {source}");
+                }
+                process::exit(exit_code(&err));
             }
         }
-        // If not, we enter interactive mode in the prompt
-        None => MMalis::interactive().expect("Failed to execut script"),
-    };
+        [command, args @ ..] if command == "compile" => compile(args, options.optimize),
+        [command, args @ ..] if command == "dis" => dis(args, options.optimize),
+        [command, args @ ..] if command == "debug" => debug(args, &options),
+        [command, args @ ..] if command == "dap" => dap(args, &options),
+        // Scripts named like a command are run explicitly
+        [command, path] if command == "run" || command == "--" => run(path, &options),
+        // If we do have a single argument, we execute it
+        [path] => run(path, &options),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(EX_USAGE);
+        }
+    }
 }
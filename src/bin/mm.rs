@@ -1,6 +1,71 @@
-use mm::{Disassembler, OpCode, Sequence, Value};
+use mm::{Assembler, Disassembler, OpCode, Sequence, Value};
+use std::{fs, io::BufWriter, path::Path, process};
+
+const USAGE: &str = "\
+Usage: mm                               disassemble sample sequences
+       mm asm <source> [-o <output>]    assemble `source` to `output`, `source.msc` by default
+
+The source is written in the assembly printed by `malis dis --asm`, or is the listing printed by
+`malis dis`. Listings leave out columns and files, along with the constants no instruction uses,
+which are assembled as `nil`.";
+
+// Exit codes, following the conventions of `sysexits.h`
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_CANTCREAT: i32 = 73;
+const EX_IOERR: i32 = 74;
+
+fn asm(args: &[String]) {
+    let (input, output) = match args {
+        [flag] if flag == "--help" || flag == "-h" => {
+            println!("{USAGE}");
+            return;
+        }
+        [input] => (input, Path::new(input).with_extension("msc")),
+        [input, flag, output] if flag == "-o" => (input, output.into()),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(EX_USAGE);
+        }
+    };
+    let source = fs::read_to_string(input).unwrap_or_else(|err| {
+        eprintln!("error: Cannot read `{input}`: {err}");
+        process::exit(EX_IOERR);
+    });
+    let sequence = Assembler::assemble(&source).unwrap_or_else(|err| {
+        eprintln!("error: {input}: {err}");
+        process::exit(EX_DATAERR);
+    });
+    // Hand-written code is easily wrong, so it is checked before the VM gets to run it
+    if let Err(err) = sequence.verify() {
+        eprintln!("error: {input}: {err}");
+        process::exit(EX_DATAERR);
+    }
+
+    let file = fs::File::create(&output).unwrap_or_else(|err| {
+        eprintln!("error: Cannot create `{}`: {err}", output.display());
+        process::exit(EX_CANTCREAT);
+    });
+    if let Err(err) = sequence.write_to(BufWriter::new(file)) {
+        eprintln!("error: Cannot write `{}`: {err}", output.display());
+        process::exit(EX_IOERR);
+    }
+}
 
 fn main() {
+    // First arguments is always the current binary's path, which we do not need
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => demo(),
+        [command, args @ ..] if command == "asm" => asm(args),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(EX_USAGE);
+        }
+    }
+}
+
+fn demo() {
     let mut seq = Sequence::new();
     // Create a new constant
     let constant = Value::from(1.2);
@@ -1,7 +1,9 @@
 //! Module storing the building blocks for sequence of `mm` bytecode
-use crate::value::{Value, ValueVec};
+use crate::object::Obj;
+use crate::value::{Value, ValueRef, ValueVec};
+use std::{collections::HashMap, fmt};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     // Return from function / call
     Return,
@@ -21,10 +23,150 @@ pub enum OpCode {
     Mul,
     // Corresponds to the plus `/` infix operator that divides 2 values
     Div,
+    // Produces the `nil` literal
+    Nil,
+    // Produces the `true` literal
+    True,
+    // Produces the `false` literal
+    False,
+    // Discards the value on top of the stack
+    Pop,
+    // Loads the global variable whose name is the constant with the index given by the next byte
+    GetGlobal,
+    // Defines a global variable whose name is the constant with the index given by the next byte,
+    // using the value on top of the stack
+    DefineGlobal,
+    // Assigns the value on top of the stack to an already defined global variable whose name is
+    // the constant with the index given by the next byte
+    SetGlobal,
+    // Corresponds to the `==` infix operator
+    Equal,
+    // Corresponds to the `>` infix operator
+    Greater,
+    // Corresponds to the `<` infix operator
+    Less,
+    // Corresponds to the `!` and `not` prefix operators
+    Not,
+    // Pops and prints the value on top of the stack
+    Print,
+    // Calls the value found below the arguments on the stack. The next byte holds the number of
+    // arguments
+    Call,
+    // Loads the local variable stored in the current call's stack slot given by the next byte
+    GetLocal,
+    // Assigns the value on top of the stack to the local variable stored in the current call's
+    // stack slot given by the next byte
+    SetLocal,
+    // Jumps forward by the 2-byte Little Endian offset following the opcode, if the value on top
+    // of the stack is falsey. The value is left on the stack
+    JumpIfFalse,
+    // Unconditionally jumps forward by the 2-byte Little Endian offset following the opcode
+    Jump,
+    // Unconditionally jumps backward by the 2-byte Little Endian offset following the opcode
+    Loop,
+    // Invokes a method on the receiver found below the arguments on the stack. The next byte is
+    // the index of the constant holding the method's name, followed by the number of arguments
+    Invoke,
+    // Adds the constant with the index given by the next byte to the value on top of the stack.
+    // Stands for `Constant` followed by `Add`
+    AddConstant,
+    // Loads the local variable stored in the current call's stack slot 0, 1, 2 or 3. They stand
+    // for `GetLocal` with these slots
+    GetLocal0,
+    GetLocal1,
+    GetLocal2,
+    GetLocal3,
+    // Adds the constant with the index given by the next byte to the local variable stored in the
+    // stack slot given by the byte after it, and loads the result. Stands for `GetLocal`,
+    // `Constant`, `Add` and `SetLocal` on the same slot, as in `i = i + 1`
+    IncrementLocal,
+    // Loads whether the local variable stored in the stack slot given by the second byte is less
+    // than the constant with the index given by the first byte, then jumps forward by the 2-byte
+    // Little Endian offset after them if it is not. Stands for `GetLocal`, `Constant`, `Less` and
+    // `JumpIfFalse`, as in the condition of `for (...; i < 10; ...)`
+    JumpIfNotLessLocal,
     // Unknown byte, kept for debugging
     Unknown(u8),
 }
 
+impl OpCode {
+    /// Name of the instruction, as printed by the disassembler and read by the assembler
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Return => "OP_RETURN",
+            Self::Constant => "OP_CONSTANT",
+            Self::ConstantLong => "OP_CONSTANT_LONG",
+            Self::Negate => "OP_NEGATE",
+            Self::Add => "OP_ADD",
+            Self::Sub => "OP_SUBTRACT",
+            Self::Mul => "OP_MULTIPLY",
+            Self::Div => "OP_DIVIDE",
+            Self::Nil => "OP_NIL",
+            Self::True => "OP_TRUE",
+            Self::False => "OP_FALSE",
+            Self::Pop => "OP_POP",
+            Self::GetGlobal => "OP_GET_GLOBAL",
+            Self::DefineGlobal => "OP_DEFINE_GLOBAL",
+            Self::SetGlobal => "OP_SET_GLOBAL",
+            Self::Equal => "OP_EQUAL",
+            Self::Greater => "OP_GREATER",
+            Self::Less => "OP_LESS",
+            Self::Not => "OP_NOT",
+            Self::Print => "OP_PRINT",
+            Self::Call => "OP_CALL",
+            Self::GetLocal => "OP_GET_LOCAL",
+            Self::SetLocal => "OP_SET_LOCAL",
+            Self::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            Self::Jump => "OP_JUMP",
+            Self::Loop => "OP_LOOP",
+            Self::Invoke => "OP_INVOKE",
+            Self::AddConstant => "OP_ADD_CONSTANT",
+            Self::GetLocal0 => "OP_GET_LOCAL_0",
+            Self::GetLocal1 => "OP_GET_LOCAL_1",
+            Self::GetLocal2 => "OP_GET_LOCAL_2",
+            Self::GetLocal3 => "OP_GET_LOCAL_3",
+            Self::IncrementLocal => "OP_INCREMENT_LOCAL",
+            Self::JumpIfNotLessLocal => "OP_JUMP_IF_NOT_LESS_LOCAL",
+            Self::Unknown(_) => "OP_UNKNOWN",
+        }
+    }
+
+    /// Returns the instruction called `mnemonic`, if there is one
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        (0..=u8::MAX)
+            .map(Self::from)
+            .take_while(|opcode| !matches!(opcode, Self::Unknown(_)))
+            .find(|opcode| opcode.mnemonic() == mnemonic)
+    }
+
+    /// Number of operand bytes following the opcode
+    pub fn operand_len(&self) -> usize {
+        match self {
+            Self::Constant
+            | Self::GetGlobal
+            | Self::DefineGlobal
+            | Self::SetGlobal
+            | Self::Call
+            | Self::GetLocal
+            | Self::SetLocal
+            | Self::AddConstant => 1,
+            Self::JumpIfFalse | Self::Jump | Self::Loop | Self::Invoke | Self::IncrementLocal => 2,
+            Self::ConstantLong => 3,
+            Self::JumpIfNotLessLocal => 4,
+            _ => 0,
+        }
+    }
+
+    /// Whether the instruction jumps by the 2-byte Little Endian offset ending its operands. The
+    /// offset is relative to the next instruction, and only `Loop` jumps backward
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self,
+            Self::JumpIfFalse | Self::Jump | Self::Loop | Self::JumpIfNotLessLocal
+        )
+    }
+}
+
 impl From<u8> for OpCode {
     fn from(value: u8) -> Self {
         match value {
@@ -36,6 +178,32 @@ impl From<u8> for OpCode {
             5 => Self::Sub,
             6 => Self::Mul,
             7 => Self::Div,
+            8 => Self::Nil,
+            9 => Self::True,
+            10 => Self::False,
+            11 => Self::Pop,
+            12 => Self::GetGlobal,
+            13 => Self::DefineGlobal,
+            14 => Self::SetGlobal,
+            15 => Self::Equal,
+            16 => Self::Greater,
+            17 => Self::Less,
+            18 => Self::Not,
+            19 => Self::Print,
+            20 => Self::Call,
+            21 => Self::GetLocal,
+            22 => Self::SetLocal,
+            23 => Self::JumpIfFalse,
+            24 => Self::Jump,
+            25 => Self::Loop,
+            26 => Self::Invoke,
+            27 => Self::AddConstant,
+            28 => Self::GetLocal0,
+            29 => Self::GetLocal1,
+            30 => Self::GetLocal2,
+            31 => Self::GetLocal3,
+            32 => Self::IncrementLocal,
+            33 => Self::JumpIfNotLessLocal,
             _ => Self::Unknown(value),
         }
     }
@@ -54,24 +222,360 @@ impl TryInto<u8> for OpCode {
             Self::Sub => Ok(5),
             Self::Mul => Ok(6),
             Self::Div => Ok(7),
+            Self::Nil => Ok(8),
+            Self::True => Ok(9),
+            Self::False => Ok(10),
+            Self::Pop => Ok(11),
+            Self::GetGlobal => Ok(12),
+            Self::DefineGlobal => Ok(13),
+            Self::SetGlobal => Ok(14),
+            Self::Equal => Ok(15),
+            Self::Greater => Ok(16),
+            Self::Less => Ok(17),
+            Self::Not => Ok(18),
+            Self::Print => Ok(19),
+            Self::Call => Ok(20),
+            Self::GetLocal => Ok(21),
+            Self::SetLocal => Ok(22),
+            Self::JumpIfFalse => Ok(23),
+            Self::Jump => Ok(24),
+            Self::Loop => Ok(25),
+            Self::Invoke => Ok(26),
+            Self::AddConstant => Ok(27),
+            Self::GetLocal0 => Ok(28),
+            Self::GetLocal1 => Ok(29),
+            Self::GetLocal2 => Ok(30),
+            Self::GetLocal3 => Ok(31),
+            Self::IncrementLocal => Ok(32),
+            Self::JumpIfNotLessLocal => Ok(33),
             Self::Unknown(value) => Ok(value),
         }
     }
 }
 
+/// Where in the source code a byte of a sequence comes from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    // Line, starting at 1. 0 stands for an unknown line
+    line: u32,
+    // Column in bytes, starting at 1. 0 stands for an unknown column
+    column: u32,
+    // Identifies the file the code comes from, for hosts compiling several files
+    file: Option<u32>,
+}
+
+impl Location {
+    pub fn new(line: u32, column: u32) -> Self {
+        Self {
+            line,
+            column,
+            file: None,
+        }
+    }
+
+    pub fn with_file(mut self, file: u32) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    pub fn file(&self) -> Option<u32> {
+        self.file
+    }
+}
+
+impl From<u32> for Location {
+    fn from(line: u32) -> Self {
+        Self::new(line, 0)
+    }
+}
+
+/// Where the bytes of a sequence come from in the source code. Lines are stored with a run-length
+/// encoding, where successive bytes from the same line only store the line once. Columns change
+/// at almost every instruction and files hardly ever, so they are kept apart from the line runs,
+/// as the offsets at which they change
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Locations {
+    // Each run of bytes from the same line, as the line followed by the offset right after the
+    // run's last byte, such that the run holding a byte can be found with a binary search
+    lines: Vec<(u32, u32)>,
+    // Offsets from which the bytes come from another column, along with that column. Bytes before
+    // the first of them have an unknown column
+    columns: Vec<(u32, u32)>,
+    // Offsets from which the bytes come from another file, along with that file
+    files: Vec<(u32, Option<u32>)>,
+}
+
+impl Locations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the locations out of their tables, as found in a compiled file
+    pub fn from_parts(
+        lines: Vec<(u32, u32)>,
+        columns: Vec<(u32, u32)>,
+        files: Vec<(u32, Option<u32>)>,
+    ) -> Self {
+        Self {
+            lines,
+            columns,
+            files,
+        }
+    }
+
+    /// Runs of bytes from the same line, as pairs of a line and the offset right after the run
+    pub fn lines(&self) -> &[(u32, u32)] {
+        &self.lines
+    }
+
+    /// Offsets at which the column changes, along with the new column
+    pub fn columns(&self) -> &[(u32, u32)] {
+        &self.columns
+    }
+
+    /// Offsets at which the file changes, along with the new file
+    pub fn files(&self) -> &[(u32, Option<u32>)] {
+        &self.files
+    }
+
+    /// Offset right after the last byte with a location
+    pub fn end(&self) -> u32 {
+        self.lines.last().map_or(0, |(_, end)| *end)
+    }
+
+    /// Records that the bytes from the current end up to `end` come from `location`
+    pub fn push(&mut self, location: Location, end: u32) {
+        let start = self.end();
+        match self.lines.last_mut() {
+            // If the line is the same as the last one pushed, we only extend the last run
+            Some((line, last_end)) if *line == location.line => *last_end = end,
+            // Otherwise we add a new run
+            _ => self.lines.push((location.line, end)),
+        }
+        if Self::at(&self.columns, start as usize).unwrap_or(0) != location.column {
+            self.columns.push((start, location.column));
+        }
+        if Self::at(&self.files, start as usize).flatten() != location.file {
+            self.files.push((start, location.file));
+        }
+    }
+
+    /// Location the byte at `idx` comes from
+    pub fn location(&self, idx: usize) -> Location {
+        // The byte belongs to the first run ending after it
+        let run = self.lines.partition_point(|(_, end)| *end as usize <= idx);
+        match self.lines.get(run) {
+            Some((line, _)) => Location {
+                line: *line,
+                column: Self::at(&self.columns, idx).unwrap_or(0),
+                file: Self::at(&self.files, idx).flatten(),
+            },
+            None => Location::default(),
+        }
+    }
+
+    /// Drops the locations from offset `len` onwards
+    pub fn truncate(&mut self, len: usize) {
+        // Keep the runs starting before `len`, the last of them now ending at `len`
+        let run = self.lines.partition_point(|(_, end)| (*end as usize) < len);
+        self.lines.truncate(if len == 0 { 0 } else { run + 1 });
+        if let Some((_, end)) = self.lines.last_mut() {
+            *end = (*end).min(len as u32);
+        }
+        self.columns.retain(|(offset, _)| (*offset as usize) < len);
+        self.files.retain(|(offset, _)| (*offset as usize) < len);
+    }
+
+    // Value of the last change made at or before `idx`, if any
+    fn at<T: Copy>(changes: &[(u32, T)], idx: usize) -> Option<T> {
+        let change = changes.partition_point(|(offset, _)| *offset as usize <= idx);
+        change.checked_sub(1).map(|change| changes[change].1)
+    }
+}
+
+impl FromIterator<(Location, u32)> for Locations {
+    /// Collects runs of bytes from the same location, each given with the offset right after it
+    fn from_iter<I: IntoIterator<Item = (Location, u32)>>(runs: I) -> Self {
+        let mut locations = Self::new();
+        for (location, end) in runs {
+            locations.push(location, end);
+        }
+        locations
+    }
+}
+
+/// Name of a local variable, kept for debuggers. The variable lives in stack slot `slot` of its
+/// call while the code from offset `start` up to offset `end` runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalName {
+    name: String,
+    slot: u8,
+    start: usize,
+    end: usize,
+}
+
+impl LocalName {
+    pub fn new(name: &str, slot: u8, start: usize, end: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            slot,
+            start,
+            end,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Whether the variable is in scope when the instruction at `offset` runs
+    pub fn is_live(&self, offset: usize) -> bool {
+        (self.start..self.end).contains(&offset)
+    }
+}
+
 /// A series of bytecode instructions
-#[derive(Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Sequence {
     // Stores the entire bytes code sequence
     code: Vec<u8>,
-    // Stores lines of the source code, using a run-length encoding algorithm where successive
-    // opcodes stored on the same line only store the line number and the number of successive
-    // occurences of that line number. Information is stored as 2 integers:
-    // 1. First is the line number
-    // 2. Second is the number of occurences of the line
-    lines: Vec<(u32, u32)>,
+    // Stores where the bytes come from in the source code
+    locations: Locations,
     // Stores constant values, referred to by their index
     constants: ValueVec,
+    // Index of the first constant holding each number and string, such that `add_constant` stores
+    // them once
+    interned: HashMap<ConstantKey, usize>,
+    // Names of the local variables, which the code itself refers to by slot. Only the compiler
+    // records them, so they are missing from sequences read from compiled files
+    local_names: Vec<LocalName>,
+}
+
+// Identifies constants which can be shared. Numbers are compared by their bits, such that `-0` and
+// `0` stay apart while a NaN is shared with the NaNs holding the same bits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<Self> {
+        match value.view() {
+            ValueRef::Nil => Some(Self::Nil),
+            ValueRef::Bool(boolean) => Some(Self::Bool(boolean)),
+            ValueRef::Number(number) => Some(Self::Number(number.to_bits())),
+            ValueRef::Obj(obj) => match obj {
+                Obj::String(string) => Some(Self::String(string.clone())),
+                // Functions are never shared, as each declaration is its own function
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Kind of the values found in constant pools
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstantKind {
+    Nil,
+    Bool,
+    Number,
+    String,
+    Function,
+    // Values which only exist at runtime, such as natives, and that the compiler never emits
+    Other,
+}
+
+impl ConstantKind {
+    pub const ALL: [Self; 6] = [
+        Self::Nil,
+        Self::Bool,
+        Self::Number,
+        Self::String,
+        Self::Function,
+        Self::Other,
+    ];
+
+    pub fn of(value: &Value) -> Self {
+        match value.view() {
+            ValueRef::Nil => Self::Nil,
+            ValueRef::Bool(_) => Self::Bool,
+            ValueRef::Number(_) => Self::Number,
+            ValueRef::Obj(obj) => match obj {
+                Obj::String(_) => Self::String,
+                Obj::Function(_) => Self::Function,
+                _ => Self::Other,
+            },
+        }
+    }
+
+    fn name(&self, count: usize) -> &'static str {
+        match (self, count) {
+            (Self::Nil, _) => "nil",
+            (Self::Bool, 1) => "boolean",
+            (Self::Bool, _) => "booleans",
+            (Self::Number, 1) => "number",
+            (Self::Number, _) => "numbers",
+            (Self::String, 1) => "string",
+            (Self::String, _) => "strings",
+            (Self::Function, 1) => "function",
+            (Self::Function, _) => "functions",
+            (Self::Other, _) => "other",
+        }
+    }
+}
+
+/// Number of constants of each kind in a constant pool
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConstantStats {
+    counts: [usize; ConstantKind::ALL.len()],
+}
+
+impl ConstantStats {
+    pub fn count(&self, kind: ConstantKind) -> usize {
+        self.counts[kind as usize]
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
+
+impl fmt::Display for ConstantStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{} constants", self.total())?;
+        let kinds: Vec<String> = ConstantKind::ALL
+            .iter()
+            .filter(|kind| self.count(**kind) > 0)
+            .map(|kind| format!("{} {}", self.count(*kind), kind.name(self.count(*kind))))
+            .collect();
+        if !kinds.is_empty() {
+            write!(f, " ({})", kinds.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
 impl Sequence {
@@ -81,44 +585,83 @@ impl Sequence {
         }
     }
 
-    pub fn push<T: TryInto<u8>>(&mut self, byte: T, line: u32) -> Result<(), SequenceError> {
+    /// Appends `byte`, which comes from `location` in the source code. A line can be given as
+    /// the location, when the column is not known
+    pub fn push<T: TryInto<u8>, L: Into<Location>>(
+        &mut self,
+        byte: T,
+        location: L,
+    ) -> Result<(), SequenceError> {
+        let location = location.into();
         self.code
             .push(byte.try_into().map_err(|_e| SequenceError::PushByte)?);
-        if !self.lines.is_empty() {
-            let last_line_idx = self.lines.len() - 1;
-            let last_line = self.lines[last_line_idx];
-            if line == last_line.0 {
-                // If the `line` is the same as the last line pushed, we only increase the number of
-                // occurences of the last already existing entry in lines
-                self.lines[last_line_idx] = (last_line.0, last_line.1 + 1);
-            } else {
-                // Otherwise we add a new entry
-                self.lines.push((line, 1));
+        let end = u32::try_from(self.code.len()).map_err(|_| SequenceError::TooLong)?;
+        self.locations.push(location, end);
+        Ok(())
+    }
+
+    /// Builds a sequence out of its parts, as found in a compiled file
+    pub fn from_parts(code: Vec<u8>, locations: Locations, constants: Vec<Value>) -> Self {
+        let mut interned = HashMap::new();
+        for (idx, constant) in constants.iter().enumerate() {
+            if let Some(key) = ConstantKey::of(constant) {
+                interned.entry(key).or_insert(idx);
             }
-        } else {
-            // Otherwise we add a new entry
-            self.lines.push((line, 1));
         }
-        Ok(())
+        Self {
+            code,
+            locations,
+            constants: ValueVec(constants),
+            interned,
+            local_names: Vec::new(),
+        }
     }
 
     pub fn code(&self) -> &[u8] {
         self.code.as_slice()
     }
 
-    pub fn line(&self, idx: usize) -> u32 {
-        // Computes the index of the last opcode having the current line number
-        let mut run_length_index = 0;
-        // For each occurence of line's run-length
-        for (line, occurences) in self.lines.iter() {
-            // Add the number of occurences
-            run_length_index += occurences;
-            // If our number of occurences goes past the desired index, we are in the desired range
-            if (idx as u32) < run_length_index {
-                return *line;
-            }
+    /// Where the bytes of the code come from in the source code
+    pub fn locations(&self) -> &Locations {
+        &self.locations
+    }
+
+    /// Drops the code from offset `len` onwards, along with its locations, and the constants from
+    /// index `constants` onwards. Used to replace the last instructions emitted
+    pub fn truncate(&mut self, len: usize, constants: usize) {
+        self.code.truncate(len);
+        self.locations.truncate(len);
+        self.constants.0.truncate(constants);
+        self.interned.retain(|_, idx| *idx < constants);
+        self.local_names.retain(|local| local.start < len);
+        for local in &mut self.local_names {
+            local.end = local.end.min(len);
         }
-        0
+    }
+
+    /// Overwrites the already pushed byte found at `offset`. Used to fill in jump offsets once
+    /// their destination is known
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    /// Reads the 2-byte Little Endian operand starting at `offset`
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Line the byte at `idx` comes from, 0 if it is not known
+    pub fn line(&self, idx: usize) -> u32 {
+        self.location(idx).line()
+    }
+
+    /// Location the byte at `idx` comes from
+    pub fn location(&self, idx: usize) -> Location {
+        self.locations.location(idx)
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        self.constants.values()
     }
 
     pub fn constant(&self, idx: usize) -> &Value {
@@ -130,6 +673,13 @@ impl Sequence {
         self.constant(usize::from(idx))
     }
 
+    /// Reads the constant whose 3-byte Little Endian index starts at `offset`
+    pub fn read_constant_long(&self, offset: usize) -> &Value {
+        let mut idx = [0; 4];
+        idx[..3].copy_from_slice(&self.code()[offset..offset + 3]);
+        self.constant(u32::from_le_bytes(idx) as usize)
+    }
+
     pub fn from_slice<P: AsRef<[u8]>>(value: P) -> Self {
         Self {
             code: value.as_ref().to_vec(),
@@ -137,33 +687,87 @@ impl Sequence {
         }
     }
 
-    /// Appends a new value to the underlying storage for this byte sequence's constants.
-    /// Returns the index where the value was added
+    /// Adds a value to the underlying storage for this byte sequence's constants, unless it is a
+    /// number or a string which is already stored. Returns the index where the value is stored
     pub fn add_constant(&mut self, value: Value) -> usize {
+        match self.find_constant(&value) {
+            Some(idx) => idx,
+            None => self.push_constant(value),
+        }
+    }
+
+    /// Appends a new value to the constants, even if it is already stored. Returns the index
+    /// where the value was added
+    pub fn push_constant(&mut self, value: Value) -> usize {
+        let idx = self.constants.0.len();
+        // The first copy of a value is the one shared by later calls to `add_constant`
+        if let Some(key) = ConstantKey::of(&value) {
+            self.interned.entry(key).or_insert(idx);
+        }
         // Push the value in the constants pool
         self.constants.push(value);
-        // Get the index where the constant was pushed
-        self.constants.0.len() - 1
+        idx
+    }
+
+    /// Index of the first constant `add_constant` would share with `value`, if any
+    pub fn find_constant(&self, value: &Value) -> Option<usize> {
+        self.interned.get(&ConstantKey::of(value)?).copied()
+    }
+
+    /// Names of the local variables, in the order their scopes end
+    pub fn local_names(&self) -> &[LocalName] {
+        &self.local_names
+    }
+
+    /// Records the name of a local variable
+    pub fn push_local_name(&mut self, local: LocalName) {
+        self.local_names.push(local);
+    }
+
+    /// Names of the local variables in scope when the instruction at `offset` runs, in the order
+    /// of their slots. Variables declared in inner blocks come after those they may shadow
+    pub fn local_names_at(&self, offset: usize) -> Vec<&LocalName> {
+        let mut locals: Vec<&LocalName> = self
+            .local_names
+            .iter()
+            .filter(|local| local.is_live(offset))
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        locals
+    }
+
+    /// Counts the constants of each kind
+    pub fn constant_stats(&self) -> ConstantStats {
+        let mut stats = ConstantStats::default();
+        for constant in self.constants() {
+            stats.counts[ConstantKind::of(constant) as usize] += 1;
+        }
+        stats
     }
 
     /// Writes a constant's index as a one-byte or 3-byte form
-    pub fn write_constant(&mut self, value: Value, line: u32) -> Result<(), SequenceError> {
+    pub fn write_constant<L: Into<Location>>(
+        &mut self,
+        value: Value,
+        location: L,
+    ) -> Result<(), SequenceError> {
+        let location = location.into();
         let idx = self.add_constant(value);
 
         // Check the size of our index value. If the value exceeds 255.
         if idx > 0xff {
             // Push the opcode
-            self.push(OpCode::ConstantLong, line)?;
+            self.push(OpCode::ConstantLong, location)?;
             // We decide to store its index as a 3-byte value in Little Endian
             let bytes = idx.to_le_bytes();
             for byte in bytes.iter().take(3) {
-                self.push(*byte, line)?;
+                self.push(*byte, location)?;
             }
         } else {
             // Push the opcode
-            self.push(OpCode::Constant, line)?;
+            self.push(OpCode::Constant, location)?;
             // Otherwise, we just write the 1-byte value
-            self.push(idx, line)?;
+            self.push(idx, location)?;
         }
         Ok(())
     }
@@ -172,7 +776,20 @@ impl Sequence {
 #[derive(Debug)]
 pub enum SequenceError {
     PushByte,
+    // The code grew past the offsets the locations can store
+    TooLong,
 }
 
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::PushByte => write!(f, "Cannot encode the instruction as a byte."),
+            Self::TooLong => write!(f, "Sequence is too long."),
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
 #[derive(Debug)]
 pub enum OpCodeError {}
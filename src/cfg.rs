@@ -0,0 +1,218 @@
+//! Control-flow graph of a sequence, made of basic blocks: runs of instructions which are always
+//! executed one after the other, as only their first instruction can be jumped to and only their
+//! last one can jump elsewhere. The graph can be exported in the DOT language of Graphviz.
+use crate::object::{Function, Obj};
+use crate::{Disassembler, OpCode, Operands, Sequence};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Basic blocks of a sequence, along with the edges between them
+pub struct ControlFlowGraph<'a> {
+    sequence: &'a Sequence,
+    blocks: Vec<BasicBlock>,
+}
+
+/// Instructions spanning `start..end` in the sequence, which always run one after the other
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    start: usize,
+    end: usize,
+    successors: Vec<Edge>,
+}
+
+/// Transfer of control from the end of a block to the start of another one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    // Offset of the block control goes to. The length of the code stands for leaving the
+    // function, as running past the end of the code returns `nil`
+    target: usize,
+    // Value of the condition the edge is taken on, if it is conditional
+    condition: Option<bool>,
+}
+
+impl<'a> ControlFlowGraph<'a> {
+    /// Splits the sequence into basic blocks, which start at the beginning of the code, at the
+    /// destination of jumps and right after jumps and returns
+    pub fn new(sequence: &'a Sequence) -> Self {
+        let len = sequence.code().len();
+        let instructions = Disassembler::decode_sequence(sequence);
+
+        let mut leaders = BTreeSet::from([0]);
+        for instruction in &instructions {
+            if let Some(target) = jump_target(instruction.operands(), len) {
+                leaders.insert(target);
+            }
+            if instruction.opcode().is_jump() || instruction.opcode() == OpCode::Return {
+                leaders.insert(instruction.next());
+            }
+        }
+        // Blocks only start where an instruction does, and before the end of the code
+        let starts: Vec<usize> = instructions
+            .iter()
+            .map(|instruction| instruction.offset())
+            .filter(|offset| leaders.contains(offset))
+            .collect();
+
+        let mut blocks = Vec::new();
+        for (idx, start) in starts.iter().enumerate() {
+            let end = starts.get(idx + 1).copied().unwrap_or(len);
+            let last = instructions
+                .iter()
+                .rev()
+                .find(|instruction| instruction.offset() < end)
+                .expect("Blocks hold at least one instruction");
+            let target = jump_target(last.operands(), len);
+            let successors = match (last.opcode(), target) {
+                (OpCode::Return, _) => vec![],
+                (OpCode::Jump | OpCode::Loop, Some(target)) => vec![Edge {
+                    target,
+                    condition: None,
+                }],
+                (OpCode::JumpIfFalse | OpCode::JumpIfNotLessLocal, Some(target)) => vec![
+                    Edge {
+                        target: end,
+                        condition: Some(true),
+                    },
+                    Edge {
+                        target,
+                        condition: Some(false),
+                    },
+                ],
+                _ => vec![Edge {
+                    target: end,
+                    condition: None,
+                }],
+            };
+            blocks.push(BasicBlock {
+                start: *start,
+                end,
+                successors,
+            });
+        }
+        Self { sequence, blocks }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Describes the graph in the DOT language, each block being labeled with its instructions
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph {} {{", quote(name));
+        let _ = writeln!(out, "    node [shape=box, fontname=\"monospace\"];");
+        self.write_nodes(&mut out, "", "    ");
+        out.push_str("}\n");
+        out
+    }
+
+    /// Describes the graphs of `function` and of the functions declared in it in the DOT
+    /// language, each function being drawn in its own cluster
+    pub fn function_to_dot(function: &Function) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph {} {{", quote(&function.to_string()));
+        let _ = writeln!(out, "    node [shape=box, fontname=\"monospace\"];");
+        let mut count = 0;
+        write_cluster(&mut out, function, &mut count);
+        out.push_str("}\n");
+        out
+    }
+
+    // Writes the blocks and edges, prefixing the identifier of every node with `prefix`
+    fn write_nodes(&self, out: &mut String, prefix: &str, indent: &str) {
+        let len = self.sequence.code().len();
+        let mut leaves = false;
+        for block in &self.blocks {
+            let mut label = String::new();
+            let mut offset = block.start;
+            while offset < block.end {
+                let instruction = Disassembler::decode(self.sequence, offset);
+                // `\l` ends a line aligned on the left
+                let text = format!("{offset:04} {instruction}");
+                let _ = write!(label, "{}\\l", escape(&text));
+                offset = instruction.next();
+            }
+            let _ = writeln!(out, "{indent}{prefix}b{} [label=\"{label}\"];", block.start,);
+            for edge in &block.successors {
+                leaves |= edge.target == len;
+                let _ = write!(out, "{indent}{prefix}b{} -> ", block.start);
+                match edge.target {
+                    target if target == len => {
+                        let _ = write!(out, "{prefix}exit");
+                    }
+                    target => {
+                        let _ = write!(out, "{prefix}b{target}");
+                    }
+                }
+                match edge.condition {
+                    Some(condition) => {
+                        let _ = writeln!(out, " [label=\"{condition}\"];");
+                    }
+                    None => out.push_str(";\n"),
+                }
+            }
+        }
+        if leaves {
+            let _ = writeln!(out, "{indent}{prefix}exit [shape=oval];");
+        }
+    }
+}
+
+impl BasicBlock {
+    /// Offset of the first instruction of the block
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Offset right after the last instruction of the block
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn successors(&self) -> &[Edge] {
+        &self.successors
+    }
+}
+
+impl Edge {
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    pub fn condition(&self) -> Option<bool> {
+        self.condition
+    }
+}
+
+fn write_cluster(out: &mut String, function: &Function, count: &mut usize) {
+    let prefix = format!("f{count}_");
+    let _ = writeln!(out, "    subgraph cluster_{count} {{");
+    *count += 1;
+    let _ = writeln!(out, "        label={};", quote(&function.to_string()));
+    ControlFlowGraph::new(function.sequence()).write_nodes(out, &prefix, "        ");
+    out.push_str("    }\n");
+    for constant in function.sequence().constants() {
+        if let Some(Obj::Function(function)) = constant.as_obj() {
+            write_cluster(out, function, count);
+        }
+    }
+}
+
+// Offset a jump lands on, if the instruction is a jump landing within the code
+fn jump_target(operands: Operands, len: usize) -> Option<usize> {
+    match operands {
+        Operands::Jump(target) | Operands::LocalJump(_, _, target) => {
+            usize::try_from(target).ok().filter(|target| *target <= len)
+        }
+        _ => None,
+    }
+}
+
+// Quotes `text` as a DOT string
+fn quote(text: &str) -> String {
+    format!("\"{}\"", escape(text))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,45 +1,1321 @@
-use crate::scan::{ScanError, Scanner};
+use crate::{
+    bytecode::{ConstantKind, LocalName, Location, OpCode, Sequence, SequenceError},
+    object::{Function, Obj},
+    scan::{ScanError, Scanner},
+    token::{Comparison, Keyword, Literal, SingleChar, Token, TokenType},
+    Value,
+};
+use std::{error::Error, fmt, rc::Rc};
 
-pub struct Compiler;
+pub struct Compiler {
+    // Whether the code was typed in the REPL. If so, the values of expression statements found at
+    // the top level are printed, and the last one does not need a terminating `;`
+    is_repl: bool,
+    // Whether constant expressions are folded and the bytecode goes through the peephole optimizer
+    optimize: bool,
+    // Whether the peephole optimizer fuses runs of instructions into superinstructions
+    superinstructions: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self {
+            is_repl: false,
+            optimize: true,
+            superinstructions: true,
+        }
+    }
+}
 
 impl Compiler {
-    pub fn compile(&self, bytes: &[u8]) -> Result<(), CompileError> {
-        // Currently no line is provided. We want to update this for each token we get
-        let mut line = None;
-        let mut scanner = Scanner::new(bytes);
-        // Keeps scanning and compiling tokens until the end of `bytes`. Usually the last token
-        // would be `Eof` and after that the `iterator` would return `None`
-        while let Some(maybe_token) = scanner.next_token() {
-            // Get the next token
-            let token = maybe_token?;
-            // If we have a line assigned from the previous token
-            if let Some(line) = &mut line {
-                // If it differs from the current token's line
-                if *line != token.line() {
-                    // Print the new line
-                    print!("{:04} ", token.line());
-                    // Replace it with the line of the current token
-                    *line = token.line();
-                } else {
-                    // If it does not differ, print a bar to show continuation of the previous line
-                    print!("   | ");
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compiles the code as if it was typed in the REPL
+    pub fn with_repl(mut self, is_repl: bool) -> Self {
+        self.is_repl = is_repl;
+        self
+    }
+
+    /// Folds constant expressions, such as `1 + 2 * 3` compiled as `7`, and runs the peephole
+    /// optimizer over the bytecode of each function, which is the default. Disabling it keeps the
+    /// bytecode close to the source code, which helps debugging
+    pub fn with_optimizations(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Selects superinstructions, such as `IncrementLocal` for `i = i + 1`, in place of the runs
+    /// of instructions they stand for, which is the default. They are only selected along with
+    /// the other optimizations
+    pub fn with_superinstructions(mut self, superinstructions: bool) -> Self {
+        self.superinstructions = superinstructions;
+        self
+    }
+
+    /// Compiles the source code in `bytes` into the top level function of a script, which can be
+    /// executed by the VM. The script evaluates to the value of its last statement if that is an
+    /// expression statement, and to `nil` otherwise.
+    ///
+    /// Compilation goes on past errors, such that all of them are reported, in the order they are
+    /// found. If there is any, no function is produced.
+    pub fn compile(&self, bytes: &[u8]) -> Result<Function, Vec<CompileError>> {
+        let mut parser = Parser::new(bytes, self.is_repl, self.optimize, self.superinstructions);
+        // Prime the parser with the first token
+        if let Err(err) = parser.advance() {
+            parser.errors.push(err);
+        }
+        // Keeps compiling declarations until the end of `bytes`
+        while !parser.check(TokenType::Eof) {
+            parser.recover(Parser::top_level_declaration);
+        }
+        let function = parser.end_function();
+        match function {
+            Ok(function) if parser.errors.is_empty() => Ok(function),
+            Ok(_) => Err(parser.errors),
+            Err(err) => {
+                parser.errors.push(err);
+                Err(parser.errors)
+            }
+        }
+    }
+}
+
+// Maximum number of local variables which can be in scope at once in a function, as they are
+// referred to with a single byte
+const LOCALS_MAX: usize = 256;
+
+// A local variable, living on the stack
+struct Local<'a> {
+    // Name of the variable in the source code
+    name: &'a str,
+    // Depth of the scope the variable was declared in. It is `None` while the variable's
+    // initializer is being compiled, such that the initializer cannot refer to the variable
+    depth: Option<usize>,
+    // Offset from which the variable can be used
+    start: usize,
+}
+
+// Whether we are compiling the top level code of a script or the body of a function
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+}
+
+// Compilation state of a function whose body is being compiled
+struct FunctionState<'a> {
+    kind: FunctionKind,
+    // Name of the function, if it has one
+    name: Option<String>,
+    // Number of parameters compiled so far
+    arity: usize,
+    // Bytecode emitted so far
+    sequence: Sequence,
+    // Local variables in scope, in the order of their stack slots
+    locals: Vec<Local<'a>>,
+    // Number of blocks surrounding the code being compiled, 0 being the function's top level
+    scope_depth: usize,
+    // Whether the value of the last statement was left on the stack, as the value the script
+    // evaluates to
+    returns_last_value: bool,
+    // Constants loaded by the last instructions emitted, one after the other, which the operators
+    // applied to them may fold
+    loaded: Vec<LoadedConstant>,
+    // Offset right after the last instruction emitted, if it always produces a number
+    numeric_end: Option<usize>,
+}
+
+// Instruction loading a constant known at compile time
+struct LoadedConstant {
+    value: Value,
+    // Offset of the instruction
+    offset: usize,
+    // Size of the constant pool before the instruction was emitted
+    constants: usize,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: Option<String>) -> Self {
+        Self {
+            kind,
+            name,
+            arity: 0,
+            sequence: Sequence::new(),
+            // The first stack slot of each call holds the callee itself, which cannot be named
+            locals: vec![Local {
+                name: "",
+                depth: Some(0),
+                start: 0,
+            }],
+            scope_depth: 0,
+            returns_last_value: false,
+            loaded: Vec::new(),
+            numeric_end: None,
+        }
+    }
+}
+
+// Precedence levels, from the loosest to the tightest binding
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    // =
+    Assignment,
+    // or
+    Or,
+    // and
+    And,
+    // == !=
+    Equality,
+    // < > <= >=
+    Comparison,
+    // + -
+    Term,
+    // * /
+    Factor,
+    // ! - not
+    Unary,
+    // ()
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    // Returns the precedence level binding one step tighter than `self`
+    fn next(self) -> Self {
+        match self {
+            Self::None => Self::Assignment,
+            Self::Assignment => Self::Or,
+            Self::Or => Self::And,
+            Self::And => Self::Equality,
+            Self::Equality => Self::Comparison,
+            Self::Comparison => Self::Term,
+            Self::Term => Self::Factor,
+            Self::Factor => Self::Unary,
+            Self::Unary => Self::Call,
+            Self::Call | Self::Primary => Self::Primary,
+        }
+    }
+
+    // Returns the precedence of `t_type` when used as an infix operator
+    fn of_infix(t_type: &TokenType) -> Self {
+        match t_type {
+            TokenType::SingleChar(SingleChar::LeftParen | SingleChar::Dot) => Self::Call,
+            TokenType::Keyword(Keyword::Or) => Self::Or,
+            TokenType::Keyword(Keyword::And) => Self::And,
+            TokenType::SingleChar(SingleChar::Minus | SingleChar::Plus) => Self::Term,
+            TokenType::SingleChar(SingleChar::Slash | SingleChar::Star) => Self::Factor,
+            TokenType::Comparison(Comparison::BangEqual | Comparison::EqualEqual) => Self::Equality,
+            TokenType::SingleChar(SingleChar::Less | SingleChar::Greater)
+            | TokenType::Comparison(Comparison::LessEqual | Comparison::GreaterEqual) => {
+                Self::Comparison
+            }
+            _ => Self::None,
+        }
+    }
+}
+
+// Single pass compiler, parsing tokens as they come out of the scanner and emitting bytecode for
+// them straight away.
+struct Parser<'a> {
+    // Source code being compiled
+    source: &'a [u8],
+    // Produces the tokens from `source`
+    scanner: Scanner<'a>,
+    // Token that was just consumed
+    previous: Token,
+    // Token that is about to be consumed
+    current: Token,
+    // Functions being compiled, each nested in the previous one. The innermost one is the last
+    states: Vec<FunctionState<'a>>,
+    // Whether we compile code typed in the REPL
+    is_repl: bool,
+    // Whether constant expressions are folded and the bytecode optimized
+    optimize: bool,
+    // Whether the optimized bytecode uses superinstructions
+    superinstructions: bool,
+    // Errors found so far
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a [u8], is_repl: bool, optimize: bool, superinstructions: bool) -> Self {
+        Self {
+            source,
+            scanner: Scanner::new(source),
+            previous: Token::default(),
+            current: Token::default(),
+            states: vec![FunctionState::new(FunctionKind::Script, None)],
+            is_repl,
+            optimize,
+            superinstructions,
+            errors: Vec::new(),
+        }
+    }
+
+    // Compiles a declaration with `declaration`. If that fails, the error is recorded and the
+    // parser skips to the start of the next statement, from where it carries on looking for errors
+    fn recover(&mut self, declaration: fn(&mut Self) -> Result<(), CompileError>) {
+        // Compilation state to go back to, as the failing declaration may have left scopes and
+        // functions open
+        let states = self.states.len();
+        let scope_depth = self.state().scope_depth;
+        let locals = self.state().locals.len();
+        let start = self.current.start();
+
+        let Err(err) = declaration(self) else {
+            return;
+        };
+        self.errors.push(err);
+        self.states.truncate(states);
+        let state = self.state_mut();
+        state.scope_depth = scope_depth;
+        state.locals.truncate(locals);
+
+        // The token the declaration could not start with must be skipped, or we would fail on it
+        // forever
+        if self.current.start() == start {
+            self.skip_token();
+        }
+        self.synchronize();
+    }
+
+    // Skips tokens until a statement boundary: after a `;`, or before a keyword starting a
+    // statement or the `}` closing the current block
+    fn synchronize(&mut self) {
+        while !self.check(TokenType::Eof) {
+            if *self.previous.t_type() == TokenType::SingleChar(SingleChar::SemiColon) {
+                return;
+            }
+            match self.current.t_type() {
+                TokenType::Keyword(
+                    Keyword::Class
+                    | Keyword::Fun
+                    | Keyword::Var
+                    | Keyword::For
+                    | Keyword::If
+                    | Keyword::While
+                    | Keyword::Print
+                    | Keyword::Return,
+                )
+                | TokenType::SingleChar(SingleChar::RightBrace) => return,
+                _ => self.skip_token(),
+            }
+        }
+    }
+
+    // Moves to the next token while recovering, recording the errors of invalid tokens
+    fn skip_token(&mut self) {
+        if let Err(err) = self.advance() {
+            self.errors.push(err);
+        }
+    }
+
+    // State of the innermost function being compiled
+    fn state(&self) -> &FunctionState<'a> {
+        self.states.last().expect("No function being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().expect("No function being compiled")
+    }
+
+    // Bytecode of the innermost function being compiled
+    fn sequence(&mut self) -> &mut Sequence {
+        &mut self.state_mut().sequence
+    }
+
+    // Finishes compiling the innermost function and hands it out
+    fn end_function(&mut self) -> Result<Function, CompileError> {
+        // Unless it already has its value on the stack, the function returns `nil`
+        if !self.state().returns_last_value {
+            self.emit(OpCode::Nil)?;
+        }
+        self.emit(OpCode::Return)?;
+        // The locals still in scope live until the end of the code
+        while self.state().locals.len() > 1 {
+            self.pop_local();
+        }
+        let state = self.states.pop().expect("No function being compiled");
+        let sequence = if self.optimize {
+            state.sequence.optimize_with(self.superinstructions)
+        } else {
+            state.sequence
+        };
+        Ok(Function::new(state.name, state.arity, sequence))
+    }
+
+    // Moves to the next token from the scanner
+    fn advance(&mut self) -> Result<(), CompileError> {
+        // The scanner only runs out of tokens after handing out `Eof`, in which case we keep
+        // reporting `Eof`
+        let token = match self.scanner.next_token() {
+            Some(token) => token?,
+            None => Token::new(
+                TokenType::Eof,
+                self.source.len(),
+                0,
+                self.current.line(),
+                self.current.column(),
+            ),
+        };
+        self.previous = core::mem::replace(&mut self.current, token);
+        Ok(())
+    }
+
+    // Returns whether the token about to be consumed is of type `t_type`
+    fn check(&self, t_type: TokenType) -> bool {
+        *self.current.t_type() == t_type
+    }
+
+    // Consumes the current token only if it is of type `t_type`
+    fn matches(&mut self, t_type: TokenType) -> Result<bool, CompileError> {
+        if !self.check(t_type) {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
+    // Consumes the current token, which must be of type `t_type`. Otherwise, an error with
+    // `message` is reported
+    fn consume(&mut self, t_type: TokenType, message: &'static str) -> Result<(), CompileError> {
+        if self.check(t_type) {
+            return self.advance();
+        }
+        let kind = match t_type {
+            TokenType::SingleChar(SingleChar::SemiColon) => SyntaxErrorKind::MissingSemicolon,
+            _ => SyntaxErrorKind::Expected,
+        };
+        Err(self.error_at(&self.current, kind, message))
+    }
+
+    // Returns the source code representation of `token`
+    fn lexeme(&self, token: &Token) -> Result<&'a str, CompileError> {
+        Ok(std::str::from_utf8(
+            self.source
+                .get(token.start()..token.end())
+                .ok_or(CompileError::ScanOutOfBounds(token.start(), token.end()))?,
+        )?)
+    }
+
+    fn error_at(
+        &self,
+        token: &Token,
+        kind: SyntaxErrorKind,
+        message: &'static str,
+    ) -> CompileError {
+        CompileError::Syntax {
+            kind,
+            message,
+            line: token.line(),
+            start: token.start(),
+            end: token.end(),
+        }
+    }
+
+    // Location of the last consumed token, which the code being emitted is attributed to
+    fn location(&self) -> Location {
+        Location::new(self.previous.line() as u32, self.previous.column() as u32)
+    }
+
+    // Appends `byte` to the sequence, attributing it to the last consumed token
+    fn emit<T: TryInto<u8>>(&mut self, byte: T) -> Result<(), CompileError> {
+        self.forget_constants();
+        let location = self.location();
+        Ok(self.sequence().push(byte, location)?)
+    }
+
+    // Forgets what is known about the values produced by the last instructions, such that they are
+    // not folded with the next ones
+    fn forget_constants(&mut self) {
+        let state = self.state_mut();
+        state.loaded.clear();
+        state.numeric_end = None;
+    }
+
+    // Emits a jump instruction with a placeholder offset. Returns where the offset is stored,
+    // such that it can be patched with `patch_jump` once the destination is known
+    fn emit_jump(&mut self, opcode: OpCode) -> Result<usize, CompileError> {
+        self.emit(opcode)?;
+        self.emit(0xffu8)?;
+        self.emit(0xffu8)?;
+        Ok(self.sequence().code().len() - 2)
+    }
+
+    // Makes the jump whose offset is stored at `offset` land on the next instruction emitted
+    fn patch_jump(&mut self, offset: usize) -> Result<(), CompileError> {
+        // The jump is relative to the end of the offset itself
+        let jump = self.sequence().code().len() - offset - 2;
+        let jump = u16::try_from(jump).map_err(|_| {
+            self.error_at(
+                &self.previous,
+                SyntaxErrorKind::TooLarge,
+                "Too much code to jump over.",
+            )
+        })?;
+        let [low, high] = jump.to_le_bytes();
+        self.sequence().patch(offset, low);
+        self.sequence().patch(offset + 1, high);
+        // The next instruction is jumped to, so it must not be folded with the previous ones
+        self.forget_constants();
+        Ok(())
+    }
+
+    // Emits an instruction jumping back to `loop_start`
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), CompileError> {
+        self.emit(OpCode::Loop)?;
+        // Jump over the 2 bytes of the offset as well
+        let jump = self.sequence().code().len() - loop_start + 2;
+        let jump = u16::try_from(jump).map_err(|_| {
+            self.error_at(
+                &self.previous,
+                SyntaxErrorKind::TooLarge,
+                "Loop body too large.",
+            )
+        })?;
+        let [low, high] = jump.to_le_bytes();
+        self.emit(low)?;
+        self.emit(high)
+    }
+
+    // Appends an instruction taking a single byte operand
+    fn emit_with_operand(&mut self, opcode: OpCode, operand: u8) -> Result<(), CompileError> {
+        self.emit(opcode)?;
+        self.emit(operand)
+    }
+
+    // Adds `value` to the constant pool and stores its index in a single byte, such that it could
+    // be used as an operand
+    fn make_constant(&mut self, value: Value) -> Result<u8, CompileError> {
+        let idx = self.sequence().add_constant(value);
+        u8::try_from(idx).map_err(|_| {
+            self.error_at(
+                &self.previous,
+                SyntaxErrorKind::TooLarge,
+                "Too many constants.",
+            )
+        })
+    }
+
+    // Stores the name of the variable `token` refers to as a constant
+    fn identifier_constant(&mut self, token: &Token) -> Result<u8, CompileError> {
+        let name = self.lexeme(token)?;
+        self.make_constant(Value::from(name))
+    }
+
+    // Compiles a declaration found directly in the script's top level code. If the script ends
+    // with an expression statement, its value is what the script evaluates to. In the REPL, the
+    // values of expression statements are printed instead
+    fn top_level_declaration(&mut self) -> Result<(), CompileError> {
+        let starts_statement = matches!(
+            self.current.t_type(),
+            TokenType::Keyword(
+                Keyword::Var
+                    | Keyword::Fun
+                    | Keyword::Class
+                    | Keyword::Print
+                    | Keyword::If
+                    | Keyword::While
+                    | Keyword::For
+                    | Keyword::Return
+            ) | TokenType::SingleChar(SingleChar::LeftBrace)
+        );
+        if starts_statement {
+            return self.declaration();
+        }
+
+        self.expression()?;
+        // It is common to omit the `;` of the last expression typed in the REPL
+        if !(self.is_repl && self.check(TokenType::Eof)) {
+            self.consume(
+                TokenType::SingleChar(SingleChar::SemiColon),
+                "Expect ';' after expression.",
+            )?;
+        }
+        if self.is_repl {
+            self.emit(OpCode::Print)
+        } else if self.check(TokenType::Eof) {
+            // Keep the value on the stack, for the script to return it
+            self.state_mut().returns_last_value = true;
+            Ok(())
+        } else {
+            self.emit(OpCode::Pop)
+        }
+    }
+
+    // declaration → funDecl | varDecl | statement
+    fn declaration(&mut self) -> Result<(), CompileError> {
+        if self.matches(TokenType::Keyword(Keyword::Fun))? {
+            self.fun_declaration()
+        } else if self.matches(TokenType::Keyword(Keyword::Var))? {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    // funDecl → "fun" IDENTIFIER "(" parameters? ")" block
+    fn fun_declaration(&mut self) -> Result<(), CompileError> {
+        let global = self.parse_variable("Expect function name.")?;
+        // Functions can refer to themselves, so the name is usable before the body is compiled
+        self.mark_initialized();
+        self.function()?;
+        self.define_variable(global)
+    }
+
+    // Compiles the parameters and the body of a function, emitting the resulting function as a
+    // constant
+    fn function(&mut self) -> Result<(), CompileError> {
+        let name = self.lexeme(&self.previous)?.to_string();
+        self.states
+            .push(FunctionState::new(FunctionKind::Function, Some(name)));
+        self.begin_scope();
+
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftParen),
+            "Expect '(' after function name.",
+        )?;
+        // parameters → IDENTIFIER ( "," IDENTIFIER )*
+        if !self.check(TokenType::SingleChar(SingleChar::RightParen)) {
+            loop {
+                self.state_mut().arity += 1;
+                if self.state().arity > 255 {
+                    return Err(self.error_at(
+                        &self.current,
+                        SyntaxErrorKind::TooLarge,
+                        "Can't have more than 255 parameters.",
+                    ));
+                }
+                let param = self.parse_variable("Expect parameter name.")?;
+                self.define_variable(param)?;
+                if !self.matches(TokenType::SingleChar(SingleChar::Comma))? {
+                    break;
                 }
-            } else {
-                // Print the new line
-                print!("{:04} ", token.line());
-                // Replace it with the line of the current token
-                line = Some(token.line())
-            }
-            // Get the representation of the token from the bytes
-            let token_str = std::str::from_utf8(
-                bytes
-                    .get(token.start()..token.end())
-                    .ok_or(CompileError::ScanOutOfBounds(token.start(), token.end()))?,
+            }
+        }
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightParen),
+            "Expect ')' after parameters.",
+        )?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftBrace),
+            "Expect '{' before function body.",
+        )?;
+        self.block()?;
+
+        // There is no need to end the scope, as the whole call frame is discarded on return
+        let function = self.end_function()?;
+        self.emit_constant(Value::from(Obj::Function(Rc::new(function))))
+    }
+
+    // varDecl → "var" IDENTIFIER ( "=" expression )? ";"
+    fn var_declaration(&mut self) -> Result<(), CompileError> {
+        let global = self.parse_variable("Expect variable name.")?;
+
+        // Variables without an initializer start as `nil`
+        if self.matches(TokenType::SingleChar(SingleChar::Equal))? {
+            self.expression()?;
+        } else {
+            self.emit(OpCode::Nil)?;
+        }
+        self.consume(
+            TokenType::SingleChar(SingleChar::SemiColon),
+            "Expect ';' after variable declaration.",
+        )?;
+        self.define_variable(global)
+    }
+
+    // Consumes the name of a variable being declared. Returns the index of the constant holding
+    // the name if the variable is global, as locals are not referred to by name at runtime
+    fn parse_variable(&mut self, message: &'static str) -> Result<u8, CompileError> {
+        self.consume(TokenType::Ident, message)?;
+        let name = self.previous.clone();
+        if self.state().scope_depth > 0 {
+            self.declare_local(&name)?;
+            return Ok(0);
+        }
+        self.identifier_constant(&name)
+    }
+
+    // Adds the local variable `name` to the current scope, without making it usable yet
+    fn declare_local(&mut self, name: &Token) -> Result<(), CompileError> {
+        let lexeme = self.lexeme(name)?;
+        let state = self.state();
+        // Shadowing a variable in the same scope is most likely a mistake
+        let redeclared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| local.name == lexeme);
+        if redeclared {
+            return Err(self.error_at(
+                name,
+                SyntaxErrorKind::AlreadyDeclared,
+                "Already a variable with this name in this scope.",
+            ));
+        }
+        if state.locals.len() == LOCALS_MAX {
+            return Err(self.error_at(
+                name,
+                SyntaxErrorKind::TooLarge,
+                "Too many local variables in function.",
+            ));
+        }
+        self.state_mut().locals.push(Local {
+            name: lexeme,
+            depth: None,
+            start: 0,
+        });
+        Ok(())
+    }
+
+    // Makes the latest declared local variable usable
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        let start = state.sequence.code().len();
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+            local.start = start;
+        }
+    }
+
+    // Emits the code defining a global variable named by the constant `global`, using the value on
+    // top of the stack. Locals need no code, as that value is already in their slot.
+    fn define_variable(&mut self, global: u8) -> Result<(), CompileError> {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return Ok(());
+        }
+        self.emit_with_operand(OpCode::DefineGlobal, global)
+    }
+
+    // Returns the stack slot of the local variable `name` in the innermost function, if any
+    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, CompileError> {
+        let lexeme = self.lexeme(name)?;
+        let Some((slot, local)) = self
+            .state()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == lexeme)
+        else {
+            return Ok(None);
+        };
+        if local.depth.is_none() {
+            return Err(self.error_at(
+                name,
+                SyntaxErrorKind::ReadInOwnInitializer,
+                "Can't read local variable in its own initializer.",
+            ));
+        }
+        Ok(Some(slot as u8))
+    }
+
+    // Returns whether `name` is a local variable of any of the functions enclosing the innermost
+    // one
+    fn is_enclosing_local(&self, name: &Token) -> Result<bool, CompileError> {
+        let lexeme = self.lexeme(name)?;
+        Ok(self.states[..self.states.len() - 1]
+            .iter()
+            .any(|state| state.locals.iter().any(|local| local.name == lexeme)))
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    // Closes the innermost block, discarding the locals declared in it
+    fn end_scope(&mut self) -> Result<(), CompileError> {
+        self.state_mut().scope_depth -= 1;
+        loop {
+            let state = self.state();
+            let in_scope = state
+                .locals
+                .last()
+                .is_some_and(|local| local.depth.is_some_and(|depth| depth > state.scope_depth));
+            if !in_scope {
+                return Ok(());
+            }
+            self.pop_local();
+            self.emit(OpCode::Pop)?;
+        }
+    }
+
+    // Drops the innermost local variable, which goes out of scope at the current offset, recording
+    // its name for debuggers
+    fn pop_local(&mut self) {
+        let state = self.state_mut();
+        let slot = state.locals.len() - 1;
+        let Some(local) = state.locals.pop() else {
+            return;
+        };
+        let end = state.sequence.code().len();
+        if local.depth.is_some() && !local.name.is_empty() {
+            let name = LocalName::new(local.name, slot as u8, local.start, end);
+            state.sequence.push_local_name(name);
+        }
+    }
+
+    // statement → printStmt | ifStmt | whileStmt | forStmt | returnStmt | block | exprStmt
+    fn statement(&mut self) -> Result<(), CompileError> {
+        if self.matches(TokenType::Keyword(Keyword::Print))? {
+            self.print_statement()
+        } else if self.matches(TokenType::Keyword(Keyword::If))? {
+            self.if_statement()
+        } else if self.matches(TokenType::Keyword(Keyword::While))? {
+            self.while_statement()
+        } else if self.matches(TokenType::Keyword(Keyword::For))? {
+            self.for_statement()
+        } else if self.matches(TokenType::Keyword(Keyword::Return))? {
+            self.return_statement()
+        } else if self.matches(TokenType::SingleChar(SingleChar::LeftBrace))? {
+            self.begin_scope();
+            self.block()?;
+            self.end_scope()
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    // block → "{" declaration* "}"
+    fn block(&mut self) -> Result<(), CompileError> {
+        while !self.check(TokenType::SingleChar(SingleChar::RightBrace))
+            && !self.check(TokenType::Eof)
+        {
+            self.recover(Self::declaration);
+        }
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightBrace),
+            "Expect '}' after block.",
+        )
+    }
+
+    // ifStmt → "if" "(" expression ")" statement ( "else" statement )?
+    fn if_statement(&mut self) -> Result<(), CompileError> {
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftParen),
+            "Expect '(' after 'if'.",
+        )?;
+        self.expression()?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightParen),
+            "Expect ')' after condition.",
+        )?;
+
+        // Skip the `then` branch if the condition is falsey. Either way, the condition is popped
+        // at the start of the branch being taken
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse)?;
+        self.emit(OpCode::Pop)?;
+        self.statement()?;
+        let else_jump = self.emit_jump(OpCode::Jump)?;
+
+        self.patch_jump(then_jump)?;
+        self.emit(OpCode::Pop)?;
+        if self.matches(TokenType::Keyword(Keyword::Else))? {
+            self.statement()?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    // whileStmt → "while" "(" expression ")" statement
+    fn while_statement(&mut self) -> Result<(), CompileError> {
+        let loop_start = self.sequence().code().len();
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftParen),
+            "Expect '(' after 'while'.",
+        )?;
+        self.expression()?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightParen),
+            "Expect ')' after condition.",
+        )?;
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse)?;
+        self.emit(OpCode::Pop)?;
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit(OpCode::Pop)
+    }
+
+    // forStmt → "for" "(" ( varDecl | exprStmt | ";" ) expression? ";" expression? ")" statement
+    fn for_statement(&mut self) -> Result<(), CompileError> {
+        // Variables declared in the initializer are scoped to the loop
+        self.begin_scope();
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftParen),
+            "Expect '(' after 'for'.",
+        )?;
+        if self.matches(TokenType::SingleChar(SingleChar::SemiColon))? {
+            // No initializer
+        } else if self.matches(TokenType::Keyword(Keyword::Var))? {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.sequence().code().len();
+        let mut exit_jump = None;
+        if !self.matches(TokenType::SingleChar(SingleChar::SemiColon))? {
+            self.expression()?;
+            self.consume(
+                TokenType::SingleChar(SingleChar::SemiColon),
+                "Expect ';' after loop condition.",
+            )?;
+            // Jump out of the loop if the condition is falsey
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse)?);
+            self.emit(OpCode::Pop)?;
+        }
+
+        if !self.matches(TokenType::SingleChar(SingleChar::RightParen))? {
+            // The increment is compiled before the body, but runs after it. So we jump over it to
+            // the body, and the body loops back to the increment instead of the condition
+            let body_jump = self.emit_jump(OpCode::Jump)?;
+            let increment_start = self.sequence().code().len();
+            self.expression()?;
+            self.emit(OpCode::Pop)?;
+            self.consume(
+                TokenType::SingleChar(SingleChar::RightParen),
+                "Expect ')' after for clauses.",
+            )?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit(OpCode::Pop)?;
+        }
+        self.end_scope()
+    }
+
+    // returnStmt → "return" expression? ";"
+    fn return_statement(&mut self) -> Result<(), CompileError> {
+        if self.state().kind == FunctionKind::Script {
+            return Err(self.error_at(
+                &self.previous,
+                SyntaxErrorKind::ReturnAtTopLevel,
+                "Can't return from top-level code.",
+            ));
+        }
+        if self.matches(TokenType::SingleChar(SingleChar::SemiColon))? {
+            self.emit(OpCode::Nil)?;
+        } else {
+            self.expression()?;
+            self.consume(
+                TokenType::SingleChar(SingleChar::SemiColon),
+                "Expect ';' after return value.",
             )?;
-            println!("{:?} {}", token.t_type(), token_str);
+        }
+        self.emit(OpCode::Return)
+    }
+
+    // printStmt → "print" expression ";"
+    fn print_statement(&mut self) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::SemiColon),
+            "Expect ';' after value.",
+        )?;
+        self.emit(OpCode::Print)
+    }
+
+    // exprStmt → expression ";"
+    fn expression_statement(&mut self) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::SemiColon),
+            "Expect ';' after expression.",
+        )?;
+        // The value of the expression is not used by anyone
+        self.emit(OpCode::Pop)
+    }
+
+    fn expression(&mut self) -> Result<(), CompileError> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    // Parses any expression binding at least as tight as `precedence`
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), CompileError> {
+        // Tokens ending a statement or a block are left in place, such that they still end it
+        // once we recover from the error
+        if matches!(
+            self.current.t_type(),
+            TokenType::SingleChar(SingleChar::SemiColon | SingleChar::RightBrace) | TokenType::Eof
+        ) {
+            return Err(self.error_at(
+                &self.current,
+                SyntaxErrorKind::Expected,
+                "Expect expression.",
+            ));
+        }
+        self.advance()?;
+        // Only the loosest expressions can be assigned to, otherwise `a * b = c` would be
+        // parsed as `a * (b = c)`
+        let can_assign = precedence <= Precedence::Assignment;
+        // The first token always starts a prefix expression
+        self.prefix(can_assign)?;
+
+        // Keep folding infix operators into the expression, for as long as they bind tighter
+        while precedence <= Precedence::of_infix(self.current.t_type()) {
+            self.advance()?;
+            self.infix()?;
+        }
+
+        // If nothing consumed the `=`, the left hand side was not something we can assign to
+        if can_assign && self.check(TokenType::SingleChar(SingleChar::Equal)) {
+            return Err(self.error_at(
+                &self.current,
+                SyntaxErrorKind::InvalidAssignmentTarget,
+                "Invalid assignment target.",
+            ));
         }
         Ok(())
     }
+
+    // Compiles the prefix expression started by the last consumed token
+    fn prefix(&mut self, can_assign: bool) -> Result<(), CompileError> {
+        match *self.previous.t_type() {
+            TokenType::SingleChar(SingleChar::LeftParen) => self.grouping(),
+            TokenType::SingleChar(SingleChar::Minus | SingleChar::Bang)
+            | TokenType::Keyword(Keyword::Not) => self.unary(),
+            TokenType::Literal(Literal::Number) => self.number(),
+            TokenType::Literal(Literal::LitString) => self.string(),
+            TokenType::Keyword(Keyword::True | Keyword::False | Keyword::Nil) => self.literal(),
+            TokenType::Ident => self.variable(can_assign),
+            _ => Err(self.error_at(
+                &self.previous,
+                SyntaxErrorKind::Expected,
+                "Expect expression.",
+            )),
+        }
+    }
+
+    // Compiles the infix expression whose operator is the last consumed token. The left operand
+    // is already compiled
+    fn infix(&mut self) -> Result<(), CompileError> {
+        match *self.previous.t_type() {
+            TokenType::SingleChar(SingleChar::LeftParen) => self.call(),
+            TokenType::SingleChar(SingleChar::Dot) => self.dot(),
+            TokenType::Keyword(Keyword::And) => self.and(),
+            TokenType::Keyword(Keyword::Or) => self.or(),
+            _ => self.binary(),
+        }
+    }
+
+    // The left operand is the result if it is falsey, otherwise the right operand is
+    fn and(&mut self) -> Result<(), CompileError> {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse)?;
+        self.emit(OpCode::Pop)?;
+        self.parse_precedence(Precedence::And)?;
+        self.patch_jump(end_jump)
+    }
+
+    // The left operand is the result if it is truthy, otherwise the right operand is
+    fn or(&mut self) -> Result<(), CompileError> {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse)?;
+        let end_jump = self.emit_jump(OpCode::Jump)?;
+        self.patch_jump(else_jump)?;
+        self.emit(OpCode::Pop)?;
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn number(&mut self) -> Result<(), CompileError> {
+        let value = self.lexeme(&self.previous)?.parse::<f64>().map_err(|_| {
+            self.error_at(
+                &self.previous,
+                SyntaxErrorKind::InvalidNumber,
+                "Invalid number literal.",
+            )
+        })?;
+        self.emit_load(Value::from(value))
+    }
+
+    fn string(&mut self) -> Result<(), CompileError> {
+        // Trim the surrounding quotes
+        let lexeme = self.lexeme(&self.previous)?;
+        let value = &lexeme[1..lexeme.len() - 1];
+        self.emit_load(Value::from(value))
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), CompileError> {
+        self.forget_constants();
+        let location = self.location();
+        Ok(self.sequence().write_constant(value, location)?)
+    }
+
+    // Emits the instruction loading `value`, remembering it such that operators can be folded
+    // with it
+    fn emit_load(&mut self, value: Value) -> Result<(), CompileError> {
+        let state = self.state_mut();
+        let mut loaded = std::mem::take(&mut state.loaded);
+        let numeric_end = state.numeric_end;
+        let offset = state.sequence.code().len();
+        let constants = state.sequence.constants().len();
+
+        match value.as_bool() {
+            Some(true) => self.emit(OpCode::True)?,
+            Some(false) => self.emit(OpCode::False)?,
+            None if value.is_nil() => self.emit(OpCode::Nil)?,
+            None => self.emit_constant(value.clone())?,
+        }
+        if let ConstantKind::Nil
+        | ConstantKind::Bool
+        | ConstantKind::Number
+        | ConstantKind::String = ConstantKind::of(&value)
+        {
+            loaded.push(LoadedConstant {
+                value,
+                offset,
+                constants,
+            });
+            let state = self.state_mut();
+            state.loaded = loaded;
+            state.numeric_end = numeric_end;
+        }
+        Ok(())
+    }
+
+    // Emits the instruction applying `opcode` to the values on top of the stack. When the result
+    // is known at compile time, the instructions loading the operands are replaced with one
+    // loading the result instead
+    fn emit_operator(&mut self, opcode: OpCode) -> Result<(), CompileError> {
+        if self.optimize && (self.fold(opcode)? || self.simplify(opcode)) {
+            return Ok(());
+        }
+        self.emit(opcode)?;
+        if let OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Negate = opcode {
+            let state = self.state_mut();
+            state.numeric_end = Some(state.sequence.code().len());
+        }
+        Ok(())
+    }
+
+    // Folds `opcode` if all of its operands are constants. Returns whether it did so
+    fn fold(&mut self, opcode: OpCode) -> Result<bool, CompileError> {
+        let arity = match opcode {
+            OpCode::Negate | OpCode::Not => 1,
+            _ => 2,
+        };
+        let state = self.state_mut();
+        let Some(start) = state.loaded.len().checked_sub(arity) else {
+            return Ok(false);
+        };
+        let operands: Vec<&Value> = state.loaded[start..].iter().map(|c| &c.value).collect();
+        let Some(result) = fold_operator(opcode, &operands) else {
+            return Ok(false);
+        };
+
+        let first = &state.loaded[start];
+        let (offset, constants) = (first.offset, first.constants);
+        state.loaded.truncate(start);
+        state.sequence.truncate(offset, constants);
+        self.emit_load(result)?;
+        Ok(true)
+    }
+
+    // Drops operations which leave their left operand unchanged, such as `x * 1`, when that
+    // operand is known to be a number. Returns whether it did so
+    fn simplify(&mut self, opcode: OpCode) -> bool {
+        let state = self.state_mut();
+        let Some(right) = state.loaded.last() else {
+            return false;
+        };
+        // The right operand must be loaded right after the instruction producing the left one
+        if state.numeric_end != Some(right.offset) {
+            return false;
+        }
+        let identity = match (opcode, right.value.as_number()) {
+            (OpCode::Mul | OpCode::Div, Some(number)) => number == 1.0,
+            // `-0 - -0` is `0`, so only a positive zero can be subtracted
+            (OpCode::Sub, Some(number)) => number == 0.0 && number.is_sign_positive(),
+            // `-0 + 0` is `0`, so additions are never dropped
+            _ => false,
+        };
+        if identity {
+            let (offset, constants) = (right.offset, right.constants);
+            state.loaded.pop();
+            state.sequence.truncate(offset, constants);
+        }
+        identity
+    }
+
+    fn literal(&mut self) -> Result<(), CompileError> {
+        match *self.previous.t_type() {
+            TokenType::Keyword(Keyword::True) => self.emit_load(Value::from(true)),
+            TokenType::Keyword(Keyword::False) => self.emit_load(Value::from(false)),
+            _ => self.emit_load(Value::nil()),
+        }
+    }
+
+    fn grouping(&mut self) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightParen),
+            "Expect ')' after expression.",
+        )
+    }
+
+    fn unary(&mut self) -> Result<(), CompileError> {
+        let operator = *self.previous.t_type();
+        // Compile the operand
+        self.parse_precedence(Precedence::Unary)?;
+        // Emit the operator instruction
+        match operator {
+            TokenType::SingleChar(SingleChar::Minus) => self.emit_operator(OpCode::Negate),
+            _ => self.emit_operator(OpCode::Not),
+        }
+    }
+
+    fn binary(&mut self) -> Result<(), CompileError> {
+        let operator = *self.previous.t_type();
+        // Compile the right operand, which binds one level tighter, as all binary operators are
+        // left associative
+        self.parse_precedence(Precedence::of_infix(&operator).next())?;
+
+        match operator {
+            TokenType::SingleChar(SingleChar::Plus) => self.emit_operator(OpCode::Add),
+            TokenType::SingleChar(SingleChar::Minus) => self.emit_operator(OpCode::Sub),
+            TokenType::SingleChar(SingleChar::Star) => self.emit_operator(OpCode::Mul),
+            TokenType::SingleChar(SingleChar::Slash) => self.emit_operator(OpCode::Div),
+            TokenType::Comparison(Comparison::EqualEqual) => self.emit_operator(OpCode::Equal),
+            TokenType::Comparison(Comparison::BangEqual) => {
+                self.emit_operator(OpCode::Equal)?;
+                self.emit_operator(OpCode::Not)
+            }
+            TokenType::SingleChar(SingleChar::Greater) => self.emit_operator(OpCode::Greater),
+            TokenType::Comparison(Comparison::GreaterEqual) => {
+                self.emit_operator(OpCode::Less)?;
+                self.emit_operator(OpCode::Not)
+            }
+            TokenType::SingleChar(SingleChar::Less) => self.emit_operator(OpCode::Less),
+            TokenType::Comparison(Comparison::LessEqual) => {
+                self.emit_operator(OpCode::Greater)?;
+                self.emit_operator(OpCode::Not)
+            }
+            _ => Err(self.error_at(
+                &self.previous,
+                SyntaxErrorKind::Expected,
+                "Expect binary operator.",
+            )),
+        }
+    }
+
+    fn call(&mut self) -> Result<(), CompileError> {
+        let arg_count = self.argument_list()?;
+        self.emit_with_operand(OpCode::Call, arg_count)
+    }
+
+    // Method invocation on the value compiled so far: "." IDENTIFIER "(" arguments
+    fn dot(&mut self) -> Result<(), CompileError> {
+        self.consume(TokenType::Ident, "Expect method name after '.'.")?;
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name)?;
+        self.consume(
+            TokenType::SingleChar(SingleChar::LeftParen),
+            "Expect '(' after method name.",
+        )?;
+        let arg_count = self.argument_list()?;
+        self.emit_with_operand(OpCode::Invoke, name)?;
+        self.emit(arg_count)
+    }
+
+    // arguments → ( expression ( "," expression )* )? ")"
+    fn argument_list(&mut self) -> Result<u8, CompileError> {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::SingleChar(SingleChar::RightParen)) {
+            loop {
+                self.expression()?;
+                arg_count = arg_count.checked_add(1).ok_or_else(|| {
+                    self.error_at(
+                        &self.previous,
+                        SyntaxErrorKind::TooLarge,
+                        "Can't have more than 255 arguments.",
+                    )
+                })?;
+                if !self.matches(TokenType::SingleChar(SingleChar::Comma))? {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::SingleChar(SingleChar::RightParen),
+            "Expect ')' after arguments.",
+        )?;
+        Ok(arg_count)
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), CompileError> {
+        let name = self.previous.clone();
+        // Locals shadow globals of the same name
+        let (get_op, set_op, operand) = match self.resolve_local(&name)? {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None => {
+                // Functions only see their own locals and the globals
+                if self.is_enclosing_local(&name)? {
+                    return Err(self.error_at(
+                        &name,
+                        SyntaxErrorKind::CaptureLocal,
+                        "Can't capture local variables of an enclosing function.",
+                    ));
+                }
+                let global = self.identifier_constant(&name)?;
+                (OpCode::GetGlobal, OpCode::SetGlobal, global)
+            }
+        };
+
+        if can_assign && self.matches(TokenType::SingleChar(SingleChar::Equal))? {
+            self.expression()?;
+            self.emit_with_operand(set_op, operand)
+        } else {
+            self.emit_with_operand(get_op, operand)
+        }
+    }
+}
+
+// Value `opcode` produces out of the constant `operands`, unless computing it at compile time
+// could change what the program does: type errors, divisions by zero and NaNs are left to the VM
+fn fold_operator(opcode: OpCode, operands: &[&Value]) -> Option<Value> {
+    let numbers = operands
+        .iter()
+        .map(|operand| operand.as_number())
+        .collect::<Option<Vec<f64>>>();
+    let result = match (opcode, operands, numbers.as_deref()) {
+        (OpCode::Not, [operand], _) => Value::from(operand.is_falsey()),
+        (OpCode::Equal, [left, right], _) => Value::from(left == right),
+        (OpCode::Negate, _, Some([number])) => Value::from(-number),
+        (OpCode::Add, _, Some([left, right])) => Value::from(left + right),
+        (OpCode::Add, [left, right], _) => match (left.as_str(), right.as_str()) {
+            (Some(left), Some(right)) => Value::from(format!("{left}{right}")),
+            _ => return None,
+        },
+        (OpCode::Sub, _, Some([left, right])) => Value::from(left - right),
+        (OpCode::Mul, _, Some([left, right])) => Value::from(left * right),
+        (OpCode::Div, _, Some([left, right])) if *right != 0.0 => Value::from(left / right),
+        (OpCode::Greater, _, Some([left, right])) => Value::from(left > right),
+        (OpCode::Less, _, Some([left, right])) => Value::from(left < right),
+        _ => return None,
+    };
+    match result.as_number() {
+        Some(number) if number.is_nan() => None,
+        _ => Some(result),
+    }
 }
 
 #[derive(Debug)]
@@ -47,7 +1323,65 @@ pub enum CompileError {
     ScanError(ScanError),
     Utf8Error(core::str::Utf8Error),
     ScanOutOfBounds(usize, usize),
+    SequenceError(SequenceError),
+    // The token spanning `start..end` on `line` does not fit the grammar
+    Syntax {
+        kind: SyntaxErrorKind,
+        message: &'static str,
+        line: usize,
+        start: usize,
+        end: usize,
+    },
+}
+
+/// What a syntax error is about, which diagnostics explain along with its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxErrorKind {
+    // A statement is missing its `;`
+    MissingSemicolon,
+    // The grammar expects another token
+    Expected,
+    // Something other than a variable is assigned to
+    InvalidAssignmentTarget,
+    // The number literal does not fit in a number
+    InvalidNumber,
+    // A local variable is declared twice in the same scope
+    AlreadyDeclared,
+    // A local variable is read in its own initializer
+    ReadInOwnInitializer,
+    // A function refers to a local variable of an enclosing function
+    CaptureLocal,
+    // `return` is used outside of a function
+    ReturnAtTopLevel,
+    // The code goes past one of the limits of the bytecode
+    TooLarge,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::ScanError(err) => write!(f, "{err}"),
+            Self::Utf8Error(_) => write!(f, "Source code is not valid UTF-8."),
+            Self::ScanOutOfBounds(start, end) => {
+                write!(f, "Token spanning {start}..{end} is outside of the source.")
+            }
+            Self::SequenceError(err) => write!(f, "{err}"),
+            Self::Syntax { message, line, .. } => write!(f, "[line {line}] {message}"),
+        }
+    }
+}
+
+impl Error for CompileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::ScanError(err) => err.source(),
+            Self::Utf8Error(err) => Some(err),
+            Self::SequenceError(err) => err.source(),
+            Self::ScanOutOfBounds(..) | Self::Syntax { .. } => None,
+        }
+    }
 }
 
 crate::impl_from_err!(ScanError, CompileError, ScanError);
 crate::impl_from_err!(core::str::Utf8Error, CompileError, Utf8Error);
+crate::impl_from_err!(SequenceError, CompileError, SequenceError);
@@ -0,0 +1,572 @@
+//! Debug Adapter Protocol server, run by `malis dap` such that editors can debug scripts. Messages
+//! are framed by a `Content-Length` header, and read from any input and written to any output. The
+//! script runs on the same thread as the server, which handles requests before it starts, whenever
+//! it stops at a breakpoint or after a step, and once it is done.
+use crate::debug::{self, Resume, Stepper};
+use crate::json::Json;
+use crate::{Function, MMalis, MMalisError, TraceStep, Tracer, Value};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+// Scripts run on a single thread
+const THREAD_ID: u32 = 1;
+
+// References clients use to ask for variables. The locals of the frame `n` are found at
+// `LOCALS_REFERENCE + n`
+const GLOBALS_REFERENCE: u64 = 1;
+const LOCALS_REFERENCE: u64 = 2;
+
+// Exit code reported for scripts failing while running, the same as `malis` exits with
+const EX_SOFTWARE: u32 = 70;
+
+// Messages longer than this are rejected, such that a bogus `Content-Length` does not make us
+// allocate whatever it asks for
+const MESSAGE_MAX: usize = 16 * 1024 * 1024;
+
+/// Server speaking the Debug Adapter Protocol with a client, debugging the script it launches
+pub struct DapServer<R: BufRead, W: Write> {
+    session: Rc<RefCell<Session<R, W>>>,
+}
+
+impl<R: BufRead + 'static, W: Write + 'static> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            session: Rc::new(RefCell::new(Session {
+                input,
+                output,
+                seq: 0,
+                program: None,
+                started: false,
+                stopped: false,
+                breakpoints: Vec::new(),
+                next_breakpoint: 1,
+                stepper: Stepper::new(false),
+                printed: String::new(),
+                error: None,
+                disconnected: false,
+            })),
+        }
+    }
+
+    /// Answers the client until it disconnects or closes the input. Fails if messages cannot be
+    /// read or written
+    pub fn run(self) -> io::Result<()> {
+        // The client launches the script and sets its breakpoints, then tells us to run it
+        let program = loop {
+            let mut session = self.session.borrow_mut();
+            let Some(request) = session.read()? else {
+                return Ok(());
+            };
+            match session.handle(&request, None)? {
+                Flow::Run => break session.program.clone(),
+                Flow::Disconnect => return Ok(()),
+                Flow::Stay | Flow::Resume => {}
+            }
+        };
+        let Some(program) = program else {
+            return Ok(());
+        };
+
+        let mut malis = MMalis::new();
+        malis.vm().set_output(ScriptOutput(self.session.clone()));
+        malis.vm().set_tracer(SessionTracer(self.session.clone()));
+        let result = malis.vm().run_function(program.script.clone());
+        drop(malis);
+
+        let mut session = self.session.borrow_mut();
+        if let Some(err) = session.error.take() {
+            return Err(err);
+        }
+        if session.disconnected {
+            return Ok(());
+        }
+        session.flush_printed()?;
+        let exit_code = match result {
+            Ok(_) => 0,
+            Err(err) => {
+                let output = MMalisError::from(err).render(&program.path, &program.bytes);
+                session.event(
+                    "output",
+                    Json::object([("category", "stderr".into()), ("output", output.into())]),
+                )?;
+                EX_SOFTWARE
+            }
+        };
+        session.event("exited", Json::object([("exitCode", exit_code.into())]))?;
+        session.event("terminated", Json::Null)?;
+
+        // Clients may still look around before disconnecting
+        while let Some(request) = session.read()? {
+            if let Flow::Disconnect = session.handle(&request, None)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a request asks of the server, on top of being answered
+enum Flow {
+    Stay,
+    // Resume the stopped script
+    Resume,
+    // Start running the launched script
+    Run,
+    Disconnect,
+}
+
+// Script being debugged
+#[derive(Clone)]
+struct Program {
+    path: String,
+    // Contents of the file, to render errors with
+    bytes: Vec<u8>,
+    script: Rc<Function>,
+}
+
+// State of the server, shared with the tracer and the output of the script
+struct Session<R, W> {
+    input: R,
+    output: W,
+    // Sequence number of the last message sent
+    seq: u64,
+    program: Option<Program>,
+    // Whether the script started running, and whether it stopped since
+    started: bool,
+    stopped: bool,
+    // Ids of the breakpoints, along with the lines they stop at
+    breakpoints: Vec<(u64, u32)>,
+    next_breakpoint: u64,
+    stepper: Stepper,
+    // What the script printed since the end of the last line it printed
+    printed: String,
+    // Failure to read or write messages while the script runs, which stops it
+    error: Option<io::Error>,
+    disconnected: bool,
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    // Reads the next message, or `None` once the client closes the input
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let mut length = None;
+        // Headers end with an empty line
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() && length.is_some() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    let value = value.trim().parse::<usize>();
+                    length = Some(value.map_err(|err| invalid(err.to_string()))?);
+                }
+            }
+        }
+        let length = length.unwrap_or_default();
+        if length > MESSAGE_MAX {
+            return Err(invalid(format!(
+                "Message of {length} bytes is over the limit of {MESSAGE_MAX} bytes."
+            )));
+        }
+        let mut body = vec![0; length];
+        self.input.read_exact(&mut body)?;
+        let body = String::from_utf8(body).map_err(|err| invalid(err.to_string()))?;
+        let message = Json::parse(&body).map_err(|err| invalid(err.to_string()))?;
+        Ok(Some(message))
+    }
+
+    fn send(&mut self, kind: &str, members: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        let header = [("seq", self.seq.into()), ("type", kind.into())];
+        let message = Json::Object(
+            header
+                .into_iter()
+                .chain(members)
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+        .to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{message}",
+            message.len()
+        )?;
+        self.output.flush()
+    }
+
+    // Answers `request` with `body` if it succeeded, or with the message telling why it failed
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) -> io::Result<()> {
+        let mut members = vec![
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", body.is_ok().into()),
+            (
+                "command",
+                request.get("command").cloned().unwrap_or(Json::Null),
+            ),
+        ];
+        match body {
+            Ok(Json::Null) => {}
+            Ok(body) => members.push(("body", body)),
+            Err(message) => members.push(("message", message.into())),
+        }
+        self.send("response", members)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut members = vec![("event", event.into())];
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.send("event", members)
+    }
+
+    // Sends what the script printed without ending the line, if anything
+    fn flush_printed(&mut self) -> io::Result<()> {
+        if self.printed.is_empty() {
+            return Ok(());
+        }
+        let output = std::mem::take(&mut self.printed);
+        self.event(
+            "output",
+            Json::object([("category", "stdout".into()), ("output", output.into())]),
+        )
+    }
+
+    // Answers `request`. Requests looking into the script can only be made while it is stopped
+    // before `step`
+    fn handle(&mut self, request: &Json, step: Option<&TraceStep<'_>>) -> io::Result<Flow> {
+        let command = request.get("command").and_then(Json::as_str);
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let mut flow = Flow::Stay;
+        let body = match (command.unwrap_or_default(), step) {
+            ("initialize", _) => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsEvaluateForHovers", true.into()),
+                ("supportsTerminateRequest", true.into()),
+            ])),
+            ("launch", _) => self.launch(arguments),
+            ("setBreakpoints", _) => Ok(self.set_breakpoints(arguments)),
+            // Scripts have no exceptions to break on
+            ("setExceptionBreakpoints", _) => Ok(Json::Null),
+            ("configurationDone", _) => match &self.program {
+                None => Err("No program was launched.".to_string()),
+                Some(_) if self.started => Err("The program is already running.".to_string()),
+                Some(_) => {
+                    self.started = true;
+                    flow = Flow::Run;
+                    Ok(Json::Null)
+                }
+            },
+            ("threads", _) => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "main".into()),
+                ])]
+                .into(),
+            )])),
+            ("stackTrace", Some(step)) => Ok(self.stack_trace(step)),
+            ("scopes", Some(step)) => scopes(step, arguments),
+            ("variables", Some(step)) => variables(step, arguments),
+            ("evaluate", Some(step)) => evaluate(step, arguments),
+            ("continue" | "next" | "stepIn" | "stepOut", Some(step)) => {
+                let resume = match command {
+                    Some("continue") => Resume::Continue,
+                    Some("next") => Resume::Over,
+                    Some("stepIn") => Resume::Line,
+                    _ => Resume::Out,
+                };
+                self.stepper.resume(step, resume);
+                flow = Flow::Resume;
+                match resume {
+                    Resume::Continue => Ok(Json::object([("allThreadsContinued", true.into())])),
+                    _ => Ok(Json::Null),
+                }
+            }
+            ("disconnect" | "terminate", _) => {
+                flow = Flow::Disconnect;
+                Ok(Json::Null)
+            }
+            (
+                "stackTrace" | "scopes" | "variables" | "evaluate" | "continue" | "next" | "stepIn"
+                | "stepOut",
+                None,
+            ) => Err("The program is not stopped.".to_string()),
+            (command, _) => Err(format!("Unsupported request `{command}`.")),
+        };
+        let launched = command == Some("launch") && body.is_ok();
+        self.respond(request, body)?;
+        // Breakpoints can be set from now on
+        if launched {
+            self.event("initialized", Json::Null)?;
+        }
+        Ok(flow)
+    }
+
+    // Reads and compiles the script to debug, which may also be a compiled one
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let Some(path) = arguments.get("program").and_then(Json::as_str) else {
+            return Err("Missing the path of the program to launch.".to_string());
+        };
+        let bytes = fs::read(path).map_err(|err| format!("Cannot read `{path}`: {err}"))?;
+        let sequence =
+            MMalis::compile_for_debugging(&bytes).map_err(|err| err.render(path, &bytes))?;
+
+        let stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool);
+        self.stepper = Stepper::new(stop_on_entry.unwrap_or_default());
+        self.program = Some(Program {
+            path: path.to_string(),
+            bytes,
+            script: Rc::new(Function::new(None, 0, sequence)),
+        });
+        Ok(Json::Null)
+    }
+
+    // Replaces the breakpoints, moving those on lines without code to the next line having some.
+    // Scripts are a single file, so every source stands for it
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let lines = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line")?.as_u64());
+
+        self.breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            let id = self.next_breakpoint;
+            self.next_breakpoint += 1;
+            let line = u32::try_from(line).unwrap_or(u32::MAX);
+            let code_line = self
+                .program
+                .as_ref()
+                .and_then(|program| debug::code_line(&program.script, line));
+            breakpoints.push(match code_line {
+                Some(code_line) => {
+                    self.breakpoints.push((id, code_line));
+                    Json::object([
+                        ("id", id.into()),
+                        ("verified", true.into()),
+                        ("line", code_line.into()),
+                    ])
+                }
+                None => Json::object([
+                    ("id", id.into()),
+                    ("verified", false.into()),
+                    ("line", line.into()),
+                    ("message", "No code at or after this line.".into()),
+                ]),
+            });
+        }
+        Json::object([("breakpoints", breakpoints.into())])
+    }
+
+    fn stack_trace(&self, step: &TraceStep<'_>) -> Json {
+        let source = self.program.as_ref().map_or(Json::Null, |program| {
+            Json::object([("path", program.path.as_str().into())])
+        });
+        let frames: Vec<Json> = step
+            .backtrace()
+            .iter()
+            .enumerate()
+            .map(|(idx, frame)| {
+                Json::object([
+                    ("id", idx.into()),
+                    ("name", frame.function().to_string().into()),
+                    ("source", source.clone()),
+                    ("line", frame.line().into()),
+                    ("column", 1u32.into()),
+                ])
+            })
+            .collect();
+        Json::object([
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", frames.into()),
+        ])
+    }
+
+    // Stops before `step` if stepping or a breakpoint says so, and answers requests until the
+    // client resumes the script
+    fn trace(&mut self, step: &TraceStep<'_>) -> io::Result<()> {
+        let (entered, stop) = self.stepper.advance(step);
+        let hits: Vec<Json> = self
+            .breakpoints
+            .iter()
+            .filter(|(_, line)| entered && *line == step.line())
+            .map(|(id, _)| (*id).into())
+            .collect();
+        if !stop && hits.is_empty() {
+            return Ok(());
+        }
+
+        let reason = match (hits.is_empty(), self.stopped) {
+            (false, _) => "breakpoint",
+            (true, false) => "entry",
+            (true, true) => "step",
+        };
+        self.stopped = true;
+        // What the script printed so far shows up before it stops
+        self.flush_printed()?;
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if !hits.is_empty() {
+            body.push(("hitBreakpointIds", hits.into()));
+        }
+        let body = Json::Object(
+            body.into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        );
+        self.event("stopped", body)?;
+
+        loop {
+            let Some(request) = self.read()? else {
+                self.disconnected = true;
+                return Ok(());
+            };
+            match self.handle(&request, Some(step))? {
+                Flow::Resume => return Ok(()),
+                Flow::Disconnect => {
+                    self.disconnected = true;
+                    return Ok(());
+                }
+                Flow::Stay | Flow::Run => {}
+            }
+        }
+    }
+}
+
+// Scopes of the frame `frameId`: its locals, and the globals
+fn scopes(step: &TraceStep<'_>, arguments: &Json) -> Result<Json, String> {
+    let frame = arguments
+        .get("frameId")
+        .and_then(Json::as_u64)
+        .filter(|frame| *frame < step.depth() as u64)
+        .ok_or("Unknown frame.")?;
+    let scope = |name: &str, reference: u64| {
+        Json::object([
+            ("name", name.into()),
+            ("variablesReference", reference.into()),
+            ("expensive", false.into()),
+        ])
+    };
+    Ok(Json::object([(
+        "scopes",
+        vec![
+            scope("Locals", LOCALS_REFERENCE + frame),
+            scope("Globals", GLOBALS_REFERENCE),
+        ]
+        .into(),
+    )]))
+}
+
+fn variables(step: &TraceStep<'_>, arguments: &Json) -> Result<Json, String> {
+    let reference = arguments
+        .get("variablesReference")
+        .and_then(Json::as_u64)
+        .ok_or("Missing the variables reference.")?;
+    let variables = if reference == GLOBALS_REFERENCE {
+        step.globals()
+    } else {
+        let frames = step.backtrace();
+        let frame = reference
+            .checked_sub(LOCALS_REFERENCE)
+            .and_then(|frame| frames.get(usize::try_from(frame).ok()?))
+            .ok_or("Unknown variables reference.")?;
+        frame.locals()
+    };
+    let variables: Vec<Json> = variables
+        .into_iter()
+        .map(|(name, value)| {
+            Json::object([
+                ("name", name.into()),
+                ("value", describe(value).into()),
+                ("type", value.type_name().into()),
+                ("variablesReference", 0u32.into()),
+            ])
+        })
+        .collect();
+    Ok(Json::object([("variables", variables.into())]))
+}
+
+// Evaluates variable names, looked up in the frame `frameId` and then among the globals
+fn evaluate(step: &TraceStep<'_>, arguments: &Json) -> Result<Json, String> {
+    let expression = arguments
+        .get("expression")
+        .and_then(Json::as_str)
+        .ok_or("Missing the expression.")?
+        .trim();
+    let frame = arguments.get("frameId").and_then(Json::as_u64).unwrap_or(0);
+    let value = step
+        .lookup(usize::try_from(frame).unwrap_or(usize::MAX), expression)
+        .ok_or_else(|| format!("No variable `{expression}` in scope."))?;
+    Ok(Json::object([
+        ("result", describe(value).into()),
+        ("type", value.type_name().into()),
+        ("variablesReference", 0u32.into()),
+    ]))
+}
+
+// Shows `value` the way it would be written in a script, such that strings stand out
+fn describe(value: &Value) -> String {
+    match value.as_str() {
+        Some(string) => format!("{string:?}"),
+        None => value.to_string(),
+    }
+}
+
+// Tracer handing the steps of the script to the session
+struct SessionTracer<R, W>(Rc<RefCell<Session<R, W>>>);
+
+impl<R: BufRead, W: Write> Tracer for SessionTracer<R, W> {
+    fn trace(&mut self, step: &TraceStep<'_>) {
+        let mut session = self.0.borrow_mut();
+        if session.error.is_some() || session.disconnected {
+            return;
+        }
+        if let Err(err) = session.trace(step) {
+            session.error = Some(err);
+        }
+    }
+
+    fn interrupted(&self) -> bool {
+        let session = self.0.borrow();
+        session.error.is_some() || session.disconnected
+    }
+}
+
+// Output of the script, sent to the client one line at a time
+struct ScriptOutput<R, W>(Rc<RefCell<Session<R, W>>>);
+
+impl<R: BufRead, W: Write> Write for ScriptOutput<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.0.borrow_mut();
+        session.printed.push_str(&String::from_utf8_lossy(buf));
+        while let Some(end) = session.printed.find('\n') {
+            let line: String = session.printed.drain(..=end).collect();
+            session.event(
+                "output",
+                Json::object([("category", "stdout".into()), ("output", line.into())]),
+            )?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush_printed()
+    }
+}
@@ -0,0 +1,500 @@
+//! Interactive debugger, run by `malis debug`. It is a tracer stopping the VM at breakpoints or
+//! after each step, then reading commands inspecting the script until one resumes it. Commands
+//! are read from any input and answered on any output, such that sessions can be scripted. The
+//! DAP server steps through scripts the same way.
+use crate::object::{Function, Obj};
+use crate::{Disassembler, TraceStep, Tracer};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+/// Commands understood by the debugger
+const HELP: &str = "\
+break <line>               stop before running the code of `line`
+break [function]@<offset>  stop before the instruction at `offset` of `function`, or of the script
+delete <n>                 remove breakpoint `n`
+breakpoints                list the breakpoints
+continue, c                run until a breakpoint is reached
+step, s                    run until another line is reached, entering calls
+next, n                    run until another line is reached, stepping over calls
+stepi, si                  run a single instruction
+finish, out                run until the current call returns
+stack                      show the value stack
+locals                     show the local variables of the current call
+print, p <name>            show the local or global variable `name`
+globals                    show the global variables
+backtrace, bt              show the calls in progress
+dis [n]                    disassemble `n` instructions around the current one
+help                       show this message
+quit, q                    stop the script";
+
+// Number of instructions `dis` shows on each side of the current one, unless told otherwise
+const DIS_CONTEXT: usize = 3;
+
+// Where the script stops
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Breakpoint {
+    // First instruction run from a line, in any function
+    Line(u32),
+    // Instruction at `offset` of the function named `function`, or of the script
+    Offset {
+        function: Option<String>,
+        offset: usize,
+    },
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line(line) => write!(f, "line {line}"),
+            Self::Offset {
+                function: Some(function),
+                offset,
+            } => write!(f, "<fn {function}> offset {offset:04}"),
+            Self::Offset {
+                function: None,
+                offset,
+            } => write!(f, "<script> offset {offset:04}"),
+        }
+    }
+}
+
+// Line being run by a given call, telling when stepping reaches another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    function: *const Function,
+    depth: usize,
+    line: u32,
+}
+
+impl Position {
+    fn of(step: &TraceStep<'_>) -> Self {
+        Self {
+            function: step.function(),
+            depth: step.depth(),
+            line: step.line(),
+        }
+    }
+}
+
+// How far the script runs before stopping again, on top of the breakpoints
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Instruction,
+    Continue,
+    // Until another line is reached, in any call
+    Line(Position),
+    // Until another line is reached in the same call or in one of its callers
+    Over(Position),
+    // Until the call returns
+    Out(Position),
+}
+
+/// Ways of resuming a stopped script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resume {
+    // Until the next instruction
+    Instruction,
+    // Until a breakpoint is reached
+    Continue,
+    // Until another line is reached, entering calls
+    Line,
+    // Until another line is reached, stepping over calls
+    Over,
+    // Until the current call returns
+    Out,
+}
+
+/// Follows the instructions of a script, telling where stepping through it stops
+#[derive(Debug)]
+pub(crate) struct Stepper {
+    mode: Mode,
+    // Position of the previous instruction
+    last: Option<Position>,
+}
+
+impl Stepper {
+    /// Stepper stopping before the first instruction if `stop_on_entry` is set, and otherwise
+    /// only at breakpoints
+    pub(crate) fn new(stop_on_entry: bool) -> Self {
+        let mode = if stop_on_entry {
+            Mode::Instruction
+        } else {
+            Mode::Continue
+        };
+        Self { mode, last: None }
+    }
+
+    /// Moves on to `step`, returning whether it starts another line, which line breakpoints stop
+    /// at, and whether stepping stops before it
+    pub(crate) fn advance(&mut self, step: &TraceStep<'_>) -> (bool, bool) {
+        let position = Position::of(step);
+        // Returning to a caller goes back to a line it already started
+        let entered = self
+            .last
+            .is_none_or(|last| last != position && last.depth <= position.depth);
+        self.last = Some(position);
+
+        let stop = match self.mode {
+            Mode::Instruction => true,
+            Mode::Continue => false,
+            Mode::Line(from) => position != from,
+            Mode::Over(from) => position != from && position.depth <= from.depth,
+            Mode::Out(from) => position.depth < from.depth,
+        };
+        (entered, stop)
+    }
+
+    /// Resumes the script stopped before `step`
+    pub(crate) fn resume(&mut self, step: &TraceStep<'_>, resume: Resume) {
+        let position = Position::of(step);
+        self.mode = match resume {
+            Resume::Instruction => Mode::Instruction,
+            Resume::Continue => Mode::Continue,
+            Resume::Line => Mode::Line(position),
+            Resume::Over => Mode::Over(position),
+            Resume::Out => Mode::Out(position),
+        };
+    }
+}
+
+/// Tracer stopping the script before its first instruction, and then wherever it is told to,
+/// reading commands from `input` at each stop
+pub struct Debugger<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    // Lines of the source code of the script, if it is known
+    source: Option<Vec<String>>,
+    // Breakpoints by number, minus one. Deleted ones leave a hole, such that numbers stay the same
+    breakpoints: Vec<Option<Breakpoint>>,
+    stepper: Stepper,
+    // Command repeated when entering an empty line
+    last_command: String,
+    quit: bool,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            source: None,
+            breakpoints: Vec::new(),
+            stepper: Stepper::new(true),
+            last_command: String::new(),
+            quit: false,
+        }
+    }
+
+    /// Shows the lines of `source`, which the script was compiled from, when stopping
+    pub fn with_source(mut self, source: &[u8]) -> Self {
+        let source = String::from_utf8_lossy(source);
+        self.source = Some(source.lines().map(str::to_string).collect());
+        self
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+
+    // Number of the breakpoint stopping the script before `step`, if any. `entered` tells whether
+    // the step starts another line, which line breakpoints stop at
+    fn breakpoint_hit(&self, step: &TraceStep<'_>, entered: bool) -> Option<usize> {
+        let idx = self
+            .breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Some(Breakpoint::Line(line)) => entered && *line == step.line(),
+                Some(Breakpoint::Offset { function, offset }) => {
+                    *offset == step.offset() && function.as_deref() == step.function().name()
+                }
+                None => false,
+            })?;
+        Some(idx + 1)
+    }
+
+    // Reads and runs commands until one of them resumes the script
+    fn stop(&mut self, step: &TraceStep<'_>, breakpoint: Option<usize>) {
+        let mut out = String::new();
+        if let Some(number) = breakpoint {
+            out.push_str(&format!("Breakpoint {number}, "));
+        }
+        let line = step.line();
+        out.push_str(&format!("{} at line {line}\n", step.function()));
+        if let Some(text) = self.source_line(line) {
+            out.push_str(&format!("{line:>4} | {text}\n"));
+        }
+        out.push_str(&format!("=> {:04} {}\n", step.offset(), step.instruction()));
+        self.write(&out);
+
+        loop {
+            self.write("(mdb) ");
+            let mut line = String::new();
+            // Running out of commands ends the session
+            if !matches!(self.input.read_line(&mut line), Ok(read) if read > 0) {
+                self.quit = true;
+                return;
+            }
+            let mut command = line.trim().to_string();
+            if command.is_empty() {
+                command.clone_from(&self.last_command);
+            } else {
+                self.last_command.clone_from(&command);
+            }
+            if self.command(step, &command) {
+                return;
+            }
+        }
+    }
+
+    // Runs `command`, returning whether it resumes the script
+    fn command(&mut self, step: &TraceStep<'_>, command: &str) -> bool {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+        let resume = match name {
+            "continue" | "c" => Resume::Continue,
+            "step" | "s" => Resume::Line,
+            "next" | "n" => Resume::Over,
+            "stepi" | "si" => Resume::Instruction,
+            "finish" | "out" => Resume::Out,
+            "quit" | "q" => {
+                self.quit = true;
+                return true;
+            }
+            _ => {
+                let out = self.inspect(step, name, argument);
+                self.write(&out);
+                return false;
+            }
+        };
+        self.stepper.resume(step, resume);
+        true
+    }
+
+    // Runs the command `name`, which does not resume the script, returning what it shows
+    fn inspect(&mut self, step: &TraceStep<'_>, name: &str, argument: &str) -> String {
+        match name {
+            "break" | "b" => match parse_breakpoint(step, argument) {
+                Ok(breakpoint) => {
+                    let out = format!(
+                        "Breakpoint {} at {breakpoint}\n",
+                        self.breakpoints.len() + 1
+                    );
+                    self.breakpoints.push(Some(breakpoint));
+                    out
+                }
+                Err(err) => format!("{err}\n"),
+            },
+            "delete" | "d" => {
+                let number = argument.parse::<usize>().ok();
+                let breakpoint = number
+                    .and_then(|number| self.breakpoints.get_mut(number.checked_sub(1)?))
+                    .and_then(Option::take);
+                match breakpoint {
+                    Some(breakpoint) => format!("Deleted breakpoint {argument} at {breakpoint}\n"),
+                    None => format!("No breakpoint `{argument}`.\n"),
+                }
+            }
+            "breakpoints" => {
+                let out: String = self
+                    .breakpoints
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, breakpoint)| {
+                        Some(format!("{}: {}\n", idx + 1, breakpoint.as_ref()?))
+                    })
+                    .collect();
+                if out.is_empty() {
+                    "No breakpoints.\n".to_string()
+                } else {
+                    out
+                }
+            }
+            "stack" if step.stack().is_empty() => "The stack is empty.\n".to_string(),
+            "stack" => step
+                .stack()
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| format!("{idx:>4}: {value}\n"))
+                .collect(),
+            "locals" => {
+                let locals = step.locals();
+                let frames = step.backtrace();
+                let named = step.function().sequence().local_names();
+                if locals.is_empty() && named.is_empty() && !frames.is_empty() {
+                    // Compiled scripts do not name their locals, so their slots are shown instead.
+                    // The first one holds the callee
+                    return frames[0]
+                        .slots()
+                        .iter()
+                        .enumerate()
+                        .skip(1)
+                        .map(|(slot, value)| format!("slot {slot} = {value}\n"))
+                        .collect();
+                }
+                if locals.is_empty() {
+                    return "No local variables.\n".to_string();
+                }
+                locals
+                    .iter()
+                    .map(|(name, value)| format!("{name} = {value}\n"))
+                    .collect()
+            }
+            "print" | "p" => match step.lookup(0, argument) {
+                Some(value) => format!("{argument} = {value}\n"),
+                None => format!("No variable `{argument}` in scope.\n"),
+            },
+            "globals" => step
+                .globals()
+                .iter()
+                .map(|(name, value)| format!("{name} = {value}\n"))
+                .collect(),
+            "backtrace" | "bt" => step
+                .backtrace()
+                .iter()
+                .enumerate()
+                .map(|(idx, frame)| {
+                    format!(
+                        "#{idx} {} at line {}, offset {:04}\n",
+                        frame.function(),
+                        frame.line(),
+                        frame.offset()
+                    )
+                })
+                .collect(),
+            "dis" => match argument {
+                "" => disassemble(step, DIS_CONTEXT),
+                argument => match argument.parse() {
+                    Ok(context) => disassemble(step, context),
+                    Err(_) => format!("Invalid number of instructions `{argument}`.\n"),
+                },
+            },
+            "help" => format!("{HELP}\n"),
+            _ => format!("Unknown command `{name}`, see `help`.\n"),
+        }
+    }
+
+    fn source_line(&self, line: u32) -> Option<&str> {
+        let idx = usize::try_from(line).ok()?.checked_sub(1)?;
+        Some(self.source.as_ref()?.get(idx)?.as_str())
+    }
+
+    // The script keeps running whether or not the debugger could write to its output
+    fn write(&mut self, out: &str) {
+        let _ = self.output.write_all(out.as_bytes());
+        let _ = self.output.flush();
+    }
+}
+
+impl<R: BufRead, W: Write> Tracer for Debugger<R, W> {
+    fn trace(&mut self, step: &TraceStep<'_>) {
+        let (entered, stop) = self.stepper.advance(step);
+        let breakpoint = self.breakpoint_hit(step, entered);
+        if stop || breakpoint.is_some() {
+            self.stop(step, breakpoint);
+        }
+    }
+
+    fn interrupted(&self) -> bool {
+        self.quit
+    }
+}
+
+// The script and every function declared in it, however deeply
+fn functions(script: &Function) -> Vec<&Function> {
+    let mut functions = vec![script];
+    let mut idx = 0;
+    while let Some(function) = functions.get(idx) {
+        let nested: Vec<&Function> = function
+            .sequence()
+            .constants()
+            .iter()
+            .filter_map(|constant| match constant.as_obj() {
+                Some(Obj::Function(function)) => Some(function.as_ref()),
+                _ => None,
+            })
+            .collect();
+        functions.extend(nested);
+        idx += 1;
+    }
+    functions
+}
+
+/// First line from `line` on which some code of `script` or of its functions was compiled from,
+/// where a breakpoint on `line` stops
+pub(crate) fn code_line(script: &Function, line: u32) -> Option<u32> {
+    functions(script)
+        .iter()
+        .flat_map(|function| Disassembler::decode_sequence(function.sequence()))
+        .map(|instruction| instruction.line())
+        .filter(|code_line| *code_line >= line)
+        .min()
+}
+
+// Parses the argument of `break`. Lines without code move the breakpoint to the next line having
+// some, and offsets must start an instruction of the function
+fn parse_breakpoint(step: &TraceStep<'_>, argument: &str) -> Result<Breakpoint, String> {
+    let frames = step.backtrace();
+    let Some(script) = frames.last() else {
+        return Err("No script is running.".to_string());
+    };
+    let functions = functions(script.function());
+
+    let Some((name, offset)) = argument.split_once('@') else {
+        let line: u32 = argument
+            .parse()
+            .map_err(|_| format!("Invalid line `{argument}`."))?;
+        return code_line(script.function(), line)
+            .map(Breakpoint::Line)
+            .ok_or_else(|| format!("No code at or after line {line}."));
+    };
+
+    let offset: usize = offset
+        .parse()
+        .map_err(|_| format!("Invalid offset `{offset}`."))?;
+    let name = (!name.is_empty()).then_some(name);
+    let function = functions
+        .iter()
+        .find(|function| function.name() == name)
+        .ok_or_else(|| format!("No function named `{}`.", name.unwrap_or_default()))?;
+    let starts_instruction = Disassembler::decode_sequence(function.sequence())
+        .iter()
+        .any(|instruction| instruction.offset() == offset);
+    if !starts_instruction {
+        return Err(format!("No instruction at offset {offset} of {function}."));
+    }
+    Ok(Breakpoint::Offset {
+        function: name.map(str::to_string),
+        offset,
+    })
+}
+
+// Lists the instructions around the current one, `context` of them on each side
+fn disassemble(step: &TraceStep<'_>, context: usize) -> String {
+    let instructions = Disassembler::decode_sequence(step.function().sequence());
+    let current = instructions
+        .iter()
+        .position(|instruction| instruction.offset() == step.offset())
+        .unwrap_or_default();
+    let first = current.saturating_sub(context);
+    let last = current
+        .saturating_add(context)
+        .saturating_add(1)
+        .min(instructions.len());
+    instructions[first..last]
+        .iter()
+        .map(|instruction| {
+            let marker = if instruction.offset() == step.offset() {
+                "=>"
+            } else {
+                "  "
+            };
+            format!(
+                "{marker} {:04} {:>4} {instruction}\n",
+                instruction.offset(),
+                instruction.line()
+            )
+        })
+        .collect()
+}
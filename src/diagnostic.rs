@@ -0,0 +1,325 @@
+//! Human readable reports of the errors found in scripts. A `Diagnostic` points at the offending
+//! code and renders it the way compilers usually do:
+//!
+//! ```text
+//! error[E0100]: Expect ';' after value.
+//!  --> script.ms:1:8
+//!   |
+//! 1 | print 1
+//!   |        ^
+//!   = help: add `;` at the end of the statement
+//! ```
+use crate::compiler::{CompileError, SyntaxErrorKind};
+use crate::scan::ScanError;
+use crate::{InterpretError, MMalisError, RuntimeError};
+use core::fmt::Write;
+use core::ops::Range;
+use std::error::Error;
+
+/// Extra information attached to a diagnostic, below the source snippet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteKind {
+    // Explains why the code is wrong
+    Note,
+    // Suggests how to fix the code
+    Help,
+}
+
+impl NoteKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Note => "note",
+            Self::Help => "help",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    // Identifies the kind of error, such that it could be looked up
+    code: Option<&'static str>,
+    message: String,
+    // Bytes of the source the error is about
+    span: Option<Range<usize>>,
+    // Line the error is about, for errors that do not know their exact span
+    line: Option<usize>,
+    notes: Vec<(NoteKind, String)>,
+}
+
+impl Diagnostic {
+    pub fn error<S: Into<String>>(message: S) -> Self {
+        Self {
+            code: None,
+            message: message.into(),
+            span: None,
+            line: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Points the diagnostic at the `span` bytes of the source
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Points the diagnostic at a whole line of the source, starting at 1
+    pub fn with_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push((NoteKind::Note, note.into()));
+        self
+    }
+
+    pub fn with_help<S: Into<String>>(mut self, help: S) -> Self {
+        self.notes.push((NoteKind::Help, help.into()));
+        self
+    }
+
+    pub fn code(&self) -> Option<&'static str> {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.span.clone()
+    }
+
+    pub fn notes(&self) -> &[(NoteKind, String)] {
+        &self.notes
+    }
+
+    /// Renders the diagnostic for `source`, the contents of the file called `file`
+    pub fn render(&self, file: &str, source: &[u8]) -> String {
+        let mut out = String::new();
+        // Writing to a `String` cannot fail
+        let _ = self.write(&mut out, file, source);
+        out
+    }
+
+    fn write(&self, out: &mut String, file: &str, source: &[u8]) -> core::fmt::Result {
+        match self.code {
+            Some(code) => writeln!(out, "error[{code}]: {}", self.message)?,
+            None => writeln!(out, "error: {}", self.message)?,
+        }
+
+        // Width of the column holding line numbers, on the left of the snippet
+        let mut gutter = 1;
+        if let Some(span) = &self.span {
+            let snippet = Snippet::new(source, span.clone());
+            gutter = snippet.line.to_string().len();
+            writeln!(
+                out,
+                "{:gutter$}--> {file}:{}:{}",
+                "", snippet.line, snippet.column
+            )?;
+            writeln!(out, "{:gutter$} |", "")?;
+            writeln!(out, "{} | {}", snippet.line, snippet.text)?;
+            writeln!(
+                out,
+                "{:gutter$} | {:pad$}{}",
+                "",
+                "",
+                "^".repeat(snippet.width),
+                pad = snippet.column - 1
+            )?;
+        } else if let Some(line) = self.line {
+            writeln!(out, "{:gutter$}--> {file}:{line}", "")?;
+        }
+
+        for (kind, note) in &self.notes {
+            writeln!(out, "{:gutter$} = {}: {note}", "", kind.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+// The source line a span starts on, along with where the span sits in it
+struct Snippet {
+    // Line number, starting at 1
+    line: usize,
+    // Column the span starts at, in displayed characters and starting at 1
+    column: usize,
+    // Number of characters the span covers on the line, which is at least 1
+    width: usize,
+    // Contents of the line, as displayed
+    text: String,
+}
+
+impl Snippet {
+    fn new(source: &[u8], span: Range<usize>) -> Self {
+        let mut start = span.start.min(source.len());
+        // Empty spans are reported when the source ends too early, in which case we point right
+        // after the last piece of code rather than at trailing whitespace
+        if span.is_empty() {
+            start = source[..start]
+                .iter()
+                .rposition(|byte| !byte.is_ascii_whitespace())
+                .map_or(0, |idx| idx + 1);
+        }
+        let line_start = source[..start]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |idx| idx + 1);
+        let line_end = source[start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(source.len(), |idx| start + idx);
+        // The underline does not go past the first line of the span
+        let end = span.end.clamp(start, line_end);
+
+        let line = source[..start]
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count()
+            + 1;
+        let column = display(&source[line_start..start]).chars().count() + 1;
+        let width = display(&source[start..end]).chars().count().max(1);
+        let text = display(&source[line_start..line_end]);
+        let text = text.trim_end_matches('\r').to_string();
+
+        Self {
+            line,
+            column,
+            width,
+            text,
+        }
+    }
+}
+
+// Converts source bytes for display, expanding tabs such that the underline stays aligned
+fn display(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).replace('\t', "    ")
+}
+
+impl From<&ScanError> for Diagnostic {
+    fn from(value: &ScanError) -> Self {
+        match value {
+            ScanError::UnexpectedByte(byte, offset, _) => {
+                Self::error(format!("Unexpected character '{}'.", byte.escape_ascii()))
+                    .with_code("E0001")
+                    .with_span(*offset..offset + 1)
+            }
+            ScanError::UnterminatedString(start, _) => Self::error("Unterminated string.")
+                .with_code("E0002")
+                .with_span(*start..start + 1)
+                .with_help("add a closing `\"` to end the string"),
+            ScanError::CannotConsumeByte => {
+                Self::error("Unexpected end of input.").with_code("E0900")
+            }
+        }
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(value: &CompileError) -> Self {
+        match value {
+            CompileError::ScanError(err) => err.into(),
+            CompileError::Syntax {
+                kind,
+                message,
+                start,
+                end,
+                ..
+            } => syntax(*kind, message).with_span(*start..*end),
+            CompileError::Utf8Error(err) => Self::error("Source code is not valid UTF-8.")
+                .with_code("E0003")
+                .with_note(err.to_string()),
+            CompileError::ScanOutOfBounds(start, end) => Self::error(format!(
+                "Token spanning {start}..{end} is outside of the source."
+            ))
+            .with_code("E0900"),
+            CompileError::SequenceError(err) => {
+                Self::error(format!("Cannot emit bytecode: {err:?}.")).with_code("E0900")
+            }
+        }
+    }
+}
+
+// Gives the syntax error of `kind` its code, adding what could help fixing it
+fn syntax(kind: SyntaxErrorKind, message: &'static str) -> Diagnostic {
+    let diagnostic = Diagnostic::error(message);
+    match kind {
+        SyntaxErrorKind::MissingSemicolon => diagnostic
+            .with_code("E0100")
+            .with_help("add `;` at the end of the statement"),
+        SyntaxErrorKind::Expected => diagnostic.with_code("E0100"),
+        SyntaxErrorKind::InvalidAssignmentTarget => diagnostic
+            .with_code("E0101")
+            .with_note("only variables can be assigned to"),
+        SyntaxErrorKind::InvalidNumber => diagnostic.with_code("E0102"),
+        SyntaxErrorKind::AlreadyDeclared => diagnostic
+            .with_code("E0200")
+            .with_note("a variable can only shadow one declared in an enclosing scope"),
+        SyntaxErrorKind::ReadInOwnInitializer => diagnostic
+            .with_code("E0201")
+            .with_help("give the new variable another name"),
+        SyntaxErrorKind::CaptureLocal => diagnostic
+            .with_code("E0202")
+            .with_note("closures are not supported")
+            .with_help("pass the value to the function as an argument"),
+        SyntaxErrorKind::ReturnAtTopLevel => diagnostic.with_code("E0203"),
+        SyntaxErrorKind::TooLarge => diagnostic.with_code("E0300"),
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(value: &RuntimeError) -> Self {
+        let diagnostic = Self::error(value.message());
+        match value.line() {
+            Some(line) => diagnostic.with_line(line as usize),
+            None => diagnostic,
+        }
+    }
+}
+
+impl InterpretError {
+    /// Describes the error, which takes one diagnostic per error the compiler found
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Self::CompileError(errors) => errors.iter().map(Diagnostic::from).collect(),
+            Self::RuntimeError(err) => vec![err.into()],
+            Self::VerifyError(err) => vec![Diagnostic::error(err.to_string())],
+            Self::StackEmpty => vec![Diagnostic::error("The stack is empty.")],
+            Self::Interrupted => vec![Diagnostic::error("Execution was interrupted.")],
+        }
+    }
+}
+
+impl MMalisError {
+    /// Describes the error, which takes one diagnostic per error the compiler found
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let diagnostic = match self {
+            Self::StdIO(err) => Diagnostic::error(err.to_string()),
+            Self::InterpretError(err) => return err.diagnostics(),
+            Self::UndefinedGlobal(name) => {
+                Diagnostic::error(format!("Undefined variable '{name}'."))
+            }
+            Self::Conversion(err) => Diagnostic::error(err.to_string()),
+            Self::Format(err) => match err.source() {
+                Some(source) => Diagnostic::error(err.to_string()).with_note(source.to_string()),
+                None => Diagnostic::error(err.to_string()),
+            },
+        };
+        vec![diagnostic]
+    }
+
+    /// Renders all the diagnostics of the error, for `source` found in `file`
+    pub fn render(&self, file: &str, source: &[u8]) -> String {
+        self.diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.render(file, source))
+            .collect()
+    }
+}
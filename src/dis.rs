@@ -1,4 +1,10 @@
-use crate::bytecode::{OpCode, Sequence};
+use crate::bytecode::{Location, OpCode, Sequence};
+use crate::json::quote;
+use crate::object::{Function, Obj};
+use crate::{Value, ValueRef};
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::io;
 
 #[derive(Default)]
 pub struct Disassembler;
@@ -7,78 +13,602 @@ impl Disassembler {
     pub fn new() -> Self {
         Self
     }
+
+    /// Prints the sequence to the standard output
     pub fn dis_sequence(sequence: &Sequence, name: &str) {
-        println!("== {} ==", name);
-        // Start at the beginning of the sequence
-        let mut offset = 0;
-        // While we still have bytes
-        while offset < sequence.code().len() {
-            // Disassemble an instruction and move the cursor to the first byte after that
-            // instruction
-            offset = Self::dis_instruction(sequence, offset);
-        }
+        let _ = Self::write_to(io::stdout().lock(), sequence, name);
     }
 
+    /// Prints the body of `function` to the standard output, followed by the bodies of the
+    /// functions declared in it
+    pub fn dis_function(function: &Function) {
+        let mut out = String::new();
+        let _ = Self::write_function(&mut out, function);
+        print!("{out}");
+    }
+
+    /// Prints the instruction found at `offset` to the standard output, returning the offset of
+    /// the next instruction
     pub fn dis_instruction(sequence: &Sequence, offset: usize) -> usize {
+        let mut out = String::new();
+        // Writing to a `String` cannot fail
+        let next = Self::write_instruction(&mut out, sequence, offset).unwrap_or(offset + 1);
+        print!("{out}");
+        next
+    }
+
+    /// Writes the sequence, along with its name as a header, to the `writer`
+    pub fn write_to<W: io::Write>(
+        mut writer: W,
+        sequence: &Sequence,
+        name: &str,
+    ) -> io::Result<()> {
+        let mut out = String::new();
+        // Writing to a `String` cannot fail
+        let _ = Self::write_sequence(&mut out, sequence, name);
+        writer.write_all(out.as_bytes())
+    }
+
+    /// Writes the sequence, along with its name as a header
+    pub fn write_sequence<W: Write>(out: &mut W, sequence: &Sequence, name: &str) -> fmt::Result {
+        write_listing(out, sequence, name, None)
+    }
+
+    /// Writes the body of `function`, followed by the bodies of the functions declared in it
+    pub fn write_function<W: Write>(out: &mut W, function: &Function) -> fmt::Result {
+        write_functions(out, function, None)
+    }
+
+    /// Writes the body of `function` and of the functions declared in it, like
+    /// `Disassembler::write_function`, showing each line of `source` above the instructions
+    /// compiled from it
+    pub fn write_annotated<W: Write>(
+        out: &mut W,
+        function: &Function,
+        source: &[u8],
+    ) -> fmt::Result {
+        let lines: Vec<&[u8]> = source.split(|byte| *byte == b'\n').collect();
+        write_functions(out, function, Some(&lines))
+    }
+
+    /// Writes the instruction found at `offset` on its own line, returning the offset of the next
+    /// instruction
+    pub fn write_instruction<W: Write>(
+        out: &mut W,
+        sequence: &Sequence,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let instruction = Self::decode(sequence, offset);
         // Print offset of the instruction in the bytecode sequence
-        print!("{:04} ", offset);
+        write!(out, "{:04} ", offset)?;
         // Print information about the source code line. If we are not at the first offset and
         // the current offset line is the same as a previous line, we print `|` to avoid noise.
         // Otherwise, we print the source line
-        let line_token = if offset > 0 && sequence.line(offset) == sequence.line(offset - 1) {
-            "   |".to_string()
+        if offset > 0 && sequence.line(offset) == sequence.line(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            format!("{:04}", sequence.line(offset))
+            write!(out, "{:04} ", instruction.line())?;
+        }
+        writeln!(out, "{instruction}")?;
+        Ok(instruction.next())
+    }
+
+    /// Decodes the instruction found at `offset`
+    pub fn decode(sequence: &Sequence, offset: usize) -> DecodedInstruction {
+        let code = sequence.code();
+        let opcode = OpCode::from(code[offset]);
+        let len = 1 + opcode.operand_len();
+        let Some(bytes) = code.get(offset + 1..offset + len) else {
+            return DecodedInstruction {
+                offset,
+                len: code.len() - offset,
+                location: sequence.location(offset),
+                opcode,
+                operands: Operands::Truncated,
+                constant: None,
+            };
         };
-        print!("{} ", line_token);
 
-        let instruction = sequence.code()[offset].into();
-        match instruction {
-            OpCode::Return => Instruction::simple("OP_RETURN", offset),
-            OpCode::Constant => Instruction::constant("OP_CONSTANT", sequence, offset),
+        // The 2-byte jump distance is relative to the next instruction
+        let target = || {
+            let jump = u16::from_le_bytes([bytes[len - 3], bytes[len - 2]]) as isize;
+            let sign = if let OpCode::Loop = opcode { -1 } else { 1 };
+            (offset + len) as isize + sign * jump
+        };
+        let operands = match opcode {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::AddConstant => Operands::Constant(usize::from(bytes[0])),
             OpCode::ConstantLong => {
-                Instruction::constant_long("OP_CONSTANT_LONG", sequence, offset)
+                Operands::Constant(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize)
+            }
+            OpCode::Call | OpCode::GetLocal | OpCode::SetLocal => Operands::Byte(bytes[0]),
+            OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop => Operands::Jump(target()),
+            OpCode::Invoke => Operands::Invoke(usize::from(bytes[0]), bytes[1]),
+            OpCode::IncrementLocal => Operands::Local(usize::from(bytes[0]), bytes[1]),
+            OpCode::JumpIfNotLessLocal => {
+                Operands::LocalJump(usize::from(bytes[0]), bytes[1], target())
+            }
+            _ => Operands::None,
+        };
+        let constant = match operands {
+            Operands::Constant(idx)
+            | Operands::Invoke(idx, _)
+            | Operands::Local(idx, _)
+            | Operands::LocalJump(idx, _, _) => sequence.constants().get(idx).cloned(),
+            _ => None,
+        };
+        DecodedInstruction {
+            offset,
+            len,
+            location: sequence.location(offset),
+            opcode,
+            operands,
+            constant,
+        }
+    }
+
+    /// Decodes every instruction of the sequence
+    pub fn decode_sequence(sequence: &Sequence) -> Vec<DecodedInstruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < sequence.code().len() {
+            let instruction = Self::decode(sequence, offset);
+            offset = instruction.next();
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    /// Describes the instructions of the sequence as a JSON object, holding its name and the list
+    /// of its instructions
+    pub fn to_json(sequence: &Sequence, name: &str) -> String {
+        let instructions: Vec<String> = Self::decode_sequence(sequence)
+            .iter()
+            .map(|instruction| format!("    {}", instruction.to_json()))
+            .collect();
+        format!(
+            "{{\n  \"name\": {},\n  \"instructions\": [\n{}\n  ]\n}}\n",
+            quote(name),
+            instructions.join(",\n")
+        )
+    }
+
+    /// Writes the sequence in the textual form read by `Assembler::assemble`, such that
+    /// assembling it gives back the same code, lines and constants
+    pub fn to_assembly(sequence: &Sequence) -> String {
+        let mut out = String::new();
+        write_assembly(&mut out, sequence, 0);
+        out
+    }
+}
+
+fn write_functions<W: Write>(
+    out: &mut W,
+    function: &Function,
+    source: Option<&[&[u8]]>,
+) -> fmt::Result {
+    // Functions show their arity, which the assembler needs to read the listing back
+    let name = match function.name() {
+        Some(_) => format!("{function} (arity {})", function.arity()),
+        None => function.to_string(),
+    };
+    write_listing(out, function.sequence(), &name, source)?;
+    for constant in function.sequence().constants() {
+        if let Some(Obj::Function(function)) = constant.as_obj() {
+            write_functions(out, function, source)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the instructions of the sequence, each group of instructions compiled from the same line
+// being preceded by that line when the `source` lines are given
+fn write_listing<W: Write>(
+    out: &mut W,
+    sequence: &Sequence,
+    name: &str,
+    source: Option<&[&[u8]]>,
+) -> fmt::Result {
+    writeln!(out, "== {} ==", name)?;
+    writeln!(out, "{}", sequence.constant_stats())?;
+    // Start at the beginning of the sequence
+    let mut offset = 0;
+    // While we still have bytes
+    while offset < sequence.code().len() {
+        let line = sequence.line(offset);
+        let is_new_line = offset == 0 || line != sequence.line(offset - 1);
+        // Lines start at 1, and 0 is used for code which does not come from a line
+        let text = (line as usize)
+            .checked_sub(1)
+            .and_then(|idx| source?.get(idx));
+        if let (true, Some(text)) = (is_new_line, text) {
+            let text = String::from_utf8_lossy(text);
+            writeln!(out, "{line:>9} | {}", text.trim_end())?;
+        }
+        // Disassemble an instruction and move the cursor to the first byte after that
+        // instruction
+        offset = Disassembler::write_instruction(out, sequence, offset)?;
+    }
+    Ok(())
+}
+
+/// An instruction of a sequence, along with its decoded operands
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedInstruction {
+    offset: usize,
+    // Number of bytes the instruction spans, including its operands
+    len: usize,
+    location: Location,
+    opcode: OpCode,
+    operands: Operands,
+    // Constant the instruction refers to, if it exists
+    constant: Option<Value>,
+}
+
+impl DecodedInstruction {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Offset of the instruction following this one
+    pub fn next(&self) -> usize {
+        self.offset + self.len
+    }
+
+    pub fn line(&self) -> u32 {
+        self.location.line()
+    }
+
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> Operands {
+        self.operands
+    }
+
+    pub fn constant(&self) -> Option<&Value> {
+        self.constant.as_ref()
+    }
+
+    /// Describes the instruction as a JSON object
+    pub fn to_json(&self) -> String {
+        let operands = match self.operands {
+            Operands::None => match self.opcode {
+                OpCode::Unknown(byte) => format!("[{byte}]"),
+                _ => "[]".to_string(),
+            },
+            Operands::Constant(idx) => format!("[{idx}]"),
+            Operands::Byte(byte) => format!("[{byte}]"),
+            Operands::Jump(target) => format!("[{target}]"),
+            Operands::Invoke(idx, arg_count) => format!("[{idx}, {arg_count}]"),
+            Operands::Local(idx, slot) => format!("[{idx}, {slot}]"),
+            Operands::LocalJump(idx, slot, target) => format!("[{idx}, {slot}, {target}]"),
+            Operands::Truncated => "null".to_string(),
+        };
+        let constant = match &self.constant {
+            Some(constant) => format!(
+                "{{\"type\": {}, \"value\": {}}}",
+                quote(constant.type_name()),
+                json_value(constant)
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"offset\": {}, \"line\": {}, \"column\": {}, \"opcode\": {}, \"operands\": {operands}, \"constant\": {constant}}}",
+            self.offset,
+            self.location.line(),
+            self.location.column(),
+            quote(self.opcode.mnemonic()),
+        )
+    }
+}
+
+/// Operands of a decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operands {
+    None,
+    // Index of the constant the instruction refers to
+    Constant(usize),
+    // Stack slot of a local variable, or number of arguments of a call
+    Byte(u8),
+    // Offset the jump lands on, which is only negative in malformed code
+    Jump(isize),
+    // Index of the constant holding the method's name, followed by the number of arguments
+    Invoke(usize, u8),
+    // Index of a constant, followed by the stack slot of the local variable it is combined with
+    Local(usize, u8),
+    // Same as `Local`, followed by the offset the jump lands on
+    LocalJump(usize, u8, isize),
+    // The code ends before the operands
+    Truncated,
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let name = self.opcode.mnemonic();
+        // Strings are quoted, such that they cannot be mistaken for other values
+        let constant = |f: &mut fmt::Formatter<'_>| match &self.constant {
+            Some(constant) => match constant.as_str() {
+                Some(string) => write!(f, " -> value: {string:?}"),
+                None => write!(f, " -> value: {constant}"),
+            },
+            None => write!(f, " -> no constant"),
+        };
+        match self.operands {
+            Operands::None => match self.opcode {
+                OpCode::Unknown(byte) => write!(f, "Unknown opcode {byte}"),
+                _ => write!(f, "{name}"),
+            },
+            Operands::Constant(idx) => {
+                write!(f, "{name} {idx}")?;
+                constant(f)
+            }
+            Operands::Byte(byte) => write!(f, "{name} {byte}"),
+            Operands::Jump(target) => write!(f, "{name} {} -> {target}", self.offset),
+            Operands::Invoke(idx, arg_count) => {
+                write!(f, "{name} ({arg_count} args) {idx}")?;
+                constant(f)
             }
-            OpCode::Negate => Instruction::simple("OP_NEGATE", offset),
-            OpCode::Add => Instruction::simple("OP_ADD", offset),
-            OpCode::Sub => Instruction::simple("OP_SUBTRACT", offset),
-            OpCode::Mul => Instruction::simple("OP_MULTIPLY", offset),
-            OpCode::Div => Instruction::simple("OP_DIVIDE", offset),
-            OpCode::Unknown(byte) => {
-                println!("Unknown opcode {}", byte);
-                offset + 1
+            Operands::Local(idx, slot) => {
+                write!(f, "{name} (slot {slot}) {idx}")?;
+                constant(f)
             }
+            Operands::LocalJump(idx, slot, target) => {
+                write!(f, "{name} (slot {slot}) {idx}")?;
+                constant(f)?;
+                write!(f, ", {} -> {target}", self.offset)
+            }
+            Operands::Truncated => write!(f, "{name} <truncated>"),
         }
     }
 }
 
-pub struct Instruction;
+fn json_value(value: &Value) -> String {
+    match value.view() {
+        ValueRef::Nil => "null".to_string(),
+        ValueRef::Bool(boolean) => boolean.to_string(),
+        // JSON has no representation for infinities and NaN
+        ValueRef::Number(number) if number.is_finite() => number.to_string(),
+        ValueRef::Obj(obj) => match obj {
+            Obj::String(string) => quote(string),
+            obj => quote(&obj.to_string()),
+        },
+        _ => quote(&value.to_string()),
+    }
+}
+
+// A piece of code, as written in assembly
+enum Item {
+    // An instruction that can be written with its mnemonic
+    Instruction(usize, OpCode),
+    // A byte which is written as is, as it is not part of a valid instruction
+    Byte(usize),
+}
+
+fn write_assembly(out: &mut String, sequence: &Sequence, indent: usize) {
+    let code = sequence.code();
+    let constants = sequence.constants();
+    let items = decode(sequence);
+    let targets: HashSet<usize> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Instruction(offset, opcode) => jump_target(sequence, *offset, *opcode),
+            Item::Byte(_) => None,
+        })
+        .collect();
+
+    // Constants are written in the instructions using them when they are used in the order of
+    // the pool and are not repeated in it, such that assembling them fills the pool again.
+    // Otherwise, the whole pool is declared upfront and instructions refer to its indices.
+    let mut first_uses = Vec::new();
+    for item in &items {
+        if let Item::Instruction(offset, opcode) = item {
+            if let Some(idx) = constant_index(sequence, *offset, *opcode) {
+                if !first_uses.contains(&idx) {
+                    first_uses.push(idx);
+                }
+            }
+        }
+    }
+    let inline = first_uses.iter().copied().eq(0..constants.len())
+        && constants.iter().enumerate().all(|(idx, constant)| {
+            sequence
+                .find_constant(constant)
+                .is_none_or(|first| first == idx)
+        });
+    if !inline {
+        for constant in constants {
+            write_function(out, constant, indent);
+            let _ = writeln!(out, "{:indent$}.const {}", "", literal(constant));
+        }
+    }
+
+    let mut location = None;
+    let mut written = 0;
+    for item in &items {
+        let (offset, opcode) = match item {
+            Item::Instruction(offset, opcode) => (*offset, Some(*opcode)),
+            Item::Byte(offset) => (*offset, None),
+        };
+        let constant = opcode.and_then(|opcode| constant_index(sequence, offset, opcode));
+        // Functions are declared right before the first instruction using them
+        let operand = match constant {
+            Some(idx) if inline && idx == written => {
+                written += 1;
+                write_function(out, &constants[idx], indent);
+                Some(literal(&constants[idx]))
+            }
+            Some(idx) => Some(format!("#{idx}")),
+            None => None,
+        };
 
-impl Instruction {
-    pub fn simple(name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+        if targets.contains(&offset) {
+            let _ = writeln!(out, "{:indent$}L{offset:04}:", "");
+        }
+        if location != Some(sequence.location(offset)) {
+            location = Some(sequence.location(offset));
+            let _ = write_location(out, sequence.location(offset), indent);
+        }
+        let _ = write!(out, "{:indent$}    ", "");
+        let Some(opcode) = opcode else {
+            let _ = writeln!(out, ".byte {}", code[offset]);
+            continue;
+        };
+        let _ = write!(out, "{}", opcode.mnemonic());
+        if let Some(operand) = operand {
+            let _ = write!(out, " {operand}");
+        }
+        match opcode {
+            OpCode::Call | OpCode::GetLocal | OpCode::SetLocal => {
+                let _ = write!(out, " {}", code[offset + 1]);
+            }
+            OpCode::Invoke | OpCode::IncrementLocal | OpCode::JumpIfNotLessLocal => {
+                let _ = write!(out, " {}", code[offset + 2]);
+            }
+            _ => {}
+        }
+        if opcode.is_jump() {
+            let target = jump_target(sequence, offset, opcode).expect("Checked while decoding");
+            let _ = write!(out, " L{target:04}");
+        }
+        out.push('\n');
+    }
+    if targets.contains(&code.len()) {
+        let _ = writeln!(out, "{:indent$}L{:04}:", "", code.len());
     }
+}
 
-    pub fn constant(name: &str, sequence: &Sequence, offset: usize) -> usize {
-        // Get the constant index
-        let constant_idx = sequence.code()[offset + 1];
-        // Get thec constant, based on index
-        let constant = sequence.constant(constant_idx as usize);
-        println!("{name} {constant_idx} -> value: {constant}");
-        offset + 2
+// Splits the code into instructions, falling back to bytes wherever the code cannot be written
+// as an instruction
+fn decode(sequence: &Sequence) -> Vec<Item> {
+    let code = sequence.code();
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::from(code[offset]);
+        let len = 1 + opcode.operand_len();
+        let is_valid = !matches!(opcode, OpCode::Unknown(_))
+            && offset + len <= code.len()
+            // The assembler attributes a whole instruction to the same location
+            && (offset..offset + len).all(|idx| sequence.location(idx) == sequence.location(offset))
+            && constant_index(sequence, offset, opcode)
+                .is_none_or(|idx| idx < sequence.constants().len())
+            && (!opcode.is_jump() || jump_target(sequence, offset, opcode).is_some());
+        if is_valid {
+            items.push(Item::Instruction(offset, opcode));
+            offset += len;
+        } else {
+            items.push(Item::Byte(offset));
+            offset += 1;
+        }
     }
 
-    pub fn constant_long(name: &str, sequence: &Sequence, offset: usize) -> usize {
-        // Get the constant index which comprises of the next 3 bytes.
-        let mut constant_idx = [0; 4];
-        for (idx, byte) in sequence.code()[offset + 1..].iter().take(3).enumerate() {
-            constant_idx[idx] = *byte;
+    // Labels can only be declared between instructions, so the instructions jumps land in the
+    // middle of are written as bytes
+    let targets: Vec<usize> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Instruction(offset, opcode) => jump_target(sequence, *offset, *opcode),
+            Item::Byte(_) => None,
+        })
+        .collect();
+    items
+        .into_iter()
+        .flat_map(|item| match item {
+            Item::Instruction(offset, opcode) => {
+                let end = offset + 1 + opcode.operand_len();
+                if targets
+                    .iter()
+                    .any(|target| (offset + 1..end).contains(target))
+                {
+                    (offset..end).map(Item::Byte).collect()
+                } else {
+                    vec![Item::Instruction(offset, opcode)]
+                }
+            }
+            item => vec![item],
+        })
+        .collect()
+}
+
+// Index of the constant the instruction at `offset` refers to, if any
+fn constant_index(sequence: &Sequence, offset: usize, opcode: OpCode) -> Option<usize> {
+    let code = sequence.code();
+    match opcode {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::Invoke
+        | OpCode::AddConstant
+        | OpCode::IncrementLocal
+        | OpCode::JumpIfNotLessLocal => code.get(offset + 1).map(|idx| usize::from(*idx)),
+        OpCode::ConstantLong => {
+            let bytes = code.get(offset + 1..offset + 4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize)
         }
-        let constant_idx = u32::from_le_bytes(constant_idx);
-        // Get thec constant, based on index
-        let constant = sequence.constant(constant_idx as usize);
-        println!("{name} {constant_idx} -> value: {constant}");
-        offset + 4
+        _ => None,
+    }
+}
+
+// Offset the jump at `offset` lands on, if it is a jump landing within the code
+fn jump_target(sequence: &Sequence, offset: usize, opcode: OpCode) -> Option<usize> {
+    if !opcode.is_jump() {
+        return None;
+    }
+    let code = sequence.code();
+    let next = offset + 1 + opcode.operand_len();
+    let bytes = code.get(next - 2..next)?;
+    let jump = usize::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+    let target = match opcode {
+        OpCode::Loop => next.checked_sub(jump)?,
+        _ => next + jump,
+    };
+    (target <= code.len()).then_some(target)
+}
+
+// Writes the `.line` directive attributing the next bytes to `location`
+fn write_location(out: &mut String, location: Location, indent: usize) -> fmt::Result {
+    write!(out, "{:indent$}.line {}", "", location.line())?;
+    match (location.column(), location.file()) {
+        (column, Some(file)) => write!(out, " {column} {file}")?,
+        (0, None) => {}
+        (column, None) => write!(out, " {column}")?,
+    }
+    writeln!(out)
+}
+
+// Declares the function held by `constant`, if it holds one
+fn write_function(out: &mut String, constant: &Value, indent: usize) {
+    let Some(Obj::Function(function)) = constant.as_obj() else {
+        return;
+    };
+    let name = function.name().unwrap_or("-");
+    let _ = writeln!(out, "{:indent$}.function {name} {}", "", function.arity());
+    write_assembly(out, function.sequence(), indent + 4);
+    let _ = writeln!(out, "{:indent$}.end", "");
+}
+
+// Writes a constant the way the assembler reads it
+fn literal(constant: &Value) -> String {
+    match constant.view() {
+        ValueRef::Nil => "nil".to_string(),
+        ValueRef::Bool(boolean) => boolean.to_string(),
+        ValueRef::Number(number) => number.to_string(),
+        ValueRef::Obj(obj) => match obj {
+            Obj::String(string) => format!("{string:?}"),
+            Obj::Function(function) => function.to_string(),
+            // Anything else only exists at runtime and cannot be assembled, so `nil` keeps its
+            // place in the pool
+            _ => "nil".to_string(),
+        },
     }
 }
@@ -0,0 +1,512 @@
+//! Line editor used by the interactive prompt. It drives the terminal itself, through `stty` and
+//! ANSI escape sequences, and falls back to plain line reads when the input is not a terminal.
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Name of the file, in the home directory, keeping the history across sessions
+const HISTORY_FILE: &str = ".malis_history";
+/// Number of history entries kept around
+const HISTORY_MAX: usize = 1000;
+
+/// Outcome of reading a line
+pub(crate) enum Input {
+    // A line, without its terminating newline
+    Line(String),
+    // The user pressed `Ctrl-C`, abandoning what they were typing
+    Interrupted,
+    // The input reached its end, or the user pressed `Ctrl-D` on an empty line
+    Eof,
+}
+
+pub(crate) struct Editor {
+    // Previously entered lines, from the oldest to the newest
+    history: Vec<String>,
+    // File the history is saved to, if we know where the home directory is
+    history_path: Option<PathBuf>,
+    // Whether lines can be edited in a terminal, or are merely read
+    is_terminal: bool,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        let is_terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+        // Lines piped into the prompt are not worth remembering
+        let history_path = std::env::var_os("HOME")
+            .filter(|_| is_terminal)
+            .map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        let mut history: Vec<String> = history_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|history| history.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+
+        // Keep the history file from growing forever
+        if history.len() > HISTORY_MAX {
+            history.drain(..history.len() - HISTORY_MAX);
+            if let Some(path) = &history_path {
+                let mut contents = history.join("\n");
+                contents.push('\n');
+                let _ = fs::write(path, contents);
+            }
+        }
+
+        Self {
+            history,
+            history_path,
+            is_terminal,
+        }
+    }
+
+    /// Shows `prompt` and reads a line. In a terminal, the line can be edited, browsed from the
+    /// history and completed with the `words` that start like the word under the cursor.
+    pub fn read_line(&mut self, prompt: &str, words: &[String]) -> io::Result<Input> {
+        if !self.is_terminal {
+            return read_plain(prompt);
+        }
+        // Without control over the terminal we cannot do better than plain reads
+        let Ok(raw_mode) = RawMode::enable() else {
+            self.is_terminal = false;
+            return read_plain(prompt);
+        };
+        let input = self.edit(prompt, words);
+        drop(raw_mode);
+
+        if let Ok(Input::Line(line)) = &input {
+            self.add_history(line);
+        }
+        input
+    }
+
+    fn edit(&self, prompt: &str, words: &[String]) -> io::Result<Input> {
+        let mut stdout = io::stdout();
+        let mut line = Line::default();
+        // Position in the history of the line being edited, the end being the new line
+        let mut position = self.history.len();
+        // The new line, kept aside while browsing the history
+        let mut draft = String::new();
+
+        line.render(&mut stdout, prompt)?;
+        loop {
+            match read_key()? {
+                Key::Enter => {
+                    writeln!(stdout)?;
+                    return Ok(Input::Line(line.text()));
+                }
+                Key::Ctrl(b'c') => {
+                    writeln!(stdout, "^C")?;
+                    return Ok(Input::Interrupted);
+                }
+                Key::Eof => {
+                    writeln!(stdout)?;
+                    return Ok(Input::Eof);
+                }
+                Key::Ctrl(b'd') if line.is_empty() => {
+                    writeln!(stdout)?;
+                    return Ok(Input::Eof);
+                }
+                Key::Ctrl(b'd') | Key::Delete => line.delete(),
+                Key::Backspace => line.backspace(),
+                Key::Left | Key::Ctrl(b'b') => line.left(),
+                Key::Right | Key::Ctrl(b'f') => line.right(),
+                Key::Home | Key::Ctrl(b'a') => line.home(),
+                Key::End | Key::Ctrl(b'e') => line.end(),
+                Key::Ctrl(b'k') => line.kill_end(),
+                Key::Ctrl(b'u') => line.kill_start(),
+                Key::Ctrl(b'w') => line.kill_word(),
+                Key::Up | Key::Ctrl(b'p') if position > 0 => {
+                    if position == self.history.len() {
+                        draft = line.text();
+                    }
+                    position -= 1;
+                    line.set(&self.history[position]);
+                }
+                Key::Down | Key::Ctrl(b'n') if position < self.history.len() => {
+                    position += 1;
+                    line.set(self.history.get(position).unwrap_or(&draft));
+                }
+                Key::Tab => {
+                    // List the candidates when the completion is ambiguous
+                    let candidates = line.complete(words);
+                    if !candidates.is_empty() {
+                        writeln!(stdout)?;
+                        writeln!(stdout, "{}", candidates.join("  "))?;
+                    }
+                }
+                Key::Ctrl(b'r') => {
+                    if let Some(input) = self.search(prompt, &mut line)? {
+                        return Ok(input);
+                    }
+                }
+                Key::Char(c) => line.insert(c),
+                _ => {}
+            }
+            line.render(&mut stdout, prompt)?;
+        }
+    }
+
+    // Searches the history backwards for the lines containing what the user types. `Enter` runs
+    // the match, while any other key leaves it in `line` for editing.
+    fn search(&self, prompt: &str, line: &mut Line) -> io::Result<Option<Input>> {
+        let mut stdout = io::stdout();
+        let mut query = String::new();
+        // Index of the history entry matching the query
+        let mut found = None;
+
+        loop {
+            let matched = found.map_or("", |idx: usize| self.history[idx].as_str());
+            write!(stdout, "\r(reverse-i-search)`{query}': {matched}\x1b[K")?;
+            stdout.flush()?;
+
+            match read_key()? {
+                Key::Char(c) => {
+                    query.push(c);
+                    // The current match may still contain the longer query
+                    let before = found.map_or(self.history.len(), |idx| idx + 1);
+                    found = search_history(&self.history, &query, before);
+                }
+                Key::Backspace => {
+                    query.pop();
+                    found = search_history(&self.history, &query, self.history.len());
+                }
+                // Move on to an older match, if there is one
+                Key::Ctrl(b'r') => {
+                    if let Some(idx) = found {
+                        found = search_history(&self.history, &query, idx).or(found);
+                    }
+                }
+                // Give up on the search, leaving the line as it was
+                Key::Ctrl(b'c') | Key::Ctrl(b'g') => return Ok(None),
+                Key::Enter => {
+                    if let Some(idx) = found {
+                        line.set(&self.history[idx]);
+                    }
+                    line.render(&mut stdout, prompt)?;
+                    writeln!(stdout)?;
+                    return Ok(Some(Input::Line(line.text())));
+                }
+                _ => {
+                    if let Some(idx) = found {
+                        line.set(&self.history[idx]);
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn add_history(&mut self, line: &str) {
+        // Blank lines and repetitions of the previous line only get in the way
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+
+        if let Some(path) = &self.history_path {
+            // Losing the history is not worth interrupting the session for
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+}
+
+/// Returns the index of the newest entry, among the ones before `before`, that contains `query`
+pub(crate) fn search_history(history: &[String], query: &str, before: usize) -> Option<usize> {
+    history[..before]
+        .iter()
+        .rposition(|entry| entry.contains(query))
+}
+
+/// A line being edited, along with the position of the cursor in it
+#[derive(Default)]
+pub(crate) struct Line {
+    chars: Vec<char>,
+    // Index of the character the cursor is on, which is past the end when appending
+    cursor: usize,
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Replaces the contents of the line, moving the cursor to the end
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    pub fn kill_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+
+    pub fn kill_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    /// Deletes the word before the cursor, along with the spaces following it
+    pub fn kill_word(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Completes the identifier before the cursor with the longest prefix shared by the `words`
+    /// starting like it. When that does not add anything and several words match, they are
+    /// returned such that the user could pick one.
+    pub fn complete(&mut self, words: &[String]) -> Vec<String> {
+        let mut start = self.cursor;
+        while start > 0
+            && (self.chars[start - 1].is_ascii_alphanumeric() || self.chars[start - 1] == '_')
+        {
+            start -= 1;
+        }
+        if start == self.cursor {
+            return Vec::new();
+        }
+        let prefix: String = self.chars[start..self.cursor].iter().collect();
+
+        let mut candidates: Vec<&String> = words
+            .iter()
+            .filter(|word| word.starts_with(&prefix))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let Some(first) = candidates.first() else {
+            return Vec::new();
+        };
+
+        // Length, in bytes, of the prefix shared by all the candidates
+        let common = candidates.iter().fold(first.len(), |common, word| {
+            let shared: usize = first
+                .chars()
+                .zip(word.chars())
+                .take_while(|(left, right)| left == right)
+                .map(|(c, _)| c.len_utf8())
+                .sum();
+            common.min(shared)
+        });
+        if common > prefix.len() {
+            first[prefix.len()..common]
+                .chars()
+                .for_each(|c| self.insert(c));
+            return Vec::new();
+        }
+
+        if candidates.len() > 1 {
+            candidates.into_iter().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Redraws the line and moves the terminal's cursor where ours is
+    fn render(&self, out: &mut impl Write, prompt: &str) -> io::Result<()> {
+        write!(out, "\r{prompt}{}\x1b[K", self.text())?;
+        let back = self.chars.len() - self.cursor;
+        if back > 0 {
+            write!(out, "\x1b[{back}D")?;
+        }
+        out.flush()
+    }
+}
+
+/// A key pressed by the user
+enum Key {
+    Char(char),
+    // A letter pressed along with `Ctrl`
+    Ctrl(u8),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    // The terminal stopped sending input
+    Eof,
+    Unknown,
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Reads the next key pressed, decoding escape sequences and UTF-8 characters
+fn read_key() -> io::Result<Key> {
+    let mut stdin = io::stdin().lock();
+    let Some(byte) = read_byte(&mut stdin)? else {
+        return Ok(Key::Eof);
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        // Terminals send either `DEL` or `Ctrl-H` for backspace
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => read_escape(&mut stdin)?,
+        0x01..=0x1a => Key::Ctrl(byte - 1 + b'a'),
+        0x00..=0x1f => Key::Unknown,
+        _ => {
+            // The leading byte tells how many bytes the character spans
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read_byte(&mut stdin)?);
+            }
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|c| c.chars().next())
+                .map_or(Key::Unknown, Key::Char)
+        }
+    };
+    Ok(key)
+}
+
+// Decodes the escape sequences sent for arrows and the editing keys, like `ESC [ A`
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    if !matches!(read_byte(input)?, Some(b'[' | b'O')) {
+        return Ok(Key::Escape);
+    }
+    let key = match read_byte(input)? {
+        Some(b'A') => Key::Up,
+        Some(b'B') => Key::Down,
+        Some(b'C') => Key::Right,
+        Some(b'D') => Key::Left,
+        Some(b'H') => Key::Home,
+        Some(b'F') => Key::End,
+        // Sequences like `ESC [ 3 ~`, numbering the key
+        Some(digit @ b'0'..=b'9') => {
+            let mut number = vec![digit];
+            while let Some(byte) = read_byte(input)? {
+                if byte == b'~' {
+                    break;
+                }
+                number.push(byte);
+            }
+            match number.as_slice() {
+                b"1" | b"7" => Key::Home,
+                b"3" => Key::Delete,
+                b"4" | b"8" => Key::End,
+                _ => Key::Unknown,
+            }
+        }
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+// Reads a line without any editing, as the terminal or the pipe hands it to us
+fn read_plain(prompt: &str) -> io::Result<Input> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{prompt}")?;
+    stdout.flush()?;
+
+    let mut line = String::new();
+    // If no bytes were read, it means we reached `End-of-File` or `Ctrl-D` was pressed.
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(Input::Eof);
+    }
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(Input::Line(line))
+}
+
+/// Switches the terminal to a mode where we get each key as soon as it is pressed, without the
+/// terminal echoing it. The previous mode is restored when dropped.
+struct RawMode {
+    // Settings of the terminal before we changed them, as printed by `stty -g`
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        // `-isig` and `-ixon` let us handle `Ctrl-C`, `Ctrl-S` and friends ourselves
+        stty(&[
+            "-icanon", "-echo", "-isig", "-ixon", "min", "1", "time", "0",
+        ])?;
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// Runs `stty` on the terminal we read from, returning what it printed
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty could not configure the terminal"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,312 @@
+//! Binary format of compiled scripts, such that they can be saved and run without compiling them
+//! again. All integers are stored in Little Endian. A file is laid out as:
+//!
+//! - the `MAGIC` bytes, followed by the `u16` format `VERSION`
+//! - the script's sequence, which is:
+//!   - the `u32` number of code bytes, followed by the bytes
+//!   - the `u32` number of runs in the run-length lines table, followed by each run as a `u32`
+//!     line and the `u32` number of bytes in the run
+//!   - the `u32` number of column changes, followed by each change as the `u32` offset from which
+//!     it applies and the `u32` column
+//!   - the `u32` number of file changes, followed by each change as the `u32` offset from which it
+//!     applies and the `u32` file id, which is `u32::MAX` for code without a file
+//!   - the `u32` number of constants, followed by each constant as a tag byte and its payload
+//!
+//! Function constants hold their name, their arity and their own sequence.
+use crate::object::{Function, Obj};
+use crate::{Locations, Sequence, Value, ValueRef};
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+/// Bytes every compiled file starts with
+pub const MAGIC: [u8; 4] = *b"MMSC";
+/// Version of the format, increased whenever the layout or the instruction set changes
+pub const VERSION: u16 = 3;
+
+// Functions nested deeper than this are rejected, such that a malicious file cannot overflow the
+// stack while being read
+const NESTING_MAX: usize = 256;
+
+// File id written for code which does not come from a file
+const NO_FILE: u32 = u32::MAX;
+
+// Tags identifying the type of each constant
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+impl Sequence {
+    /// Returns whether `bytes` start like a compiled file
+    pub fn is_compiled(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// Writes the sequence, along with the functions it declares, in the compiled file format
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), FormatError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        write_sequence(&mut writer, self)?;
+        Ok(writer.flush()?)
+    }
+
+    /// Reads a sequence written by `Sequence::write_to`
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, FormatError> {
+        read_file(&mut reader).map_err(|err| match err {
+            FormatError::StdIO(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                FormatError::Truncated
+            }
+            err => err,
+        })
+    }
+}
+
+fn read_file<R: Read>(reader: &mut R) -> Result<Sequence, FormatError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+    let version = read_u16(reader)?;
+    if version != VERSION {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+    read_sequence(reader, 0)
+}
+
+fn write_sequence<W: Write>(writer: &mut W, sequence: &Sequence) -> Result<(), FormatError> {
+    write_len(writer, sequence.code().len())?;
+    writer.write_all(sequence.code())?;
+
+    let locations = sequence.locations();
+    write_len(writer, locations.lines().len())?;
+    // Runs are stored with their length rather than where they end, which is the same for every
+    // sequence
+    let mut start = 0;
+    for (line, end) in locations.lines() {
+        writer.write_all(&line.to_le_bytes())?;
+        writer.write_all(&end.saturating_sub(start).to_le_bytes())?;
+        start = *end;
+    }
+    write_len(writer, locations.columns().len())?;
+    for (offset, column) in locations.columns() {
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&column.to_le_bytes())?;
+    }
+    write_len(writer, locations.files().len())?;
+    for (offset, file) in locations.files() {
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&file.unwrap_or(NO_FILE).to_le_bytes())?;
+    }
+
+    write_len(writer, sequence.constants().len())?;
+    for constant in sequence.constants() {
+        write_constant(writer, constant)?;
+    }
+    Ok(())
+}
+
+fn write_constant<W: Write>(writer: &mut W, constant: &Value) -> Result<(), FormatError> {
+    match constant.view() {
+        ValueRef::Nil => writer.write_all(&[TAG_NIL])?,
+        ValueRef::Bool(false) => writer.write_all(&[TAG_FALSE])?,
+        ValueRef::Bool(true) => writer.write_all(&[TAG_TRUE])?,
+        ValueRef::Number(number) => {
+            writer.write_all(&[TAG_NUMBER])?;
+            writer.write_all(&number.to_bits().to_le_bytes())?;
+        }
+        ValueRef::Obj(obj) => match obj {
+            Obj::String(string) => {
+                writer.write_all(&[TAG_STRING])?;
+                write_str(writer, string)?;
+            }
+            Obj::Function(function) => {
+                writer.write_all(&[TAG_FUNCTION])?;
+                match function.name() {
+                    Some(name) => {
+                        writer.write_all(&[1])?;
+                        write_str(writer, name)?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+                write_len(writer, function.arity())?;
+                write_sequence(writer, function.sequence())?;
+            }
+            // Anything else only exists at runtime, and never ends up in a constant pool produced
+            // by the compiler
+            obj => return Err(FormatError::UnsupportedConstant(obj.type_name())),
+        },
+    }
+    Ok(())
+}
+
+fn write_str<W: Write>(writer: &mut W, string: &str) -> Result<(), FormatError> {
+    write_len(writer, string.len())?;
+    Ok(writer.write_all(string.as_bytes())?)
+}
+
+fn write_len<W: Write>(writer: &mut W, len: usize) -> Result<(), FormatError> {
+    let len = u32::try_from(len).map_err(|_| FormatError::TooLarge(len))?;
+    Ok(writer.write_all(&len.to_le_bytes())?)
+}
+
+fn read_sequence<R: Read>(reader: &mut R, depth: usize) -> Result<Sequence, FormatError> {
+    if depth > NESTING_MAX {
+        return Err(FormatError::TooDeep);
+    }
+    let code = read_bytes(reader)?;
+
+    let lines_len = read_u32(reader)?;
+    let mut lines = Vec::new();
+    let mut end: u32 = 0;
+    for _ in 0..lines_len {
+        let line = read_u32(reader)?;
+        let len = read_u32(reader)?;
+        end = end
+            .checked_add(len)
+            .ok_or(FormatError::TooLarge(end as usize + len as usize))?;
+        lines.push((line, end));
+    }
+    let columns_len = read_u32(reader)?;
+    let mut columns = Vec::new();
+    for _ in 0..columns_len {
+        columns.push((read_u32(reader)?, read_u32(reader)?));
+    }
+    let files_len = read_u32(reader)?;
+    let mut files = Vec::new();
+    for _ in 0..files_len {
+        let offset = read_u32(reader)?;
+        let file = match read_u32(reader)? {
+            NO_FILE => None,
+            file => Some(file),
+        };
+        files.push((offset, file));
+    }
+    let locations = Locations::from_parts(lines, columns, files);
+
+    let constants_len = read_u32(reader)?;
+    let mut constants = Vec::new();
+    for _ in 0..constants_len {
+        constants.push(read_constant(reader, depth)?);
+    }
+    Ok(Sequence::from_parts(code, locations, constants))
+}
+
+fn read_constant<R: Read>(reader: &mut R, depth: usize) -> Result<Value, FormatError> {
+    let value = match read_u8(reader)? {
+        TAG_NIL => Value::nil(),
+        TAG_FALSE => Value::from(false),
+        TAG_TRUE => Value::from(true),
+        TAG_NUMBER => {
+            let mut bits = [0; 8];
+            reader.read_exact(&mut bits)?;
+            Value::from(f64::from_bits(u64::from_le_bytes(bits)))
+        }
+        TAG_STRING => Value::from(read_string(reader)?),
+        TAG_FUNCTION => {
+            let name = match read_u8(reader)? {
+                0 => None,
+                _ => Some(read_string(reader)?),
+            };
+            let arity = read_u32(reader)? as usize;
+            let sequence = read_sequence(reader, depth + 1)?;
+            Value::from(Obj::Function(Rc::new(Function::new(name, arity, sequence))))
+        }
+        tag => return Err(FormatError::UnknownTag(tag)),
+    };
+    Ok(value)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, FormatError> {
+    String::from_utf8(read_bytes(reader)?).map_err(|_| FormatError::InvalidString)
+}
+
+// Reads a length followed by as many bytes
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, FormatError> {
+    let len = read_u32(reader)?;
+    let mut bytes = Vec::new();
+    // Going through `take` avoids allocating whatever a corrupted length asks for upfront
+    reader.take(u64::from(len)).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, FormatError> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, FormatError> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, FormatError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// A sequence could not be written or read in the compiled file format
+#[derive(Debug)]
+pub enum FormatError {
+    StdIO(io::Error),
+    // The file does not start with `MAGIC`
+    BadMagic,
+    // The file ends in the middle of the sequence
+    Truncated,
+    // The file was written in another version of the format
+    UnsupportedVersion(u16),
+    // A constant has a tag we do not know of
+    UnknownTag(u8),
+    // A string constant is not valid UTF-8
+    InvalidString,
+    // Functions are nested deeper than `NESTING_MAX`
+    TooDeep,
+    // A constant of this type cannot be written
+    UnsupportedConstant(&'static str),
+    // A length does not fit in the 4 bytes the format stores it in
+    TooLarge(usize),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::StdIO(err) => write!(f, "I/O error: {err}"),
+            Self::BadMagic => write!(f, "Not a compiled Malis file."),
+            Self::Truncated => write!(f, "Compiled file ends unexpectedly."),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Compiled with format version {version}, but only version {VERSION} is supported."
+            ),
+            Self::UnknownTag(tag) => write!(f, "Unknown constant tag {tag}."),
+            Self::InvalidString => write!(f, "String constant is not valid UTF-8."),
+            Self::TooDeep => write!(f, "Functions are nested too deeply."),
+            Self::UnsupportedConstant(type_name) => {
+                write!(f, "Cannot write a {type_name} constant.")
+            }
+            Self::TooLarge(len) => write!(f, "Length {len} does not fit in 4 bytes."),
+        }
+    }
+}
+
+impl Error for FormatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::StdIO(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+crate::impl_from_err!(io::Error, FormatError, StdIO);
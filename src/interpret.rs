@@ -1,26 +1,69 @@
 use crate::compiler::{CompileError, Compiler};
+use crate::{RuntimeError, Value, VerifyError, VM};
+use std::{error::Error, fmt, rc::Rc};
 
 pub struct Interpreter;
 
 impl Interpreter {
-    pub fn interpret(&self, bytes: &[u8]) -> Result<(), InterpretError> {
-        let compiler = Compiler;
-        Ok(compiler.compile(bytes)?)
+    /// Compiles `bytes` with `compiler` and runs the result in `vm`, returning the value the code
+    /// evaluates to.
+    pub fn interpret(
+        &self,
+        vm: &mut VM,
+        bytes: &[u8],
+        compiler: &Compiler,
+    ) -> Result<Value, InterpretError> {
+        let script = compiler.compile(bytes)?;
+        vm.run_function(Rc::new(script))
     }
 }
 
 #[derive(Debug)]
 pub enum InterpretError {
-    // Reports a static error when compiling the source code
-    CompileError(CompileError),
+    // Reports the static errors found when compiling the source code
+    CompileError(Vec<CompileError>),
     // Reports a dynamic error when running the bytecode
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    // Reports malformed bytecode, refused before running it
+    VerifyError(VerifyError),
     // Stack trying to access and element but it's empty
     StackEmpty,
+    // The tracer asked the VM to stop, as debuggers do when told to quit
+    Interrupted,
 }
 
-impl From<CompileError> for InterpretError {
-    fn from(value: CompileError) -> Self {
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::CompileError(errors) if errors.len() == 1 => write!(f, "Compilation failed."),
+            Self::CompileError(errors) => {
+                write!(f, "Compilation failed with {} errors.", errors.len())
+            }
+            Self::RuntimeError(err) => write!(f, "{err}"),
+            Self::VerifyError(err) => write!(f, "{err}"),
+            Self::StackEmpty => write!(f, "The stack is empty."),
+            Self::Interrupted => write!(f, "Execution was interrupted."),
+        }
+    }
+}
+
+impl Error for InterpretError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            // The first error is the one the others likely follow from
+            Self::CompileError(errors) => errors.first().map(|err| err as &(dyn Error + 'static)),
+            Self::RuntimeError(err) => err.source(),
+            Self::VerifyError(err) => err.source(),
+            Self::StackEmpty | Self::Interrupted => None,
+        }
+    }
+}
+
+impl From<Vec<CompileError>> for InterpretError {
+    fn from(value: Vec<CompileError>) -> Self {
         Self::CompileError(value)
     }
 }
+
+crate::impl_from_err!(RuntimeError, InterpretError, RuntimeError);
+crate::impl_from_err!(VerifyError, InterpretError, VerifyError);
@@ -1,88 +1,259 @@
 mod alloc;
+mod asm;
 mod bytecode;
+mod cfg;
 mod compiler;
+mod dap;
+mod debug;
+mod diagnostic;
 mod dis;
+mod editor;
+mod format;
 mod interpret;
+mod json;
+mod native;
+mod object;
+mod peephole;
+mod repl;
 mod scan;
 pub mod token;
+mod trace;
 mod value;
+mod verify;
 mod vm;
 
-pub use bytecode::{OpCode, Sequence};
-pub use dis::Disassembler;
-use interpret::{InterpretError, Interpreter};
-pub use value::Value;
-pub use vm::VM;
+pub use asm::{AssembleError, Assembler};
+pub use bytecode::{ConstantKind, ConstantStats, LocalName, Location, Locations, OpCode, Sequence};
+pub use cfg::{BasicBlock, ControlFlowGraph, Edge};
+use compiler::Compiler;
+pub use dap::DapServer;
+pub use debug::Debugger;
+pub use diagnostic::{Diagnostic, NoteKind};
+pub use dis::{DecodedInstruction, Disassembler, Operands};
+use editor::{Editor, Input};
+pub use format::FormatError;
+pub use interpret::InterpretError;
+use interpret::Interpreter;
+pub use object::{Finalizer, Function, MethodFn, Native, NativeFn, Obj, Userdata, UserdataClass};
+pub use trace::{StackFrame, TraceStep, Tracer, WriteTracer};
+pub use value::{ConversionError, FromValue, Value, ValueRef};
+pub use verify::{VerifyError, VerifyErrorKind};
+pub use vm::{RuntimeError, VM};
 
-use std::{
-    fs,
-    io::{self, Write},
-    path::Path,
-};
+use std::{error::Error, fmt, fs, path::Path, rc::Rc};
 
-#[derive(Default)]
-pub struct MMalis;
+/// An embeddable Malis session. Globals defined by the code it runs persist across runs, such that
+/// the host can keep evaluating code and exchanging values with it.
+pub struct MMalis {
+    // Virtual machine holding the state of the session
+    vm: VM,
+    // Whether the code is optimized when compiling it
+    optimize: bool,
+}
 
 impl MMalis {
+    pub fn new() -> Self {
+        Self {
+            vm: VM::new(),
+            optimize: true,
+        }
+    }
+
     /// Scans, compiles and executes a Malis file found in `path`
     pub fn execute<P: AsRef<Path>>(path: P) -> Result<(), MMalisError> {
         // Create a new `MMalis` object
-        let mut malis = Self;
+        let mut malis = Self::new();
+        malis.execute_file(path)
+    }
+
+    /// Same as `MMalis::execute`, using the natives registered with this instance
+    pub fn execute_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), MMalisError> {
         // Read the file from the path
-        let source = fs::read(path)?;
+        let bytes = fs::read(path)?;
         // Run the contents of the file
-        malis.run(&source, false)
+        self.execute_bytes(&bytes)
+    }
+
+    /// Runs `bytes`, which are either source code or a script compiled by `MMalis::compile`
+    pub fn execute_bytes(&mut self, bytes: &[u8]) -> Result<(), MMalisError> {
+        if Sequence::is_compiled(bytes) {
+            let script = Function::new(None, 0, Sequence::read_from(bytes)?);
+            self.vm.run_function(Rc::new(script))?;
+            Ok(())
+        } else {
+            self.run(bytes, false)
+        }
+    }
+
+    /// Compiles `source` into the bytecode of its top level code, which can be saved with
+    /// `Sequence::write_to` and run later on without compiling it again
+    pub fn compile(source: &[u8]) -> Result<Sequence, MMalisError> {
+        Self::compile_with(Compiler::new(), source)
+    }
+
+    /// Same as `MMalis::compile`, without folding constant expressions nor optimizing the
+    /// bytecode, such that it follows the source code closely
+    pub fn compile_unoptimized(source: &[u8]) -> Result<Sequence, MMalisError> {
+        Self::compile_with(Compiler::new().with_optimizations(false), source)
+    }
+
+    /// Same as `MMalis::compile`, keeping the instructions superinstructions stand for, such that
+    /// their effect can be measured on their own
+    pub fn compile_without_superinstructions(source: &[u8]) -> Result<Sequence, MMalisError> {
+        Self::compile_with(Compiler::new().with_superinstructions(false), source)
+    }
+
+    /// Reads `bytes`, which are either source code or a script compiled by `MMalis::compile`,
+    /// into the bytecode debuggers run. Source code is compiled without optimizations, such that
+    /// stepping through the code follows the source closely
+    pub fn compile_for_debugging(bytes: &[u8]) -> Result<Sequence, MMalisError> {
+        if Sequence::is_compiled(bytes) {
+            Ok(Sequence::read_from(bytes)?)
+        } else {
+            Self::compile_unoptimized(bytes)
+        }
+    }
+
+    fn compile_with(compiler: Compiler, source: &[u8]) -> Result<Sequence, MMalisError> {
+        let script = compiler.compile(source).map_err(InterpretError::from)?;
+        Ok(script.sequence().clone())
+    }
+
+    /// Sets whether the code run in this session is optimized when compiling it, which it is by
+    /// default
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // Compiler for the code run in this session
+    pub(crate) fn compiler(&self, is_repl: bool) -> Compiler {
+        Compiler::new()
+            .with_repl(is_repl)
+            .with_optimizations(self.optimize)
+    }
+
+    /// Registers a Rust `function` which scripts can call as `name` with exactly `arity`
+    /// arguments. Errors returned by the function are reported as runtime errors, on the line of
+    /// the call.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.vm.define_native(name, arity, function);
+    }
+
+    /// Runs `source` in this session, returning the value of its last statement if that is an
+    /// expression statement, or `nil` otherwise.
+    pub fn eval(&mut self, source: &str) -> Result<Value, MMalisError> {
+        let compiler = self.compiler(false);
+        Ok(Interpreter.interpret(&mut self.vm, source.as_bytes(), &compiler)?)
+    }
+
+    /// Reads the global variable `name`, converted to `T`
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, MMalisError> {
+        let value = self
+            .vm
+            .global(name)
+            .ok_or_else(|| MMalisError::UndefinedGlobal(name.to_string()))?;
+        Ok(T::from_value(value)?)
+    }
+
+    /// Defines or overwrites the global variable `name`
+    pub fn set_global<T: Into<Value>>(&mut self, name: &str, value: T) {
+        self.vm.define_global(name, value.into());
     }
 
-    // Main, single point running function for executiong of `bytes`
-    fn run(&mut self, bytes: &[u8], _is_repl: bool) -> Result<(), MMalisError> {
-        let interpreter = Interpreter;
-        Ok(interpreter.interpret(bytes)?)
+    /// Calls the function stored in the global variable `name` with `args`, returning its result
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, MMalisError> {
+        let callee = self
+            .vm
+            .global(name)
+            .ok_or_else(|| MMalisError::UndefinedGlobal(name.to_string()))?
+            .clone();
+        Ok(self.vm.call(callee, args)?)
     }
 
-    /// Fires up an interactive command prompt which is capable of executing code one line at
-    /// a time.
+    /// Gives access to the virtual machine backing the session
+    pub fn vm(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    // Main, single point running function for executiong of `bytes`. Whatever the outcome, the
+    // globals defined so far are kept for the next run
+    fn run(&mut self, bytes: &[u8], is_repl: bool) -> Result<(), MMalisError> {
+        let compiler = self.compiler(is_repl);
+        Interpreter.interpret(&mut self.vm, bytes, &compiler)?;
+        Ok(())
+    }
+
+    /// Fires up an interactive command prompt which is capable of executing code one input at
+    /// a time. An input spans multiple lines for as long as it has unclosed braces, parentheses
+    /// or strings, or until an empty line is entered. Lines starting with `:` are meta-commands,
+    /// listed by `:help`. In a terminal, lines can be edited, recalled from the history kept in
+    /// `~/.malis_history`, searched with `Ctrl-R` and completed with `Tab`.
     // Also known as "REPL", from Lisp:
     // - Read a line of input
     // - Evaluate it
     // - Print the result
     // - Loop and do it all over again
     pub fn interactive() -> Result<(), MMalisError> {
-        let mut malis = MMalis;
-        // Get new handles to the stdin and stdout streams
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        // Create a new buffer to store the input
+        Self::new().run_prompt()
+    }
+
+    /// Fires up the interactive command prompt of `MMalis::interactive` in this session, such that
+    /// it starts with the globals and options set so far
+    pub fn run_prompt(&mut self) -> Result<(), MMalisError> {
+        // The session lives for the whole prompt, such that each input sees what the previous
+        // ones defined
+        let mut editor = Editor::new();
+        // Create a new buffer to store the input, which may span multiple lines
         let mut buffer = String::new();
+        // Last input we evaluated, which meta-commands can inspect
+        let mut last_input = String::new();
 
         loop {
-            // Write the new line identifier
-            let _ = stdout.write(b"> ")?;
-            // Flush it to make sure we print it
-            stdout.flush()?;
-            // Read the next line
-            let bread = stdin.read_line(&mut buffer)?;
-
-            // If no bytes were read, it means we reached `End-of-File` or `Ctrl-D` was pressed.
-            if bread == 0 {
-                break;
+            // The prompt shows whether we continue the previous line
+            let prompt = if buffer.is_empty() { "> " } else { ".. " };
+            let line = match editor.read_line(prompt, &self.completion_words())? {
+                Input::Line(line) => line,
+                // Abandon the whole input, not only the current line
+                Input::Interrupted => {
+                    buffer.clear();
+                    continue;
+                }
+                Input::Eof => break,
+            };
+
+            // Commands are only recognized at the start of an input
+            if buffer.is_empty() {
+                match line.trim() {
+                    "q" | "quit" | "exit" => break,
+                    "" => continue,
+                    command if command.starts_with(':') => {
+                        if let Err(err) = self.meta_command(command, &last_input) {
+                            print!("{}", err.render("<repl>", b""));
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
             }
 
-            match buffer.as_str().trim() {
-                "q" | "quit" | "exit" => break,
-                _ => {}
+            // Keep reading lines until the input is complete. An empty line forces the input to
+            // be evaluated as is, such that we never get stuck on a missing delimiter
+            let force = line.trim().is_empty();
+            buffer.push_str(&line);
+            buffer.push('\n');
+            if !force && !repl::is_complete(buffer.as_bytes()) {
+                continue;
             }
 
-            // If a line is invalid, we report the error and go to the next iteration. We also
-            // specify the `is_repl` true such that we could evaluate both expressions and
-            // statements
-            if let Err(err) = malis.run(buffer.as_bytes(), true) {
-                println!("Interpreter: {err:?}");
-                stdout.flush()?;
+            // If an input is invalid, we report the error and go to the next iteration. Whatever
+            // ran before the error is kept in the session. We also specify the `is_repl` true such
+            // that we could evaluate both expressions and statements
+            if let Err(err) = self.run(buffer.as_bytes(), true) {
+                print!("{}", err.render("<repl>", buffer.as_bytes()));
             }
 
             // Make sure to clean the buffer for the next iteration
-            buffer.clear();
+            last_input = core::mem::take(&mut buffer);
         }
 
         Ok(())
@@ -101,12 +272,51 @@ macro_rules! impl_from_err {
 
 pub(crate) use impl_from_err;
 
+impl Default for MMalis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 pub enum MMalisError {
     StdIO(std::io::Error),
     InterpretError(InterpretError),
+    // The host asked for a global variable which is not defined
+    UndefinedGlobal(String),
+    // A value handed to the host does not have the expected type
+    Conversion(ConversionError),
+    // A compiled script could not be read or written
+    Format(FormatError),
+}
+
+impl fmt::Display for MMalisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::StdIO(err) => write!(f, "I/O error: {err}"),
+            Self::InterpretError(err) => write!(f, "{err}"),
+            Self::UndefinedGlobal(name) => write!(f, "Undefined variable '{name}'."),
+            Self::Conversion(err) => write!(f, "{err}"),
+            Self::Format(err) => write!(f, "{err}"),
+        }
+    }
 }
 
+impl Error for MMalisError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::StdIO(err) => Some(err),
+            Self::InterpretError(err) => err.source(),
+            Self::UndefinedGlobal(_) => None,
+            Self::Conversion(err) => err.source(),
+            Self::Format(err) => err.source(),
+        }
+    }
+}
+
+impl_from_err!(ConversionError, MMalisError, Conversion);
+impl_from_err!(FormatError, MMalisError, Format);
+
 impl From<std::io::Error> for MMalisError {
     fn from(value: std::io::Error) -> Self {
         Self::StdIO(value)
@@ -122,6 +332,8 @@ impl From<InterpretError> for MMalisError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use std::rc::Rc;
 
     #[test]
     fn debug_dis() {
@@ -143,7 +355,7 @@ mod tests {
         seq.push(OpCode::Return, 14).unwrap();
         seq.push(OpCode::Return, 14).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
         /*
         // Check if constant long works
@@ -174,6 +386,64 @@ mod tests {
         Disassembler::dis_sequence(&seq2, "test sequence2");
     }
 
+    #[test]
+    fn scan_statements() {
+        use token::{Keyword, Literal, SingleChar, TokenType};
+
+        let source = b"var x1 = 4.5; // the answer\nprint x1;";
+        let mut scanner = scan::Scanner::new(source);
+        let mut tokens = Vec::new();
+        while let Some(token) = scanner.next_token() {
+            let token = token.unwrap();
+            let lexeme = std::str::from_utf8(&source[token.start()..token.end()]).unwrap();
+            tokens.push((*token.t_type(), lexeme, token.line()));
+        }
+        // Comments are skipped, and a single `Eof` ends the tokens
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Keyword(Keyword::Var), "var", 1),
+                (TokenType::Ident, "x1", 1),
+                (TokenType::SingleChar(SingleChar::Equal), "=", 1),
+                (TokenType::Literal(Literal::Number), "4.5", 1),
+                (TokenType::SingleChar(SingleChar::SemiColon), ";", 1),
+                (TokenType::Keyword(Keyword::Print), "print", 2),
+                (TokenType::Ident, "x1", 2),
+                (TokenType::SingleChar(SingleChar::SemiColon), ";", 2),
+                (TokenType::Eof, "", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn globals() {
+        let mut malis = MMalis::new();
+        malis
+            .eval("var a = 1;\nvar b = a + 2;\na = b * 2;\nvar s = \"x\" + \"y\";")
+            .unwrap();
+        assert_eq!(malis.vm().global("a").and_then(Value::as_number), Some(6.0));
+        assert_eq!(malis.vm().global("b").and_then(Value::as_number), Some(3.0));
+        assert_eq!(malis.vm().global("s").and_then(Value::as_str), Some("xy"));
+        assert!(malis.vm().global("c").is_none());
+    }
+
+    #[test]
+    fn statement_errors() {
+        // Undefined variables are found while running, and reported on the line using them
+        let mut malis = MMalis::new();
+        match malis.eval("var a = 1;\nprint b;") {
+            Err(MMalisError::InterpretError(InterpretError::RuntimeError(err))) => {
+                assert_eq!(err.message(), "Undefined variable 'b'.");
+                assert_eq!(err.line(), Some(2));
+            }
+            result => panic!("Expected a runtime error, got {result:?}"),
+        }
+        assert!(matches!(
+            malis.eval("var = 1;"),
+            Err(MMalisError::InterpretError(InterpretError::CompileError(_)))
+        ));
+    }
+
     #[test]
     fn negate() {
         let mut seq = Sequence::new();
@@ -185,7 +455,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 18).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -203,7 +473,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 18).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -229,7 +499,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -247,7 +517,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 18).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -267,7 +537,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -289,7 +559,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 18).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -307,7 +577,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -331,7 +601,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -365,7 +635,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -399,7 +669,7 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
 
@@ -427,7 +697,1259 @@ mod tests {
         // Push return
         seq.push(OpCode::Return, 21).unwrap();
         // Create a new VM that will execute code
-        let mut vm = VM::new(&seq);
+        let mut vm = VM::new();
         vm.interpret(&seq).unwrap();
     }
+
+    fn native_sum(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+        match (args[0].as_number(), args[1].as_number()) {
+            (Some(left), Some(right)) => Ok(Value::from(left + right)),
+            _ => Err(RuntimeError::new("sum expects numbers")),
+        }
+    }
+
+    // Runs `source` in `malis`, expecting it to fail at runtime
+    fn runtime_error(malis: &mut MMalis, source: &str) -> RuntimeError {
+        match malis.eval(source) {
+            Err(MMalisError::InterpretError(InterpretError::RuntimeError(err))) => err,
+            result => panic!("expected a runtime error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn native_call() {
+        let mut malis = MMalis::new();
+        malis.define_native("sum", 2, native_sum);
+        malis.eval("var x = sum(1, sum(2, 3));").unwrap();
+        assert_eq!(malis.get_global::<f64>("x").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn native_arity_mismatch() {
+        let mut malis = MMalis::new();
+        malis.define_native("sum", 2, native_sum);
+        let err = runtime_error(&mut malis, "var x = 1;\nsum(x);");
+        assert_eq!(err.message(), "Expected 2 arguments but got 1.");
+        assert_eq!(err.line(), Some(2));
+    }
+
+    #[test]
+    fn native_error_has_line() {
+        let mut malis = MMalis::new();
+        malis.define_native("sum", 2, native_sum);
+        let err = runtime_error(&mut malis, "fun f() {\n  return sum(1, \"a\");\n}\nf();");
+        assert_eq!(err.message(), "sum expects numbers");
+        assert_eq!(err.line(), Some(2));
+    }
+
+    #[test]
+    fn native_cannot_reset() {
+        fn wipe(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+            vm.reset()?;
+            Ok(Value::nil())
+        }
+        let mut malis = MMalis::new();
+        malis.define_native("wipe", 0, wipe);
+        let err = runtime_error(&mut malis, "var x = 1;\nwipe();\nclock();");
+        assert_eq!(
+            err.message(),
+            "Cannot reset the VM while a script is running."
+        );
+        assert_eq!(err.line(), Some(2));
+        // The script's globals survive the attempt
+        assert_eq!(malis.get_global::<f64>("x").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn session_keeps_globals() {
+        let mut malis = MMalis::new();
+        malis.eval("var greeting = \"hello\";").unwrap();
+        let value = malis.eval("greeting + \" world\";").unwrap();
+        assert_eq!(value.as_str(), Some("hello world"));
+        // Statements other than expressions evaluate to `nil`
+        assert!(malis
+            .eval("greeting = \"bye\";\nprint greeting;")
+            .unwrap()
+            .is_nil());
+        assert_eq!(malis.get_global::<String>("greeting").unwrap(), "bye");
+    }
+
+    #[test]
+    fn host_globals() {
+        let mut malis = MMalis::new();
+        malis.set_global("limit", 3.0);
+        malis.set_global("names", vec!["a", "b"]);
+        malis.set_global("missing", None::<f64>);
+        malis
+            .eval("var doubled = limit * 2;\nvar none = missing == nil;")
+            .unwrap();
+        assert_eq!(malis.get_global::<f64>("doubled").unwrap(), 6.0);
+        assert!(malis.get_global::<bool>("none").unwrap());
+        assert_eq!(
+            malis.get_global::<Vec<String>>("names").unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(malis.get_global::<Option<f64>>("missing").unwrap(), None);
+        assert!(matches!(
+            malis.get_global::<f64>("names"),
+            Err(MMalisError::Conversion(_))
+        ));
+        assert!(matches!(
+            malis.get_global::<f64>("undefined"),
+            Err(MMalisError::UndefinedGlobal(_))
+        ));
+    }
+
+    #[test]
+    fn call_script_function() {
+        let mut malis = MMalis::new();
+        malis
+            .eval(
+                "fun fib(n) {
+                    if (n < 2) return n;
+                    return fib(n - 1) + fib(n - 2);
+                }",
+            )
+            .unwrap();
+        let result = malis.call("fib", &[Value::from(10.0)]).unwrap();
+        assert_eq!(f64::from_value(&result).unwrap(), 55.0);
+        // Arity is checked for calls coming from the host as well
+        assert!(malis.call("fib", &[]).is_err());
+        // A failed call does not break the session
+        assert_eq!(malis.eval("fib(6);").unwrap(), Value::from(8.0));
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut malis = MMalis::new();
+        let value = malis
+            .eval(
+                "var total = 0;
+                for (var i = 0; i < 5; i = i + 1) {
+                    if (i == 2 or i == 4) total = total + 10; else total = total + 1;
+                }
+                var n = 0;
+                while (n < 3 and true) n = n + 1;
+                total + n;",
+            )
+            .unwrap();
+        assert_eq!(value, Value::from(26.0));
+    }
+
+    // Host state exposed to scripts as userdata in the tests below
+    struct Counter {
+        count: f64,
+        released: Rc<core::cell::Cell<bool>>,
+    }
+
+    fn counter_add(_vm: &mut VM, this: &Userdata, args: &[Value]) -> Result<Value, RuntimeError> {
+        let mut counter = this.borrow_mut::<Counter>()?;
+        counter.count += f64::from_value(&args[0])?;
+        Ok(Value::from(counter.count))
+    }
+
+    fn counter_release(data: &mut dyn core::any::Any) {
+        if let Some(counter) = data.downcast_mut::<Counter>() {
+            counter.released.set(true);
+        }
+    }
+
+    #[test]
+    fn userdata_methods() {
+        let class = Rc::new(
+            UserdataClass::new("Counter")
+                .method("add", 1, counter_add)
+                .finalizer(counter_release),
+        );
+        let released = Rc::new(core::cell::Cell::new(false));
+        let counter = Counter {
+            count: 0.0,
+            released: released.clone(),
+        };
+
+        let mut malis = MMalis::new();
+        malis.set_global("counter", Userdata::new(class, counter));
+        let value = malis
+            .eval("counter.add(2);\nvar c = counter;\nc.add(3);")
+            .unwrap();
+        assert_eq!(value, Value::from(5.0));
+
+        let err = runtime_error(&mut malis, "counter.sub(1);");
+        assert_eq!(err.message(), "Undefined method 'sub' on Counter.");
+        let err = runtime_error(&mut malis, "counter.add(\"x\");");
+        assert_eq!(err.message(), "Expected a number but found a string.");
+
+        // The finalizer runs once the last reference is gone
+        malis.eval("counter = nil;").unwrap();
+        assert!(!released.get());
+        malis.eval("c = nil;").unwrap();
+        assert!(released.get());
+    }
+
+    #[test]
+    fn repl_keeps_state_across_errors() {
+        let mut malis = MMalis::new();
+        malis.run(b"var x = 1;", true).unwrap();
+        // Bare expressions are printed, even without a terminating `;`
+        malis.run(b"x + 1", true).unwrap();
+        // Nothing runs when the line does not compile
+        assert!(malis.run(b"x = 5;\nx = x +;", true).is_err());
+        // Whatever ran before a runtime error is kept
+        assert!(malis
+            .run(b"fun f() { return y; }\nx = 10;\nf();", true)
+            .is_err());
+        malis.run(b"x = x + 1;", true).unwrap();
+        assert_eq!(malis.get_global::<f64>("x").unwrap(), 11.0);
+        assert!(malis.get_global::<Value>("f").is_ok());
+    }
+
+    #[test]
+    fn repl_input_completeness() {
+        assert!(repl::is_complete(b"print 1;"));
+        assert!(!repl::is_complete(b"fun f() {\n  print 1;"));
+        assert!(!repl::is_complete(b"print (1 +"));
+        assert!(!repl::is_complete(b"print \"abc"));
+        assert!(repl::is_complete(b"fun f() {\n  print 1;\n}"));
+        // Stray closing delimiters are left for the compiler to report
+        assert!(repl::is_complete(b"}"));
+    }
+
+    #[test]
+    fn reset_forgets_globals() {
+        let mut malis = MMalis::new();
+        malis.eval("var x = 1;").unwrap();
+        malis.vm().reset().unwrap();
+        assert!(malis.get_global::<f64>("x").is_err());
+        // Builtins come back after a reset
+        assert!(malis.get_global::<Value>("clock").is_ok());
+    }
+
+    #[test]
+    fn editor_completion() {
+        let words: Vec<String> = ["print", "prime", "return", "counter"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+        let mut line = editor::Line::default();
+        "x = re".chars().for_each(|c| line.insert(c));
+        assert!(line.complete(&words).is_empty());
+        assert_eq!(line.text(), "x = return");
+
+        // Ambiguous words are completed up to where they differ, then listed
+        line.set("pr");
+        assert!(line.complete(&words).is_empty());
+        assert_eq!(line.text(), "pri");
+        assert_eq!(line.complete(&words), ["prime", "print"]);
+        assert_eq!(line.text(), "pri");
+    }
+
+    #[test]
+    fn editor_history_search() {
+        let history: Vec<String> = ["var a = 1;", "print a;", "var b = a;"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(editor::search_history(&history, "var", 3), Some(2));
+        assert_eq!(editor::search_history(&history, "var", 2), Some(0));
+        assert_eq!(editor::search_history(&history, "b", 2), None);
+    }
+
+    #[test]
+    fn diagnostic_points_at_source() {
+        let source = "var a = 1;\nprint a\n";
+        let err = MMalis::new().eval(source).unwrap_err();
+        let diagnostic = &err.diagnostics()[0];
+        assert_eq!(diagnostic.code(), Some("E0100"));
+        assert_eq!(
+            diagnostic.render("test.ms", source.as_bytes()),
+            "error[E0100]: Expect ';' after value.\n \
+             --> test.ms:2:8\n  \
+             |\n\
+             2 | print a\n  \
+             |        ^\n  \
+             = help: add `;` at the end of the statement\n"
+        );
+
+        // Underlines cover the whole token and stay aligned past tabs
+        let source = "fun f() {\n\tvar x = 1;\n\tfun g() { return x; }\n}";
+        let err = MMalis::new().eval(source).unwrap_err();
+        let rendered = err.render("test.ms", source.as_bytes());
+        assert!(rendered.contains("--> test.ms:3:22\n"));
+        assert!(rendered.contains("\n3 |     fun g() { return x; }\n  |                      ^\n"));
+
+        // Each kind of syntax error has its own code
+        for (source, code) in [
+            ("print ;", "E0100"),
+            ("1 = 2;", "E0101"),
+            ("{ var a = 1; var a = 2; }", "E0200"),
+            ("{ var a = a; }", "E0201"),
+            ("return 1;", "E0203"),
+        ] {
+            let err = MMalis::new().eval(source).unwrap_err();
+            assert_eq!(err.diagnostics()[0].code(), Some(code), "{source}");
+        }
+
+        // Runtime errors only know their line
+        let err = MMalis::new().eval("\nprint nope;").unwrap_err();
+        assert_eq!(
+            err.render("test.ms", b""),
+            "error: Undefined variable 'nope'.\n --> test.ms:2\n"
+        );
+    }
+
+    #[test]
+    fn compile_reports_every_error() {
+        let mut malis = MMalis::new();
+        let source = "var a = ;\nprint a\nfun f() { print }\nprint 1 + @;\nvar ok = 1;";
+        let err = malis.eval(source).unwrap_err();
+        let messages: Vec<_> = err
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.message().to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Expect expression.",
+                "Expect ';' after value.",
+                "Expect expression.",
+                "Unexpected character '@'.",
+            ]
+        );
+        // Nothing runs, not even the valid declarations
+        assert!(malis.get_global::<f64>("ok").is_err());
+    }
+
+    #[test]
+    fn errors_display_and_chain() {
+        let mut malis = MMalis::new();
+        let err = malis.eval("print 1 +;\nprint 2 +;").unwrap_err();
+        assert_eq!(err.to_string(), "Compilation failed with 2 errors.");
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "[line 1] Expect expression."
+        );
+
+        let err = malis.eval("print 1;\nprint -nil;").unwrap_err();
+        assert_eq!(err.to_string(), "[line 2] Operand must be a number.");
+        assert!(err.source().is_none());
+
+        let err = MMalis::execute("does/not/exist.ms").unwrap_err();
+        let source = err.source().unwrap();
+        assert!(source.is::<std::io::Error>());
+        assert_eq!(err.to_string(), format!("I/O error: {source}"));
+    }
+
+    #[test]
+    fn compiled_file_round_trip() {
+        let source = b"fun greet(name) { return \"hi \" + name; }\n\
+                       var greeting = greet(\"bob\");\n\
+                       var zero = -0.0;";
+        let sequence = MMalis::compile(source).unwrap();
+        let mut bytes = Vec::new();
+        sequence.write_to(&mut bytes).unwrap();
+        assert!(Sequence::is_compiled(&bytes));
+
+        let read = Sequence::read_from(bytes.as_slice()).unwrap();
+        assert_eq!(read.code(), sequence.code());
+        assert_eq!(read.locations(), sequence.locations());
+        assert_eq!(read.constants().len(), sequence.constants().len());
+
+        let mut malis = MMalis::new();
+        malis.execute_bytes(&bytes).unwrap();
+        assert_eq!(malis.get_global::<String>("greeting").unwrap(), "hi bob");
+        assert!(malis.get_global::<f64>("zero").unwrap().is_sign_negative());
+    }
+
+    #[test]
+    fn compiled_file_rejects_bad_input() {
+        let mut bytes = Vec::new();
+        MMalis::compile(b"print 1;")
+            .unwrap()
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            Sequence::read_from(truncated),
+            Err(FormatError::Truncated)
+        ));
+        bytes[4] = 0xff;
+        assert!(matches!(
+            Sequence::read_from(bytes.as_slice()),
+            Err(FormatError::UnsupportedVersion(0xff))
+        ));
+        // Readers of the previous version know nothing of the superinstructions
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(
+            Sequence::read_from(bytes.as_slice()),
+            Err(FormatError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Sequence::read_from(&b"print 1;"[..]),
+            Err(FormatError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn verifier_accepts_compiled_code() {
+        let source = b"fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                       var total = 0;\n\
+                       for (var i = 0; i < 10 and total >= 0 or false; i = i + 1) {\n\
+                         var square = i * i;\n\
+                         while (square > 50) square = square / 2;\n\
+                         total = total + fib(i) + square;\n\
+                       }\n\
+                       total;";
+        MMalis::compile(source).unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn verifier_rejects_malformed_code() {
+        let verify = |code: &[u8], constants: Vec<Value>| {
+            let locations = Locations::from_iter([(Location::from(1), code.len() as u32)]);
+            Sequence::from_parts(code.to_vec(), locations, constants)
+                .verify()
+                .map_err(|err| (err.offset(), err.kind().clone()))
+        };
+        let number = || vec![Value::from(1.0)];
+
+        assert_eq!(verify(&[1, 0, 0], number()), Ok(()));
+        assert_eq!(
+            verify(&[1, 0, 200], number()),
+            Err((2, VerifyErrorKind::UnknownOpcode(200)))
+        );
+        assert_eq!(verify(&[1], number()), Err((0, VerifyErrorKind::Truncated)));
+        assert_eq!(
+            verify(&[1, 1, 0], number()),
+            Err((0, VerifyErrorKind::ConstantOutOfRange(1)))
+        );
+        assert_eq!(
+            verify(&[12, 0, 0], number()),
+            Err((0, VerifyErrorKind::NotAName(0)))
+        );
+        assert_eq!(
+            verify(&[11, 8, 0], vec![]),
+            Err((0, VerifyErrorKind::StackUnderflow))
+        );
+        assert_eq!(
+            verify(&[21, 1, 0], vec![]),
+            Err((0, VerifyErrorKind::LocalOutOfRange(1)))
+        );
+        // Jump over the first byte of `Constant 0`
+        assert_eq!(
+            verify(&[24, 1, 0, 1, 0, 0], number()),
+            Err((0, VerifyErrorKind::JumpIntoInstruction(4)))
+        );
+        assert_eq!(
+            verify(&[25, 9, 0], vec![]),
+            Err((0, VerifyErrorKind::JumpOutOfBounds))
+        );
+        // The second `true` is only pushed when the jump is not taken, so `Return` is reached with
+        // different stacks
+        assert_eq!(
+            verify(&[9, 23, 1, 0, 9, 0], vec![]),
+            Err((
+                5,
+                VerifyErrorKind::StackMismatch {
+                    expected: 3,
+                    found: 2
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn assembly_round_trip() {
+        let source = b"fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                       var name = \"tab\there\";\n\
+                       for (var i = 0; i < 10 and name != nil or false; i = i + 1) {\n\
+                         var square = -i * i;\n\
+                         while (square > 50) square = square / 2;\n\
+                         print fib(i) + square;\n\
+                       }\n\
+                       name = name;";
+        let script = MMalis::compile(source).unwrap();
+        // Constants used out of order, along with one never used, are declared upfront
+        let mut pooled = Sequence::new();
+        let first = pooled.add_constant(Value::from("first"));
+        pooled.add_constant(Value::nil());
+        pooled.push_constant(Value::from("first"));
+        pooled.write_constant(Value::from(-0.0), 2).unwrap();
+        pooled.push(OpCode::GetGlobal, 3).unwrap();
+        pooled.push(first as u8, 3).unwrap();
+        // Malformed code is written as bytes
+        let locations = Locations::from_iter([(Location::new(1, 3).with_file(7), 5)]);
+        let malformed = Sequence::from_parts(vec![24, 2, 0, 200, 1], locations, vec![]);
+
+        for sequence in [&script, &pooled, &malformed] {
+            let assembly = Disassembler::to_assembly(sequence);
+            let assembled = Assembler::assemble(&assembly).unwrap();
+            assert_eq!(assembled.code(), sequence.code(), "{assembly}");
+            assert_eq!(assembled.locations(), sequence.locations(), "{assembly}");
+            assert_eq!(Disassembler::to_assembly(&assembled), assembly);
+        }
+
+        // Listings give back the code, its lines and the constants instructions use, along with
+        // the functions listed after the script
+        let listing = |sequence: &Sequence| {
+            let mut listing = String::new();
+            let script = Function::new(None, 0, sequence.clone());
+            Disassembler::write_function(&mut listing, &script).unwrap();
+            listing
+        };
+        let listed = listing(&script);
+        let assembled = Assembler::assemble(&listed).unwrap();
+        assert_eq!(assembled.code(), script.code(), "{listed}");
+        assert_eq!(listing(&assembled), listed);
+        // Constants no instruction uses are not listed, and come back as `nil`
+        let mut listed = String::new();
+        Disassembler::write_sequence(&mut listed, &pooled, "pooled").unwrap();
+        let assembled = Assembler::assemble(&listed).unwrap();
+        assert_eq!(assembled.code(), pooled.code());
+        assert_eq!(assembled.constants()[3], pooled.constants()[3]);
+        assert!(assembled.constants()[2].is_nil());
+        Disassembler::write_sequence(&mut listed, &script, "script").unwrap();
+        let error = |listed: &str| Assembler::assemble(listed).unwrap_err().to_string();
+        assert_eq!(error(&listed), "[line 5] No code refers to script.");
+        assert_eq!(
+            error(&listing(&malformed)),
+            "[line 5] The operands of 'OP_CONSTANT' are not listed."
+        );
+        let mut listed = String::new();
+        Disassembler::write_sequence(&mut listed, &script, "script").unwrap();
+        assert_eq!(
+            error(&listed),
+            "[line 3] The code of <fn fib> is not listed next."
+        );
+    }
+
+    #[test]
+    fn assembled_code_runs() {
+        let source = "\
+            .line 1
+                OP_CONSTANT 0           ; i = 0
+                OP_DEFINE_GLOBAL \"i\"
+            loop:
+                OP_GET_GLOBAL #1
+                OP_CONSTANT 5
+                OP_LESS
+                OP_JUMP_IF_FALSE done
+                OP_POP
+            .line 2
+                OP_GET_GLOBAL #1
+                OP_CONSTANT 1
+                OP_ADD
+                OP_SET_GLOBAL #1
+                OP_POP
+                OP_LOOP loop
+            done:
+                OP_POP
+                OP_NIL
+                OP_RETURN";
+        let sequence = Assembler::assemble(source).unwrap();
+        assert_eq!(sequence.verify(), Ok(()));
+        let mut malis = MMalis::new();
+        malis.vm().interpret(&sequence).unwrap();
+        assert_eq!(malis.get_global::<f64>("i").unwrap(), 5.0);
+
+        let error = |source| Assembler::assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            error("OP_JUMP nowhere"),
+            "[line 1] Unknown label 'nowhere'."
+        );
+        assert_eq!(
+            error("back:\nOP_JUMP back"),
+            "[line 2] Label 'back' is in the wrong direction."
+        );
+        assert_eq!(
+            error("OP_ADD 1"),
+            "[line 1] 'OP_ADD' takes 0 operands, found 1."
+        );
+        assert_eq!(
+            error(".function f 0\nOP_RETURN"),
+            "[line 1] Missing '.end' for the function."
+        );
+    }
+
+    #[test]
+    fn disassembler_output() {
+        let sequence = MMalis::compile(b"var a = 1;\nif (a) print \"a\\b\";").unwrap();
+        let mut text = String::new();
+        Disassembler::write_sequence(&mut text, &sequence, "script").unwrap();
+        assert_eq!(
+            text,
+            "== script ==\n\
+             3 constants (1 number, 2 strings)\n\
+             0000 0001 OP_CONSTANT 1 -> value: 1\n\
+             0002    | OP_DEFINE_GLOBAL 0 -> value: \"a\"\n\
+             0004 0002 OP_GET_GLOBAL 0 -> value: \"a\"\n\
+             0006    | OP_JUMP_IF_FALSE 6 -> 16\n\
+             0009    | OP_POP\n\
+             0010    | OP_CONSTANT 2 -> value: \"a\\\\b\"\n\
+             0012    | OP_PRINT\n\
+             0013    | OP_JUMP 13 -> 17\n\
+             0016    | OP_POP\n\
+             0017    | OP_NIL\n\
+             0018    | OP_RETURN\n"
+        );
+        let mut bytes = Vec::new();
+        Disassembler::write_to(&mut bytes, &sequence, "script").unwrap();
+        assert_eq!(bytes, text.as_bytes());
+
+        let instructions = Disassembler::decode_sequence(&sequence);
+        assert_eq!(instructions[3].opcode(), OpCode::JumpIfFalse);
+        assert_eq!(instructions[3].operands(), Operands::Jump(16));
+        assert_eq!(instructions[5].constant(), Some(&Value::from("a\\b")));
+        assert_eq!(
+            instructions[5].to_json(),
+            "{\"offset\": 10, \"line\": 2, \"column\": 14, \"opcode\": \"OP_CONSTANT\", \"operands\": [2], \
+             \"constant\": {\"type\": \"string\", \"value\": \"a\\\\b\"}}"
+        );
+    }
+
+    #[test]
+    fn annotated_disassembly() {
+        let source = b"fun f(a) {\n  return -a;\n}\nprint f(1);\n";
+        let script = Function::new(None, 0, MMalis::compile_unoptimized(source).unwrap());
+        let mut text = String::new();
+        Disassembler::write_annotated(&mut text, &script, source).unwrap();
+        assert_eq!(
+            text,
+            "== <script> ==\n\
+             3 constants (1 number, 1 string, 1 function)\n        3 | }\n\
+             0000 0003 OP_CONSTANT 1 -> value: <fn f>\n\
+             0002    | OP_DEFINE_GLOBAL 0 -> value: \"f\"\n        4 | print f(1);\n\
+             0004 0004 OP_GET_GLOBAL 0 -> value: \"f\"\n\
+             0006    | OP_CONSTANT 2 -> value: 1\n\
+             0008    | OP_CALL 1\n\
+             0010    | OP_PRINT\n\
+             0011    | OP_NIL\n\
+             0012    | OP_RETURN\n\
+             == <fn f> (arity 1) ==\n0 constants\n        2 |   return -a;\n\
+             0000 0002 OP_GET_LOCAL 1\n\
+             0002    | OP_NEGATE\n\
+             0003    | OP_RETURN\n        3 | }\n\
+             0004 0003 OP_NIL\n\
+             0005    | OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn control_flow_graph() {
+        let sequence = MMalis::compile(b"var a = 1; while (a < 3) a = a + 1; print a;").unwrap();
+        let graph = ControlFlowGraph::new(&sequence);
+        let edges: Vec<_> = graph
+            .blocks()
+            .iter()
+            .map(|block| {
+                let successors = block.successors().iter();
+                let successors = successors.map(|edge| (edge.target(), edge.condition()));
+                (block.start(), successors.collect::<Vec<_>>())
+            })
+            .collect();
+        // The condition, the body looping back to it, and the code after the loop
+        assert_eq!(
+            edges,
+            vec![
+                (0, vec![(4, None)]),
+                (4, vec![(12, Some(true)), (23, Some(false))]),
+                (12, vec![(4, None)]),
+                (23, vec![]),
+            ]
+        );
+
+        let dot = graph.to_dot("<script>");
+        assert!(dot.starts_with("digraph \"<script>\" {\n"));
+        assert!(dot.contains("    b4 -> b23 [label=\"false\"];\n"));
+        assert!(dot.contains("    b23 [label=\"0023 OP_POP\\l0024 OP_GET_GLOBAL"));
+    }
+
+    #[test]
+    fn sequence_locations() {
+        let location =
+            |offset: usize| Location::new((offset as f64).sqrt() as u32, offset as u32 / 10);
+        let mut sequence = Sequence::new();
+        for offset in 0..1000 {
+            sequence.push(OpCode::Nil, location(offset)).unwrap();
+        }
+        // Successive bytes from the same line share a single run, and columns are only stored
+        // where they change
+        let changes = |part: fn(Location) -> u32| {
+            (0..1000)
+                .filter(|offset| {
+                    *offset == 0 || part(location(*offset)) != part(location(offset - 1))
+                })
+                .count()
+        };
+        assert_eq!(sequence.locations().lines().len(), changes(|l| l.line()));
+        // The first bytes have column 0, which needs no change
+        assert_eq!(
+            sequence.locations().columns().len(),
+            changes(|l| l.column()) - 1
+        );
+        assert!(sequence.locations().files().is_empty());
+        for offset in 0..1000 {
+            assert_eq!(sequence.location(offset), location(offset));
+        }
+        assert_eq!(sequence.location(1000), Location::default());
+
+        // Code is attributed to the token compiled last
+        let sequence = MMalis::compile_unoptimized(b"var a =\n  1 +\n    2;").unwrap();
+        let locations: Vec<_> = (0..sequence.code().len())
+            .map(|offset| {
+                let location = sequence.location(offset);
+                (location.line(), location.column())
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                (2, 3),
+                (2, 3),
+                (3, 5),
+                (3, 5),
+                (3, 5),
+                (3, 6),
+                (3, 6),
+                (3, 6),
+                (3, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_pool_dedup() {
+        let mut sequence = Sequence::new();
+        let zero = sequence.add_constant(Value::from(0.0));
+        let nan = sequence.add_constant(Value::from(f64::NAN));
+        let name = sequence.add_constant(Value::from("name"));
+        assert_eq!(sequence.add_constant(Value::from(0.0)), zero);
+        assert_eq!(sequence.add_constant(Value::from(f64::NAN)), nan);
+        assert_eq!(sequence.add_constant(Value::from("name")), name);
+        // `-0` behaves differently from `0`, as in `1 / -0`
+        assert_ne!(sequence.add_constant(Value::from(-0.0)), zero);
+        // Explicitly pushed copies are kept apart, and the first copy stays the shared one
+        assert_eq!(sequence.push_constant(Value::from("name")), 4);
+        assert_eq!(sequence.add_constant(Value::from("name")), name);
+        let stats = sequence.constant_stats();
+        assert_eq!(stats.count(ConstantKind::Number), 3);
+        assert_eq!(stats.to_string(), "5 constants (3 numbers, 2 strings)");
+
+        // Scripts mentioning the same values over and over do not run out of constants
+        let source = "var total = 0;\n".to_string() + &"total = total + 1;\n".repeat(1000);
+        let script = MMalis::compile(source.as_bytes()).unwrap();
+        assert_eq!(script.constants().len(), 3);
+        let mut malis = MMalis::new();
+        malis.execute_bytes(source.as_bytes()).unwrap();
+        assert_eq!(malis.get_global::<f64>("total").unwrap(), 1000.0);
+    }
+
+    #[test]
+    fn constant_folding() {
+        // Folded expressions load their value with a single instruction
+        let folded = |source: &str| {
+            let sequence = MMalis::compile(source.as_bytes()).unwrap();
+            let instructions = Disassembler::decode_sequence(&sequence);
+            let first = &instructions[0];
+            assert_eq!(first.next(), instructions[1].offset(), "{source}");
+            assert_eq!(instructions[1].opcode(), OpCode::Print, "{source}");
+            match first.opcode() {
+                OpCode::Constant => sequence.read_constant(first.offset() + 1).to_string(),
+                opcode => opcode.mnemonic().to_string(),
+            }
+        };
+        assert_eq!(folded("print 1 + 2 * 3;"), "7");
+        assert_eq!(folded("print -(4 - 6) / 4;"), "0.5");
+        assert_eq!(folded("print \"a\" + \"b\" + \"c\";"), "abc");
+        assert_eq!(folded("print 1 + 2 >= 3;"), "OP_TRUE");
+        assert_eq!(folded("print !nil == (1 != 2);"), "OP_TRUE");
+        // Operands and intermediate results no longer needed are dropped from the pool
+        let sequence = MMalis::compile(b"print 1 + 2 * 3;").unwrap();
+        assert_eq!(sequence.constants().len(), 1);
+
+        // What would fail or produce a NaN at runtime is left to the VM
+        let unfolded = |source: &str| {
+            let sequence = MMalis::compile(source.as_bytes()).unwrap();
+            let unfolded = MMalis::compile_unoptimized(source.as_bytes()).unwrap();
+            assert_eq!(sequence.code(), unfolded.optimize().code(), "{source}");
+        };
+        unfolded("print 1 / 0;");
+        unfolded("print -\"a\";");
+        unfolded("print 1 + \"a\";");
+        unfolded("print nil < 1;");
+        // Identities are only dropped for operands known to be numbers
+        unfolded("var x; print x * 1;");
+        unfolded("var x; print -x + 0;");
+        let sequence = MMalis::compile(b"var x = 2; print -x * 1 / 1 - 0;").unwrap();
+        let unfolded = MMalis::compile(b"var x = 2; print -x;").unwrap();
+        assert_eq!(sequence.code(), unfolded.code());
+
+        // Jump targets are never folded away
+        let mut malis = MMalis::new();
+        malis
+            .execute_bytes(b"var a = (false and 1) == false; var b = (nil or 2) * 1;")
+            .unwrap();
+        assert!(malis.get_global::<bool>("a").unwrap());
+        assert_eq!(malis.get_global::<f64>("b").unwrap(), 2.0);
+        // `-0 - -0` is `0`, while `-0 - 0` is `-0`
+        let value = malis.eval("var x = 0; 1 / (-x - -0);").unwrap();
+        assert_eq!(value.as_number(), Some(f64::INFINITY));
+        let value = malis.eval("var x = 0; 1 / (-x - 0);").unwrap();
+        assert_eq!(value.as_number(), Some(f64::NEG_INFINITY));
+        let err = malis.eval("(nil and 1) * 1;").unwrap_err();
+        assert!(
+            err.to_string().contains("Operands must be numbers."),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn peephole_optimizer() {
+        let sequence = Assembler::assemble(
+            "\
+            .line 1
+                OP_NIL
+                OP_POP
+                OP_GET_GLOBAL \"x\"
+                OP_JUMP_IF_FALSE first  ; lands on jumps
+                OP_NEGATE               ; `x` may not be a number
+                OP_NEGATE
+                OP_CONSTANT 2
+                OP_NEGATE
+                OP_NEGATE
+                OP_POP
+            first:
+                OP_JUMP_IF_FALSE second
+            second:
+                OP_JUMP end
+                OP_NIL
+            end:
+            .line 2
+                OP_RETURN
+                OP_NIL
+                OP_RETURN",
+        )
+        .unwrap();
+        let expected = Assembler::assemble(
+            "\
+            .line 1
+                OP_GET_GLOBAL \"x\"
+                OP_JUMP_IF_FALSE end
+                OP_NEGATE
+                OP_NEGATE
+                OP_JUMP_IF_FALSE end
+                OP_JUMP end
+            end:
+            .line 2
+                OP_RETURN",
+        )
+        .unwrap();
+        assert_eq!(sequence.verify(), Ok(()));
+        let optimized = sequence.optimize();
+        assert_eq!(optimized.code(), expected.code());
+        assert_eq!(optimized.locations(), expected.locations());
+        assert_eq!(optimized.verify(), Ok(()));
+
+        // Optimized scripts behave the same, with less code
+        let source = b"fun f(n) { if (n > 1) { if (n > 2) return 3; else return 2; } return 1; }\n\
+                       var total = 0;\n\
+                       for (var i = 0; i < 5 and total >= 0; i = i + 1) {\n\
+                         if (i == 1) { 1; } else if (i == 2 or i == 3) total = total + f(i);\n\
+                         else while (false) {}\n\
+                       }";
+        let optimized = MMalis::compile(source).unwrap();
+        let unoptimized = MMalis::compile_unoptimized(source).unwrap();
+        assert!(optimized.code().len() < unoptimized.code().len());
+        for sequence in [optimized, unoptimized] {
+            let mut malis = MMalis::new();
+            malis.vm().interpret(&sequence).unwrap();
+            assert_eq!(malis.get_global::<f64>("total").unwrap(), 5.0);
+        }
+    }
+
+    #[test]
+    fn superinstructions() {
+        let source = b"var total = 0;\n\
+                       for (var i = 0; i < 10; i = i + 1) {\n\
+                         var j = i + 2;\n\
+                         total = total + j;\n\
+                       }\n\
+                       var s = \"a\";\n\
+                       { var t = s; t = t + 1; }";
+        let sequence = MMalis::compile(source).unwrap();
+        let opcodes = |sequence: &Sequence| -> Vec<_> {
+            Disassembler::decode_sequence(sequence)
+                .iter()
+                .map(|instruction| instruction.opcode())
+                .collect()
+        };
+        let fused = [
+            OpCode::JumpIfNotLessLocal,
+            OpCode::IncrementLocal,
+            OpCode::GetLocal1,
+            OpCode::AddConstant,
+            OpCode::GetLocal2,
+        ];
+        for opcode in fused {
+            assert!(
+                opcodes(&sequence).contains(&opcode),
+                "{:?}",
+                opcodes(&sequence)
+            );
+        }
+        let assembled = Assembler::assemble(&Disassembler::to_assembly(&sequence)).unwrap();
+        assert_eq!(assembled.code(), sequence.code());
+        let unfused = MMalis::compile_without_superinstructions(source).unwrap();
+        assert!(opcodes(&unfused)
+            .iter()
+            .all(|opcode| !fused.contains(opcode)));
+
+        // Fused instructions compute the same values, and fail on the same lines
+        for sequence in [
+            sequence,
+            unfused,
+            MMalis::compile_unoptimized(source).unwrap(),
+        ] {
+            let mut malis = MMalis::new();
+            let err = malis.vm().interpret(&sequence).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "[line 7] Operands must be two numbers or two strings."
+            );
+            assert_eq!(malis.get_global::<f64>("total").unwrap(), 65.0);
+        }
+    }
+
+    #[test]
+    fn value_representation() {
+        let size = if cfg!(feature = "nan-boxing") { 8 } else { 16 };
+        assert_eq!(std::mem::size_of::<Value>(), size);
+
+        // Numbers come back as they went in, including the NaNs whose payload other values use
+        for number in [
+            0.0,
+            -0.0,
+            1.5,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ] {
+            let value = Value::from(number);
+            assert_eq!(value.as_number().unwrap().to_bits(), number.to_bits());
+        }
+        for bits in [
+            0x7ff8_0000_0000_0000,
+            0x7ffc_0000_0000_0001,
+            0xfffc_0000_0000_0002,
+        ] {
+            let value = Value::from(f64::from_bits(bits));
+            assert!(value.as_number().is_some_and(f64::is_nan), "{bits:#x}");
+            assert_ne!(value, value);
+        }
+        assert!(Value::nil().is_nil() && Value::default().is_nil());
+        assert_eq!(Value::from(true).as_bool(), Some(true));
+        assert_eq!(Value::from(false).as_bool(), Some(false));
+        assert_eq!(Value::from(false).as_number(), None);
+
+        // Objects are shared by their clones, and freed along with the last one
+        let string = Value::from("malis");
+        let list = Value::from(vec![string.clone(), Value::from(1.0)]);
+        let copy = list.clone();
+        drop(list);
+        assert_eq!(copy, copy.clone());
+        assert_ne!(copy, Value::from(vec![string.clone(), Value::from(1.0)]));
+        assert_eq!(
+            Vec::<Value>::from_value(&copy).unwrap()[0],
+            Value::from("malis")
+        );
+        assert_eq!(format!("{:?}", Value::from(2.0)), "Number(2.0)");
+    }
+
+    #[test]
+    fn dispatch_runs_verified_code() {
+        // Functions built by hand are verified when they are first called
+        let code = vec![OpCode::Nil.try_into().unwrap(), 200];
+        let locations = Locations::from_iter([(Location::from(1), 2)]);
+        let function = Function::new(None, 0, Sequence::from_parts(code, locations, vec![]));
+        let mut malis = MMalis::new();
+        let err = malis
+            .vm()
+            .call(Value::from(Obj::Function(Rc::new(function))), &[])
+            .unwrap_err();
+        assert!(matches!(err, InterpretError::VerifyError(_)), "{err}");
+
+        // Script: Constant, DefineGlobal, GetGlobal, Constant, Call, DefineGlobal, Nil, Return.
+        // Function: GetLocal1, AddConstant, Pop, Nil, Return
+        let sequence = MMalis::compile(b"fun f(n) { n + 1; }\nvar x = f(1);").unwrap();
+        malis.vm().set_count_instructions(true);
+        malis.vm().interpret(&sequence).unwrap();
+        assert!(malis.get_global::<Value>("x").unwrap().is_nil());
+        assert_eq!(malis.vm().instruction_count(), 13);
+
+        // Running past the end of the code returns `nil`, which is not an instruction
+        let sequence = Assembler::assemble("OP_TRUE\nOP_POP\n").unwrap();
+        malis.vm().set_count_instructions(true);
+        malis.vm().interpret(&sequence).unwrap();
+        assert_eq!(malis.vm().instruction_count(), 2);
+        malis.vm().set_count_instructions(false);
+        assert_eq!(malis.vm().instruction_count(), 0);
+    }
+
+    #[test]
+    fn tracing() {
+        let steps = Rc::new(core::cell::RefCell::new(Vec::new()));
+        let recorded = steps.clone();
+        let mut malis = MMalis::new();
+        malis.vm().set_tracer(move |step: &TraceStep<'_>| {
+            recorded.borrow_mut().push((
+                step.function().to_string(),
+                step.instruction().opcode(),
+                step.line(),
+                step.stack().len(),
+                step.depth(),
+            ))
+        });
+        malis
+            .eval("fun f(n) {\n  return n + 1;\n}\nprint f(2);")
+            .unwrap();
+        let traced = steps.borrow().clone();
+        // The script, the callee, its argument and then the argument again are on the stack
+        assert!(traced.contains(&("<fn f>".to_string(), OpCode::AddConstant, 2, 4, 2)));
+        assert_eq!(traced.last().unwrap().1, OpCode::Return);
+
+        // Taking the tracer back stops tracing
+        assert!(malis.vm().take_tracer().is_some());
+        malis.eval("print f(3);").unwrap();
+        assert_eq!(steps.borrow().len(), traced.len());
+
+        let script = Function::new(None, 0, MMalis::compile(b"\nprint 1;").unwrap());
+        let mut tracer = WriteTracer::new(Vec::new());
+        let stack = [Value::from("a"), Value::from(2.0)];
+        tracer.trace(&TraceStep::new(&script, 0, &stack, &[], &HashMap::new()));
+        assert_eq!(
+            String::from_utf8(tracer.into_inner()).unwrap(),
+            "          [ a ][ 2 ]\n<script> 0000    2 OP_CONSTANT 0 -> value: 1\n"
+        );
+    }
+
+    #[test]
+    fn local_names() {
+        let source = b"{\n  var a = 1;\n  {\n    var b = a + 2;\n    print b;\n  }\n  print a;\n}";
+        for sequence in [
+            MMalis::compile_unoptimized(source).unwrap(),
+            MMalis::compile(source).unwrap(),
+        ] {
+            let names = |line: u32| -> Vec<String> {
+                let offset = (0..sequence.code().len())
+                    .find(|offset| sequence.line(*offset) == line)
+                    .unwrap();
+                let names = sequence.local_names_at(offset);
+                names.iter().map(|local| local.name().to_string()).collect()
+            };
+            // The value of `b` is on the stack from line 5 on, and gone by line 7
+            assert_eq!(names(5), ["a", "b"]);
+            assert_eq!(names(7), ["a"]);
+        }
+    }
+
+    // Output shared with a debugger handed to the VM
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<core::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn debugger() {
+        let source = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = 1;\nprint add(x, 2);\nprint x;";
+        let commands =
+            "break 3\ncontinue\nlocals\nbacktrace\nfinish\nprint x\nstepi\n\nbreak 20\ndis 18446744073709551615\nquit\n";
+        let output = SharedOutput::default();
+        let debugger =
+            Debugger::new(commands.as_bytes(), output.clone()).with_source(source.as_bytes());
+        let mut malis = MMalis::new();
+        malis.vm().set_tracer(debugger);
+        let err = malis.eval(source).unwrap_err();
+        assert!(matches!(
+            err,
+            MMalisError::InterpretError(InterpretError::Interrupted)
+        ));
+
+        let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+        for expected in [
+            "Breakpoint 1 at line 3\n",
+            "Breakpoint 1, <fn add> at line 3\n   3 |   return sum;\n",
+            "a = 1\nb = 2\nsum = 3\n",
+            "#0 <fn add> at line 3, offset 0003\n#1 <script> at line 6, offset 0016\n",
+            "(mdb) <script> at line 6\n",
+            "x = 1\n",
+            "No code at or after line 20.\n",
+            // Listing more instructions than there are shows all of them
+            "(mdb)    0000    4 OP_CONSTANT 1 -> value: <fn add>\n",
+        ] {
+            assert!(output.contains(expected), "{expected:?} in {output}");
+        }
+        // An empty line repeats the last command, stepping one more instruction
+        assert_eq!(output.matches("(mdb) <script> at line 7\n").count(), 2);
+    }
+
+    #[test]
+    fn json() {
+        let text = r#"{"a": [1, -2.5e1, true, null], "b": "q\"\n\u00e9\ud83d\ude00", "c": {}}"#;
+        let value = json::Json::parse(text).unwrap();
+        assert_eq!(
+            value.get("b").and_then(json::Json::as_str),
+            Some("q\"\né😀")
+        );
+        assert_eq!(
+            value.to_string(),
+            r#"{"a":[1,-25,true,null],"b":"q\"\né😀","c":{}}"#
+        );
+        assert_eq!(json::Json::parse(&value.to_string()), Ok(value));
+        for invalid in ["", "[1,]", "{\"a\" 1}", "+1", "\"\\x\"", "nul", "1 2"] {
+            assert!(json::Json::parse(invalid).is_err(), "{invalid}");
+        }
+        // Deeply nested values are rejected rather than overflowing the stack
+        let nested = |depth: usize| "[{\"a\":".repeat(depth) + "1" + &"}]".repeat(depth);
+        assert!(json::Json::parse(&nested(100)).is_ok());
+        assert!(json::Json::parse(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn dap_session() {
+        use json::Json;
+
+        let program = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/debug.ms");
+        let requests = [
+            ("initialize", r#"{"adapterID": "malis"}"#.to_string()),
+            (
+                "launch",
+                format!(r#"{{"program": {}}}"#, json::quote(program)),
+            ),
+            (
+                "setBreakpoints",
+                r#"{"source": {}, "breakpoints": [{"line": 3}, {"line": 20}]}"#.to_string(),
+            ),
+            ("configurationDone", "{}".to_string()),
+            ("stackTrace", r#"{"threadId": 1}"#.to_string()),
+            ("scopes", r#"{"frameId": 0}"#.to_string()),
+            ("variables", r#"{"variablesReference": 2}"#.to_string()),
+            (
+                "evaluate",
+                r#"{"expression": "x", "frameId": 0}"#.to_string(),
+            ),
+            ("next", r#"{"threadId": 1}"#.to_string()),
+            ("continue", r#"{"threadId": 1}"#.to_string()),
+            ("disconnect", "{}".to_string()),
+        ];
+        let mut input = String::new();
+        for (idx, (command, arguments)) in requests.iter().enumerate() {
+            let request = format!(
+                r#"{{"seq": {}, "type": "request", "command": "{command}", "arguments": {arguments}}}"#,
+                idx + 1
+            );
+            input.push_str(&format!(
+                "Content-Length: {}\r\n\r\n{request}",
+                request.len()
+            ));
+        }
+        let output = SharedOutput::default();
+        DapServer::new(std::io::Cursor::new(input.into_bytes()), output.clone())
+            .run()
+            .unwrap();
+
+        let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let messages: Vec<Json> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        let field = |message: &Json, path: &[&str]| -> String {
+            let value = path.iter().fold(message.clone(), |value, name| {
+                value.get(name).cloned().unwrap_or(Json::Null)
+            });
+            value.to_string()
+        };
+        // Each request is answered successfully, in order
+        let responses: Vec<&Json> = messages
+            .iter()
+            .filter(|message| field(message, &["type"]) == r#""response""#)
+            .collect();
+        assert_eq!(responses.len(), requests.len());
+        for (response, (command, _)) in responses.iter().zip(&requests) {
+            assert_eq!(field(response, &["command"]), format!("\"{command}\""));
+            assert_eq!(field(response, &["success"]), "true");
+        }
+        assert_eq!(
+            field(responses[2], &["body", "breakpoints"]),
+            r#"[{"id":1,"verified":true,"line":3},{"id":2,"verified":false,"line":20,"message":"No code at or after this line."}]"#
+        );
+        let frames = responses[4]
+            .get("body")
+            .unwrap()
+            .get("stackFrames")
+            .unwrap();
+        let frames: Vec<String> = frames
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| format!("{} {}", field(frame, &["name"]), field(frame, &["line"])))
+            .collect();
+        assert_eq!(frames, [r#""<fn add>" 3"#, r#""<script>" 6"#]);
+        let variables = responses[6].get("body").unwrap().get("variables").unwrap();
+        let variables: Vec<String> = variables
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variable| {
+                format!(
+                    "{}={}",
+                    field(variable, &["name"]),
+                    field(variable, &["value"])
+                )
+            })
+            .collect();
+        assert_eq!(variables, [r#""a"="1""#, r#""b"="2""#, r#""sum"="3""#]);
+        assert_eq!(field(responses[7], &["body", "result"]), r#""1""#);
+
+        // The script stops at the breakpoint and after the step, then prints and exits
+        let events: Vec<String> = messages
+            .iter()
+            .filter(|message| field(message, &["type"]) == r#""event""#)
+            .map(|event| match field(event, &["event"]).as_str() {
+                r#""stopped""# => format!("stopped {}", field(event, &["body", "reason"])),
+                r#""output""# => format!("output {}", field(event, &["body", "output"])),
+                r#""exited""# => format!("exited {}", field(event, &["body", "exitCode"])),
+                event => event.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                r#""initialized""#,
+                r#"stopped "breakpoint""#,
+                r#"stopped "step""#,
+                r#"output "3\n""#,
+                r#"output "1\n""#,
+                "exited 0",
+                r#""terminated""#,
+            ]
+        );
+
+        // Messages claiming to be huge are refused before anything is allocated for them
+        let input = "Content-Length: 1000000000000\r\n\r\n{}";
+        let err = DapServer::new(std::io::Cursor::new(input), SharedOutput::default())
+            .run()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
 }
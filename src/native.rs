@@ -0,0 +1,15 @@
+//! Native functions available to every script
+use crate::object::NativeFn;
+use crate::{RuntimeError, Value, VM};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Natives registered by default, as `(name, arity, function)`
+pub(crate) const BUILTINS: [(&str, usize, NativeFn); 1] = [("clock", 0, clock)];
+
+/// Returns the number of seconds elapsed since the Unix epoch
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RuntimeError::new("System clock is set before the Unix epoch."))?;
+    Ok(Value::from(elapsed.as_secs_f64()))
+}
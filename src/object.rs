@@ -0,0 +1,257 @@
+//! Heap allocated values, shared by the VM through `Value`
+use crate::{OpCode, RuntimeError, Sequence, Value, VerifyError, VM};
+use std::{
+    any::Any,
+    cell::{OnceCell, RefCell, RefMut},
+    collections::HashMap,
+    fmt,
+    rc::Rc,
+};
+
+#[derive(Debug)]
+pub enum Obj {
+    // An immutable string of characters
+    String(String),
+    // A function implemented in Rust by the host application
+    Native(Native),
+    // A function declared in a script
+    Function(Rc<Function>),
+    // An immutable list of values, built by the host application
+    List(Vec<Value>),
+    // Opaque state owned by the host application, exposed to scripts through methods
+    Userdata(Userdata),
+}
+
+impl Obj {
+    /// Name of the object's type, as reported to script authors
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Native(_) => "native function",
+            Self::Function(_) => "function",
+            Self::List(_) => "list",
+            Self::Userdata(_) => "userdata",
+        }
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::String(string) => write!(f, "{}", string),
+            Self::Native(native) => write!(f, "<native fn {}>", native.name()),
+            Self::Function(function) => write!(f, "{}", function),
+            Self::List(values) => {
+                write!(f, "[")?;
+                for (idx, value) in values.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Self::Userdata(userdata) => write!(f, "<{} userdata>", userdata.class().name()),
+        }
+    }
+}
+
+/// A compiled function, holding the bytecode of its body
+#[derive(Debug, Default)]
+pub struct Function {
+    // Name of the function. The top level code of a script is a function without a name
+    name: Option<String>,
+    // Number of parameters the function expects
+    arity: usize,
+    // Bytecode of the function's body
+    sequence: Sequence,
+    // Code the VM runs, set once the sequence is verified
+    runnable: OnceCell<Box<[u8]>>,
+}
+
+impl Function {
+    pub fn new(name: Option<String>, arity: usize, sequence: Sequence) -> Self {
+        Self {
+            name,
+            arity,
+            sequence,
+            runnable: OnceCell::new(),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    /// Code the VM runs, verifying the function the first time around. It is the code of the
+    /// sequence followed by an implicit `return nil`, such that the VM never runs past its end
+    pub(crate) fn runnable_code(&self) -> Result<&[u8], VerifyError> {
+        if let Some(code) = self.runnable.get() {
+            return Ok(code);
+        }
+        self.verify()?;
+        Ok(self.runnable.get_or_init(|| {
+            let mut code = self.sequence.code().to_vec();
+            for opcode in [OpCode::Nil, OpCode::Return] {
+                code.push(opcode.try_into().expect("Known opcodes have a byte"));
+            }
+            code.into_boxed_slice()
+        }))
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.name() {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// Signature of Rust functions callable from scripts. The function receives the VM that called
+/// it, such that it could inspect or alter its state, along with the call's arguments.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A Rust function registered under a name and callable from scripts
+#[derive(Debug, Clone)]
+pub struct Native {
+    // Name under which the function is registered as a global
+    name: String,
+    // Number of arguments the function must be called with
+    arity: usize,
+    // Rust code to execute
+    function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: &str, arity: usize, function: NativeFn) -> Self {
+        Self {
+            name: name.to_string(),
+            arity,
+            function,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn function(&self) -> NativeFn {
+        self.function
+    }
+}
+
+/// Signature of Rust functions callable as methods on userdata. Besides the VM and the arguments,
+/// the function receives the userdata the method was invoked on.
+pub type MethodFn = fn(&mut VM, &Userdata, &[Value]) -> Result<Value, RuntimeError>;
+
+/// Signature of the function releasing the host state of a userdata
+pub type Finalizer = fn(&mut dyn Any);
+
+/// Describes a kind of userdata: the methods scripts can invoke on it and how to release it
+pub struct UserdataClass {
+    // Name reported to script authors
+    name: String,
+    // Methods, by name, along with the number of arguments they take
+    methods: HashMap<String, (usize, MethodFn)>,
+    // Called with the host state once the userdata is no longer referenced
+    finalizer: Option<Finalizer>,
+}
+
+impl UserdataClass {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            methods: HashMap::new(),
+            finalizer: None,
+        }
+    }
+
+    /// Adds a method which scripts can invoke as `userdata.name(...)` with exactly `arity`
+    /// arguments
+    pub fn method(mut self, name: &str, arity: usize, function: MethodFn) -> Self {
+        self.methods.insert(name.to_string(), (arity, function));
+        self
+    }
+
+    /// Sets the function releasing the host state, once no value refers to the userdata anymore
+    pub fn finalizer(mut self, finalizer: Finalizer) -> Self {
+        self.finalizer = Some(finalizer);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the arity and the function of the method called `name`, if any
+    pub fn get_method(&self, name: &str) -> Option<(usize, MethodFn)> {
+        self.methods.get(name).copied()
+    }
+}
+
+/// A Rust value handed to scripts. Scripts cannot look inside it, they can only pass it around and
+/// invoke the methods registered in its class.
+pub struct Userdata {
+    // Methods and finalizer shared by all userdata of the same kind
+    class: Rc<UserdataClass>,
+    // The host state. Methods borrow it for the duration of their call
+    data: RefCell<Box<dyn Any>>,
+}
+
+impl Userdata {
+    pub fn new<T: Any>(class: Rc<UserdataClass>, data: T) -> Self {
+        Self {
+            class,
+            data: RefCell::new(Box::new(data)),
+        }
+    }
+
+    pub fn class(&self) -> &UserdataClass {
+        &self.class
+    }
+
+    /// Mutably borrows the host state as a `T`. Fails if the state is of another type or if it
+    /// is already borrowed, for instance by a method that is still running.
+    pub fn borrow_mut<T: Any>(&self) -> Result<RefMut<'_, T>, RuntimeError> {
+        let data = self.data.try_borrow_mut().map_err(|_| {
+            RuntimeError::new(format!("{} userdata is already in use.", self.class.name))
+        })?;
+        RefMut::filter_map(data, |data| data.downcast_mut::<T>()).map_err(|_| {
+            RuntimeError::new(format!(
+                "{} userdata does not hold the expected type.",
+                self.class.name
+            ))
+        })
+    }
+}
+
+impl Drop for Userdata {
+    fn drop(&mut self) {
+        // The last reference to the userdata is gone, let the host release its state
+        if let Some(finalizer) = self.class.finalizer {
+            finalizer(self.data.get_mut().as_mut());
+        }
+    }
+}
+
+impl fmt::Debug for Userdata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Userdata")
+            .field("class", &self.class.name)
+            .finish_non_exhaustive()
+    }
+}
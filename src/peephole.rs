@@ -0,0 +1,383 @@
+//! Peephole optimizer, rewriting short runs of instructions of a sequence into cheaper ones. Jumps
+//! are threaded through the jumps they land on, code which cannot run is dropped, as are pairs of
+//! instructions which cancel each other out. Common runs of instructions are then fused into
+//! superinstructions, which do the same work in a single dispatch. Jump offsets and locations
+//! follow the instructions they belong to, as do the ranges of code where local variables are named.
+use crate::{LocalName, Location, Locations, OpCode, Sequence};
+
+// Instruction of the sequence being optimized
+struct Instruction {
+    opcode: OpCode,
+    // Operand bytes. The offsets ending the operands of jumps are computed again
+    operands: Vec<u8>,
+    // Location of each byte of the instruction
+    locations: Vec<Location>,
+    // Index of the instruction a jump lands on. The number of instructions stands for the end of
+    // the code
+    target: Option<usize>,
+    // Whether the instruction was dropped
+    removed: bool,
+    // Offset of the instruction in the original code
+    origin: usize,
+}
+
+impl Sequence {
+    /// Optimizes the sequence, which must be well formed. The optimized sequence behaves the same
+    /// as the original one, and uses its constant pool as is. Sequences which cannot be decoded or
+    /// whose jumps would grow too long are handed back unchanged.
+    pub fn optimize(&self) -> Sequence {
+        self.optimize_with(true)
+    }
+
+    // Same as `Sequence::optimize`, fusing instructions into superinstructions only if
+    // `superinstructions` is set
+    pub(crate) fn optimize_with(&self, superinstructions: bool) -> Sequence {
+        let Some(mut instructions) = decode(self) else {
+            return self.clone();
+        };
+        // Each rewrite can expose more of them, so we go on until there is nothing left to do
+        loop {
+            let mut changed = thread_jumps(&mut instructions);
+            changed |= remove_unreachable(&mut instructions);
+            changed |= remove_pairs(self, &mut instructions);
+            if !changed {
+                break;
+            }
+            instructions = compact(instructions);
+        }
+        if superinstructions {
+            fuse(&mut instructions);
+        }
+        let instructions = compact(instructions);
+        encode(self, &instructions).unwrap_or_else(|| self.clone())
+    }
+}
+
+// Splits the code into instructions, resolving where jumps land
+fn decode(sequence: &Sequence) -> Option<Vec<Instruction>> {
+    let code = sequence.code();
+    let mut instructions = Vec::new();
+    // Index of the instruction starting at each offset, if any
+    let mut starts = vec![None; code.len() + 1];
+    // Offset each jump lands on
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::from(code[offset]);
+        if let OpCode::Unknown(_) = opcode {
+            return None;
+        }
+        let end = offset + 1 + opcode.operand_len();
+        let operands = code.get(offset + 1..end)?.to_vec();
+        let target = match opcode {
+            OpCode::Loop => Some(end.checked_sub(usize::from(sequence.read_u16(end - 2)))?),
+            opcode if opcode.is_jump() => Some(end + usize::from(sequence.read_u16(end - 2))),
+            _ => None,
+        };
+        starts[offset] = Some(instructions.len());
+        targets.push(target);
+        instructions.push(Instruction {
+            opcode,
+            operands,
+            locations: (offset..end).map(|idx| sequence.location(idx)).collect(),
+            target: None,
+            removed: false,
+            origin: offset,
+        });
+        offset = end;
+    }
+    starts[code.len()] = Some(instructions.len());
+
+    // Jumps must land on an instruction, or right at the end of the code
+    for (instruction, target) in instructions.iter_mut().zip(targets) {
+        if let Some(target) = target {
+            instruction.target = Some((*starts.get(target)?)?);
+        }
+    }
+    Some(instructions)
+}
+
+// Makes jumps landing on an unconditional jump land where that one goes. Conditional jumps also
+// go through the conditional jumps they land on, as the value they test is still on the stack
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    for idx in 0..instructions.len() {
+        let Some(mut target) = instructions[idx].target else {
+            continue;
+        };
+        // Conditional jumps leave a falsey value on the stack when they are taken
+        let conditional = matches!(
+            instructions[idx].opcode,
+            OpCode::JumpIfFalse | OpCode::JumpIfNotLessLocal
+        );
+        // Jumps we went through, such that we stop on a loop made only of jumps
+        let mut visited = vec![idx];
+        while let Some(next) = instructions.get(target) {
+            let goes_through = match next.opcode {
+                OpCode::Jump | OpCode::Loop => true,
+                OpCode::JumpIfFalse => conditional,
+                _ => false,
+            };
+            if !goes_through || visited.contains(&target) {
+                break;
+            }
+            visited.push(target);
+            let next = next.target.expect("Jumps have a target");
+            // Conditional jumps can only go forward
+            if conditional && next <= idx {
+                break;
+            }
+            target = next;
+        }
+        if instructions[idx].target != Some(target) {
+            instructions[idx].target = Some(target);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Drops the instructions which cannot be reached from the start of the code
+fn remove_unreachable(instructions: &mut [Instruction]) -> bool {
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(idx) = pending.pop() {
+        if idx >= instructions.len() || reachable[idx] {
+            continue;
+        }
+        reachable[idx] = true;
+        let instruction = &instructions[idx];
+        if let Some(target) = instruction.target {
+            pending.push(target);
+        }
+        if let OpCode::Return | OpCode::Jump | OpCode::Loop = instruction.opcode {
+            continue;
+        }
+        pending.push(idx + 1);
+    }
+
+    let mut changed = false;
+    for (instruction, reachable) in instructions.iter_mut().zip(reachable) {
+        if !reachable {
+            instruction.removed = true;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Drops pairs of instructions which leave the stack as they found it: a value loaded and popped
+// right away, or a number negated twice. The second instruction of a pair must not be jumped to,
+// as a jump landing there would skip the first one
+fn remove_pairs(sequence: &Sequence, instructions: &mut [Instruction]) -> bool {
+    let mut targets = vec![false; instructions.len() + 1];
+    for instruction in instructions.iter() {
+        if let Some(target) = instruction.target {
+            targets[target] = true;
+        }
+    }
+
+    let mut changed = false;
+    let mut idx = 0;
+    while idx + 1 < instructions.len() {
+        let (first, second) = (&instructions[idx], &instructions[idx + 1]);
+        let cancels = !first.removed
+            && !second.removed
+            && !targets[idx + 1]
+            && match (first.opcode, second.opcode) {
+                (
+                    OpCode::Constant
+                    | OpCode::ConstantLong
+                    | OpCode::Nil
+                    | OpCode::True
+                    | OpCode::False,
+                    OpCode::Pop,
+                ) => true,
+                // Negating anything but a number fails, so the operand must be known to be one
+                (OpCode::Negate, OpCode::Negate) => {
+                    idx > 0 && !targets[idx] && is_numeric(sequence, &instructions[idx - 1])
+                }
+                _ => false,
+            };
+        if cancels {
+            instructions[idx].removed = true;
+            instructions[idx + 1].removed = true;
+            changed = true;
+            idx += 2;
+        } else {
+            idx += 1;
+        }
+    }
+    changed
+}
+
+// Whether the instruction always leaves a number on top of the stack
+fn is_numeric(sequence: &Sequence, instruction: &Instruction) -> bool {
+    match instruction.opcode {
+        OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Negate => !instruction.removed,
+        OpCode::Constant => {
+            !instruction.removed
+                && sequence
+                    .constant(usize::from(instruction.operands[0]))
+                    .as_number()
+                    .is_some()
+        }
+        _ => false,
+    }
+}
+
+// Replaces runs of instructions with the superinstructions standing for them. Only the first
+// instruction of a run may be jumped to, and the whole run takes the location of the instruction
+// which may fail, such that errors are reported on the same line
+fn fuse(instructions: &mut [Instruction]) {
+    let mut targets = vec![false; instructions.len() + 1];
+    for instruction in instructions.iter() {
+        if let Some(target) = instruction.target {
+            targets[target] = true;
+        }
+    }
+    // Index of the constant a `Constant` instruction loads
+    let constant = |instruction: &Instruction| match instruction.opcode {
+        OpCode::Constant => Some(instruction.operands[0]),
+        _ => None,
+    };
+    // Slot of the local variable a `GetLocal` instruction loads
+    let local = |instruction: &Instruction| match instruction.opcode {
+        OpCode::GetLocal => Some(instruction.operands[0]),
+        _ => None,
+    };
+
+    let mut idx = 0;
+    while idx < instructions.len() {
+        let run = &instructions[idx..];
+        let jumped_into = |len: usize| (idx + 1..idx + len).any(|idx| targets[idx]);
+        let fused = match run {
+            [get, load, add, set, ..]
+                if add.opcode == OpCode::Add
+                    && set.opcode == OpCode::SetLocal
+                    && local(get).is_some_and(|slot| set.operands[0] == slot)
+                    && constant(load).is_some()
+                    && !jumped_into(4) =>
+            {
+                let operands = vec![load.operands[0], get.operands[0]];
+                Some((4, OpCode::IncrementLocal, operands, 2, None))
+            }
+            [get, load, less, jump, ..]
+                if less.opcode == OpCode::Less
+                    && jump.opcode == OpCode::JumpIfFalse
+                    && local(get).is_some()
+                    && constant(load).is_some()
+                    && !jumped_into(4) =>
+            {
+                let operands = vec![load.operands[0], get.operands[0], 0, 0];
+                Some((4, OpCode::JumpIfNotLessLocal, operands, 2, jump.target))
+            }
+            [load, add, ..]
+                if add.opcode == OpCode::Add && constant(load).is_some() && !jumped_into(2) =>
+            {
+                Some((2, OpCode::AddConstant, load.operands.clone(), 1, None))
+            }
+            [get, ..] if local(get).is_some_and(|slot| slot <= 3) => {
+                let opcode = match get.operands[0] {
+                    0 => OpCode::GetLocal0,
+                    1 => OpCode::GetLocal1,
+                    2 => OpCode::GetLocal2,
+                    _ => OpCode::GetLocal3,
+                };
+                Some((1, opcode, vec![], 0, None))
+            }
+            _ => None,
+        };
+        let Some((len, opcode, operands, failing, target)) = fused else {
+            idx += 1;
+            continue;
+        };
+
+        let location = instructions[idx + failing].locations[0];
+        for instruction in &mut instructions[idx + 1..idx + len] {
+            instruction.removed = true;
+        }
+        let instruction = &mut instructions[idx];
+        instruction.locations = vec![location; 1 + operands.len()];
+        instruction.opcode = opcode;
+        instruction.operands = operands;
+        instruction.target = target;
+        idx += len;
+    }
+}
+
+// Drops the removed instructions. Jumps landing on one of them land on the next instruction kept
+fn compact(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    // New index of each instruction, or of the next one kept for those which are removed
+    let mut indices = vec![0; instructions.len() + 1];
+    let mut kept = 0;
+    for (idx, instruction) in instructions.iter().enumerate() {
+        indices[idx] = kept;
+        if !instruction.removed {
+            kept += 1;
+        }
+    }
+    indices[instructions.len()] = kept;
+
+    instructions
+        .into_iter()
+        .filter(|instruction| !instruction.removed)
+        .map(|mut instruction| {
+            instruction.target = instruction.target.map(|target| indices[target]);
+            instruction
+        })
+        .collect()
+}
+
+// Lays the instructions out again, computing the offsets of jumps. Fails if one of them is too far
+fn encode(sequence: &Sequence, instructions: &[Instruction]) -> Option<Sequence> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += instruction.locations.len();
+    }
+    offsets.push(offset);
+
+    let mut optimized =
+        Sequence::from_parts(Vec::new(), Locations::new(), sequence.constants().to_vec());
+    for (idx, instruction) in instructions.iter().enumerate() {
+        let mut opcode = instruction.opcode;
+        let mut operands = instruction.operands.clone();
+        if let Some(target) = instruction.target {
+            let end = offsets[idx + 1];
+            let target = offsets[target];
+            // Threading may turn a jump forward into a jump backward, and the other way around
+            let jump = match opcode {
+                OpCode::Jump | OpCode::Loop if target < end => {
+                    opcode = OpCode::Loop;
+                    end - target
+                }
+                OpCode::Jump | OpCode::Loop => {
+                    opcode = OpCode::Jump;
+                    target - end
+                }
+                _ => target.checked_sub(end)?,
+            };
+            let len = operands.len();
+            operands[len - 2..].copy_from_slice(&u16::try_from(jump).ok()?.to_le_bytes());
+        }
+        let bytes = std::iter::once(opcode.try_into().ok()?).chain(operands);
+        for (byte, location) in bytes.zip(&instruction.locations) {
+            optimized.push(byte, *location).ok()?;
+        }
+    }
+
+    // Offsets move to the first instruction kept from there on
+    let moved = |origin: usize| {
+        let idx = instructions.partition_point(|instruction| instruction.origin < origin);
+        offsets[idx]
+    };
+    for local in sequence.local_names() {
+        let (start, end) = (moved(local.start()), moved(local.end()));
+        if start < end {
+            optimized.push_local_name(LocalName::new(local.name(), local.slot(), start, end));
+        }
+    }
+    Some(optimized)
+}
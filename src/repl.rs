@@ -0,0 +1,127 @@
+//! Support for the interactive prompt started by `MMalis::interactive`
+use crate::scan::{ScanError, Scanner};
+use crate::token::{Keyword, SingleChar, TokenType};
+use crate::{Diagnostic, Disassembler, InterpretError, MMalis, MMalisError, WriteTracer};
+use std::fs;
+
+/// Commands understood by the prompt, on top of the code itself
+const HELP: &str = "\
+:dis [code]      disassemble `code`, or the last input
+:tokens [code]   list the tokens of `code`, or of the last input
+:globals         list the global variables
+:load <file>     run `file` in the current session
+:reset           forget everything defined so far
+:trace on|off    trace the execution of each instruction to the standard error
+:trace <file>    trace the execution of each instruction to `file`
+:optimize on|off optimize the code when compiling it
+:help            show this message
+q, quit, exit    leave the prompt";
+
+/// Returns whether `source` is a complete input, or whether the user is still typing it because
+/// it has unclosed braces, parentheses or strings. Unbalanced closing delimiters count as complete,
+/// such that the compiler can report them.
+pub(crate) fn is_complete(source: &[u8]) -> bool {
+    let mut scanner = Scanner::new(source);
+    // Number of delimiters opened and not closed yet
+    let mut depth: isize = 0;
+    while let Some(token) = scanner.next_token() {
+        match token {
+            Ok(token) => match token.t_type() {
+                TokenType::SingleChar(SingleChar::LeftParen | SingleChar::LeftBrace) => depth += 1,
+                TokenType::SingleChar(SingleChar::RightParen | SingleChar::RightBrace) => {
+                    depth -= 1
+                }
+                _ => {}
+            },
+            // A string spanning multiple lines is still being typed
+            Err(ScanError::UnterminatedString(..)) => return false,
+            Err(_) => {}
+        }
+    }
+    depth <= 0
+}
+
+impl MMalis {
+    /// Words the prompt completes: the keywords and the currently defined globals
+    pub(crate) fn completion_words(&self) -> Vec<String> {
+        Keyword::ALL
+            .iter()
+            .map(|keyword| keyword.as_str().to_string())
+            .chain(self.vm.globals().map(|(name, _)| name.to_string()))
+            .collect()
+    }
+
+    /// Runs the meta-command `command`, typed in the prompt. `last_input` is the last piece of code
+    /// that was evaluated.
+    pub(crate) fn meta_command(
+        &mut self,
+        command: &str,
+        last_input: &str,
+    ) -> Result<(), MMalisError> {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+        // Commands inspecting code work on the last input, unless given some
+        let code = if argument.is_empty() {
+            last_input
+        } else {
+            argument
+        };
+
+        match name {
+            ":dis" => {
+                let script = self.compiler(true).compile(code.as_bytes());
+                match script {
+                    Ok(script) => Disassembler::dis_function(&script),
+                    Err(errors) => {
+                        for err in &errors {
+                            print!(
+                                "{}",
+                                Diagnostic::from(err).render("<repl>", code.as_bytes())
+                            );
+                        }
+                    }
+                }
+            }
+            ":tokens" => {
+                let mut scanner = Scanner::new(code.as_bytes());
+                while let Some(token) = scanner.next_token() {
+                    match token {
+                        Ok(token) => {
+                            let lexeme = code.get(token.start()..token.end()).unwrap_or_default();
+                            println!("{:04} {:?} {}", token.line(), token.t_type(), lexeme);
+                        }
+                        Err(err) => print!(
+                            "{}",
+                            Diagnostic::from(&err).render("<repl>", code.as_bytes())
+                        ),
+                    }
+                }
+            }
+            ":globals" => {
+                let mut globals: Vec<_> = self.vm.globals().collect();
+                globals.sort_by_key(|(name, _)| *name);
+                for (name, value) in globals {
+                    println!("{name} = {value}");
+                }
+            }
+            ":load" if !argument.is_empty() => {
+                let source = fs::read(argument)?;
+                // Errors point into the loaded file rather than into what was typed
+                if let Err(err) = self.run(&source, false) {
+                    print!("{}", err.render(argument, &source));
+                }
+            }
+            ":reset" => self.vm.reset().map_err(InterpretError::from)?,
+            ":trace" if argument == "on" => self.vm.set_trace_execution(true),
+            ":trace" if argument == "off" => self.vm.set_trace_execution(false),
+            ":trace" if !argument.is_empty() => self.vm.set_tracer(WriteTracer::file(argument)?),
+            ":optimize" if argument == "on" => self.set_optimize(true),
+            ":optimize" if argument == "off" => self.set_optimize(false),
+            ":help" => println!("{HELP}"),
+            _ => println!("Unknown command `{command}`, see `:help`."),
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-use crate::token::{Comparison, Literal, SingleChar, Token, TokenType};
+use crate::token::{Comparison, Keyword, Literal, SingleChar, Token, TokenType};
+use std::fmt;
 
 #[derive(Debug)]
 pub struct Scanner<'a> {
@@ -9,6 +10,10 @@ pub struct Scanner<'a> {
     offset: usize,
     // The line the cursor is on
     line: usize,
+    // Offset at which the line the cursor is on starts
+    line_start: usize,
+    // Whether the `Eof` token was already handed out
+    eof_reached: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -19,19 +24,36 @@ impl<'a> Scanner<'a> {
             start: 0,
             offset: 0,
             line: 1,
+            line_start: 0,
+            eof_reached: false,
         }
     }
 
     pub fn next_token(&mut self) -> Option<Result<Token, ScanError>> {
-        if self.start >= self.data.len() {
+        if self.eof_reached {
             None
         } else {
             // Skip whitespaces and comments first
-            self.skip_non_tokens()?;
+            self.skip_non_tokens();
             // Start from where we left off at the previous token
             self.start = self.offset;
+            // Lexemes spanning several lines are given the column they start at
+            let column = self.start - self.line_start + 1;
 
-            let token_type = match self.next_byte()? {
+            // Once we run out of bytes, we hand out a single `Eof` token, after which the
+            // scanner is exhausted
+            let Some(byte) = self.next_byte().copied() else {
+                self.eof_reached = true;
+                return Some(Ok(Token::new(
+                    TokenType::Eof,
+                    self.start,
+                    0,
+                    self.line,
+                    column,
+                )));
+            };
+
+            let token_type = match byte {
                 b'(' => TokenType::SingleChar(SingleChar::LeftParen),
                 b')' => TokenType::SingleChar(SingleChar::RightParen),
                 b'{' => TokenType::SingleChar(SingleChar::LeftBrace),
@@ -46,28 +68,28 @@ impl<'a> Scanner<'a> {
                 b'*' => TokenType::SingleChar(SingleChar::Star),
                 b'?' => TokenType::SingleChar(SingleChar::Question),
                 b'!' => {
-                    if let Some(b'=') = self.next_byte() {
+                    if self.next_matches(b'=') {
                         TokenType::Comparison(Comparison::BangEqual)
                     } else {
                         TokenType::SingleChar(SingleChar::Bang)
                     }
                 }
                 b'=' => {
-                    if let Some(b'=') = self.next_byte() {
+                    if self.next_matches(b'=') {
                         TokenType::Comparison(Comparison::EqualEqual)
                     } else {
                         TokenType::SingleChar(SingleChar::Equal)
                     }
                 }
                 b'<' => {
-                    if let Some(b'=') = self.next_byte() {
+                    if self.next_matches(b'=') {
                         TokenType::Comparison(Comparison::LessEqual)
                     } else {
                         TokenType::SingleChar(SingleChar::Less)
                     }
                 }
                 b'>' => {
-                    if let Some(b'=') = self.next_byte() {
+                    if self.next_matches(b'=') {
                         TokenType::Comparison(Comparison::GreaterEqual)
                     } else {
                         TokenType::SingleChar(SingleChar::Greater)
@@ -80,25 +102,77 @@ impl<'a> Scanner<'a> {
                         Err(e) => return Some(Err(e)),
                     }
                 }
-                _ => TokenType::Eof,
+                b'0'..=b'9' => self.number(),
+                b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.identifier(),
+                _ => return Some(Err(ScanError::UnexpectedByte(byte, self.start, self.line))),
             };
-            // Create a new debug token
-            let token = Token::new(token_type, self.start, 1, self.line);
+            // Create a new token spanning from the start of the lexeme up to the cursor
+            let len = self.offset - self.start;
+            let token = Token::new(token_type, self.start, len, self.line, column);
             Some(Ok(token))
         }
     }
 
     fn next_byte(&mut self) -> Option<&u8> {
         let b = self.data.get(self.offset);
-        self.offset = self.offset.saturating_add(1);
+        // Only move past bytes that exist, such that `offset` never goes past the end of `data`
+        if b.is_some() {
+            self.offset += 1;
+        }
         b
     }
 
-    fn peek_next(&mut self) -> Option<&u8> {
+    fn peek_next(&self) -> Option<&u8> {
         let b = self.data.get(self.offset);
         b
     }
 
+    // Peeks the byte after the one `peek_next` would return
+    fn peek_second(&self) -> Option<&u8> {
+        self.data.get(self.offset + 1)
+    }
+
+    // Consumes the next byte only if it is equal to `expected`
+    fn next_matches(&mut self, expected: u8) -> bool {
+        if self.peek_next() == Some(&expected) {
+            self.offset += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> TokenType {
+        // Consume the integer part
+        while self.peek_next().is_some_and(u8::is_ascii_digit) {
+            self.offset += 1;
+        }
+        // Look for a fractional part, which needs at least one digit after the dot
+        if self.peek_next() == Some(&b'.') && self.peek_second().is_some_and(u8::is_ascii_digit) {
+            // Consume the dot
+            self.offset += 1;
+            while self.peek_next().is_some_and(u8::is_ascii_digit) {
+                self.offset += 1;
+            }
+        }
+        TokenType::Literal(Literal::Number)
+    }
+
+    fn identifier(&mut self) -> TokenType {
+        // Identifiers may contain digits after their first character
+        while self
+            .peek_next()
+            .is_some_and(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+        {
+            self.offset += 1;
+        }
+        // Reserved words take precedence over user identifiers
+        match Keyword::from_bytes(&self.data[self.start..self.offset]) {
+            Some(keyword) => TokenType::Keyword(keyword),
+            None => TokenType::Ident,
+        }
+    }
+
     fn string(&mut self) -> Result<TokenType, ScanError> {
         // Peek the next byte
         while let Some(byte) = self.peek_next() {
@@ -110,6 +184,7 @@ impl<'a> Scanner<'a> {
             if *byte == b'\n' {
                 // We tell the scanner we are at the next line
                 self.line += 1;
+                self.line_start = self.offset + 1;
             }
             self.next_byte().ok_or(ScanError::CannotConsumeByte)?;
         }
@@ -132,29 +207,26 @@ impl<'a> Scanner<'a> {
         self.data.len() == self.offset
     }
 
-    fn skip_non_tokens(&mut self) -> Option<()> {
-        while match self.peek_next()? {
-            b' ' | b'\r' | b'\t' => true,
-            b'\n' => {
-                self.line = self.line.saturating_add(1);
-                true
-            }
-            b'/' => {
-                if let Some(b'/') = self.peek_next() {
-                    // A comment goes until end of line
-                    while self.peek_next()? != &b'\n' {
-                        self.next_byte()?;
+    fn skip_non_tokens(&mut self) {
+        while let Some(byte) = self.peek_next() {
+            match byte {
+                b' ' | b'\r' | b'\t' => {}
+                b'\n' => {
+                    self.line = self.line.saturating_add(1);
+                    self.line_start = self.offset + 1;
+                }
+                b'/' if self.peek_second() == Some(&b'/') => {
+                    // A comment goes until end of line. The newline itself is left for the next
+                    // iteration, such that it is accounted for in `line`
+                    while self.peek_next().is_some_and(|byte| *byte != b'\n') {
+                        self.offset += 1;
                     }
-                    true
-                } else {
-                    false
+                    continue;
                 }
+                _ => break,
             }
-            _ => false,
-        } {
-            self.next_byte()?;
+            self.offset += 1;
         }
-        Some(())
     }
 }
 
@@ -162,4 +234,24 @@ impl<'a> Scanner<'a> {
 pub enum ScanError {
     UnterminatedString(usize, usize),
     CannotConsumeByte,
+    // A byte that cannot start any token, along with its offset and line
+    UnexpectedByte(u8, usize, usize),
 }
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::UnterminatedString(start, _) => {
+                write!(f, "Unterminated string starting at offset {start}.")
+            }
+            Self::CannotConsumeByte => write!(f, "Unexpected end of input."),
+            Self::UnexpectedByte(byte, _, line) => write!(
+                f,
+                "[line {line}] Unexpected character '{}'.",
+                byte.escape_ascii()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
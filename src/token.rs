@@ -1,4 +1,4 @@
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Token {
     // Token type, `type` is reserved
     t_type: TokenType,
@@ -8,16 +8,19 @@ pub struct Token {
     len: usize,
     // Line on which the token occurs
     line: usize,
+    // Column the token starts at, in bytes and starting at 1
+    column: usize,
 }
 
 impl Token {
     // Used to debug the source code
-    pub fn new(t_type: TokenType, start: usize, len: usize, line: usize) -> Self {
+    pub fn new(t_type: TokenType, start: usize, len: usize, line: usize, column: usize) -> Self {
         Self {
             t_type,
             start,
             len,
             line,
+            column,
         }
     }
 
@@ -25,6 +28,10 @@ impl Token {
         self.line
     }
 
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
     pub fn start(&self) -> usize {
         self.start
     }
@@ -38,13 +45,7 @@ impl Token {
     }
 }
 
-impl Default for TokenType {
-    fn default() -> Self {
-        Self::Eof
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum TokenType {
     SingleChar(SingleChar),
     Comparison(Comparison),
@@ -54,10 +55,11 @@ pub enum TokenType {
     DebugByte(u8),
     Ident,
     Ignored,
+    #[default]
     Eof,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SingleChar {
     LeftParen,
     RightParen,
@@ -78,7 +80,7 @@ pub enum SingleChar {
     Greater,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Comparison {
     BangEqual,
     EqualEqual,
@@ -86,14 +88,14 @@ pub enum Comparison {
     LessEqual,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Literal {
     // Because `String` is reserved in Rust
     LitString,
     Number,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Keyword {
     And,
     Or,
@@ -113,3 +115,56 @@ pub enum Keyword {
     ClassSelf,
     Super,
 }
+
+impl Keyword {
+    /// Every keyword of the language, in declaration order
+    pub const ALL: [Keyword; 17] = [
+        Self::And,
+        Self::Or,
+        Self::Not,
+        Self::Class,
+        Self::Fun,
+        Self::If,
+        Self::Else,
+        Self::While,
+        Self::For,
+        Self::True,
+        Self::False,
+        Self::Nil,
+        Self::Var,
+        Self::Print,
+        Self::Return,
+        Self::ClassSelf,
+        Self::Super,
+    ];
+
+    /// Returns the source code spelling of the keyword
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::And => "and",
+            Self::Or => "or",
+            Self::Not => "not",
+            Self::Class => "class",
+            Self::Fun => "fun",
+            Self::If => "if",
+            Self::Else => "else",
+            Self::While => "while",
+            Self::For => "for",
+            Self::True => "true",
+            Self::False => "false",
+            Self::Nil => "nil",
+            Self::Var => "var",
+            Self::Print => "print",
+            Self::Return => "return",
+            Self::ClassSelf => "self",
+            Self::Super => "super",
+        }
+    }
+
+    /// Returns the keyword spelled by `bytes`, if any
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|keyword| keyword.as_str().as_bytes() == bytes)
+    }
+}
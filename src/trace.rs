@@ -0,0 +1,250 @@
+//! Tracing of the instructions the VM executes, enabled at runtime with `VM::set_tracer`. Each
+//! instruction is handed to the tracer right before it runs, along with the stack it finds, the
+//! calls in progress and the globals. Tracers can also interrupt the execution, which debuggers
+//! build on.
+use crate::object::Function;
+use crate::vm::CallFrame;
+use crate::{DecodedInstruction, Disassembler, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Receives each instruction the VM executes, right before it is executed
+pub trait Tracer {
+    fn trace(&mut self, step: &TraceStep<'_>);
+
+    /// Whether the VM should stop right after tracing the last instruction, instead of executing
+    /// it. It then fails with `InterpretError::Interrupted`
+    fn interrupted(&self) -> bool {
+        false
+    }
+}
+
+// Closures make for quick tracers
+impl<F: FnMut(&TraceStep<'_>)> Tracer for F {
+    fn trace(&mut self, step: &TraceStep<'_>) {
+        self(step)
+    }
+}
+
+/// An instruction about to be executed, along with the state of the VM
+pub struct TraceStep<'a> {
+    function: &'a Function,
+    offset: usize,
+    stack: &'a [Value],
+    // Calls in progress, the innermost being the one executing the instruction. Its offset is not
+    // kept up to date, unlike the ones of its callers
+    frames: &'a [CallFrame],
+    globals: &'a HashMap<String, Value>,
+}
+
+impl<'a> TraceStep<'a> {
+    pub(crate) fn new(
+        function: &'a Function,
+        offset: usize,
+        stack: &'a [Value],
+        frames: &'a [CallFrame],
+        globals: &'a HashMap<String, Value>,
+    ) -> Self {
+        Self {
+            function,
+            offset,
+            stack,
+            frames,
+            globals,
+        }
+    }
+
+    /// Function whose code holds the instruction
+    pub fn function(&self) -> &Function {
+        self.function
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn instruction(&self) -> DecodedInstruction {
+        Disassembler::decode(self.function.sequence(), self.offset)
+    }
+
+    /// Source line the instruction was compiled from
+    pub fn line(&self) -> u32 {
+        self.function.sequence().line(self.offset)
+    }
+
+    /// Whole stack of the VM, from the bottom up
+    pub fn stack(&self) -> &[Value] {
+        self.stack
+    }
+
+    /// Number of calls in progress, including the one executing the instruction
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Index of the first stack slot of the call executing the instruction
+    pub fn base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    /// Named local variables of the call executing the instruction, in the order of their slots
+    pub fn locals(&self) -> Vec<(&'a str, &'a Value)> {
+        self.backtrace()
+            .first()
+            .map_or_else(Vec::new, StackFrame::locals)
+    }
+
+    /// Returns the value of the global variable `name`, if it is defined
+    pub fn global(&self, name: &str) -> Option<&'a Value> {
+        self.globals.get(name)
+    }
+
+    /// Defined global variables, sorted by name
+    pub fn globals(&self) -> Vec<(&'a str, &'a Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        globals
+    }
+
+    /// Returns the value of the variable `name` as seen from the `frame`th call of the
+    /// backtrace: one of its local variables, or else a global variable
+    pub fn lookup(&self, frame: usize, name: &str) -> Option<&'a Value> {
+        self.backtrace()
+            .get(frame)
+            .and_then(|frame| frame.local(name))
+            .or_else(|| self.global(name))
+    }
+
+    /// Calls in progress, starting with the one executing the instruction and ending with the
+    /// script
+    pub fn backtrace(&self) -> Vec<StackFrame<'a>> {
+        let mut frames = Vec::with_capacity(self.frames.len());
+        let mut top = self.stack.len();
+        for (idx, frame) in self.frames.iter().enumerate().rev() {
+            let innermost = idx + 1 == self.frames.len();
+            let (offset, at) = if innermost {
+                (self.offset, self.offset)
+            } else {
+                // Callers are stopped right after the instruction making the call
+                (frame.offset, frame.offset.saturating_sub(1))
+            };
+            frames.push(StackFrame {
+                function: &frame.function,
+                offset,
+                at,
+                slots: self.stack.get(frame.base..top).unwrap_or_default(),
+            });
+            top = top.min(frame.base);
+        }
+        frames
+    }
+}
+
+/// A call in progress, as seen by a tracer
+pub struct StackFrame<'a> {
+    function: &'a Function,
+    offset: usize,
+    // Offset of a byte of the instruction being executed, which is the one making the call for
+    // callers
+    at: usize,
+    // Stack slots of the call, starting with the callee
+    slots: &'a [Value],
+}
+
+impl<'a> StackFrame<'a> {
+    pub fn function(&self) -> &'a Function {
+        self.function
+    }
+
+    /// Offset of the instruction being executed, or for callers of the one they resume at
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Source line of the instruction being executed, which for callers is the one making the call
+    pub fn line(&self) -> u32 {
+        self.function.sequence().line(self.at)
+    }
+
+    /// Stack slots of the call, starting with the callee and its arguments
+    pub fn slots(&self) -> &'a [Value] {
+        self.slots
+    }
+
+    /// Named local variables in scope, in the order of their slots. Variables of inner blocks come
+    /// after the ones they may shadow
+    pub fn locals(&self) -> Vec<(&'a str, &'a Value)> {
+        let sequence = self.function.sequence();
+        sequence
+            .local_names_at(self.at)
+            .into_iter()
+            .filter_map(|local| {
+                let value = self.slots.get(usize::from(local.slot()))?;
+                Some((local.name(), value))
+            })
+            .collect()
+    }
+
+    /// Returns the value of the local variable `name`, if it is in scope
+    pub fn local(&self, name: &str) -> Option<&'a Value> {
+        // Inner blocks come last, and their variables shadow the others
+        self.locals()
+            .into_iter()
+            .rev()
+            .find_map(|(local, value)| (local == name).then_some(value))
+    }
+}
+
+/// Tracer writing the stack and then each instruction on their own lines, to the standard error,
+/// a file or any other writer
+pub struct WriteTracer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> WriteTracer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl WriteTracer<io::Stderr> {
+    /// Traces to the standard error, such that the trace does not mix with what scripts print
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+}
+
+impl WriteTracer<BufWriter<File>> {
+    /// Traces to the file at `path`, which is created or truncated
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Tracer for WriteTracer<W> {
+    fn trace(&mut self, step: &TraceStep<'_>) {
+        let mut out = String::from("          ");
+        for value in step.stack() {
+            out.push_str(&format!("[ {value} ]"));
+        }
+        let function = step.function().to_string();
+        out.push_str(&format!(
+            "\n{function} {:04} {:>4} {}\n",
+            step.offset(),
+            step.line(),
+            step.instruction()
+        ));
+        // Scripts keep running whether or not their trace could be written
+        let _ = self.writer.write_all(out.as_bytes());
+    }
+}
@@ -1,68 +1,319 @@
+use crate::object::{Obj, Userdata};
+use crate::RuntimeError;
 use core::ops::{Add, Div, Mul, Neg, Sub};
-use std::fmt;
+use std::{fmt, rc::Rc};
 
-#[derive(Debug, Clone)]
-pub struct Value(f32);
+#[cfg(feature = "nan-boxing")]
+mod nan_box;
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+
+#[cfg(feature = "nan-boxing")]
+pub use nan_box::Value;
+#[cfg(not(feature = "nan-boxing"))]
+pub use tagged::Value;
+
+/// What a `Value` holds, borrowed from it. Both representations of `Value` hand their contents
+/// out through this view, such that code matching on values does not depend on how they are
+/// laid out
+#[derive(Debug, Clone, Copy)]
+pub enum ValueRef<'a> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(&'a Obj),
+}
+
+impl Value {
+    pub fn is_nil(&self) -> bool {
+        matches!(self.view(), ValueRef::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.view() {
+            ValueRef::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self.view() {
+            ValueRef::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<&Obj> {
+        match self.view() {
+            ValueRef::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.as_obj()? {
+            Obj::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// `nil` and `false` are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self.view(), ValueRef::Nil | ValueRef::Bool(false))
+    }
+
+    /// Name of the value's type, as reported to script authors
+    pub fn type_name(&self) -> &'static str {
+        match self.view() {
+            ValueRef::Nil => "nil",
+            ValueRef::Bool(_) => "bool",
+            ValueRef::Number(_) => "number",
+            ValueRef::Obj(obj) => obj.type_name(),
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Self::nil()
+    }
+}
+
+// Both representations print the same, as the enum `Value` used to be
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.view() {
+            ValueRef::Nil => write!(f, "Nil"),
+            ValueRef::Bool(value) => f.debug_tuple("Bool").field(&value).finish(),
+            ValueRef::Number(value) => f.debug_tuple("Number").field(&value).finish(),
+            ValueRef::Obj(obj) => f.debug_tuple("Obj").field(obj).finish(),
+        }
+    }
+}
 
 impl From<f32> for Value {
     fn from(value: f32) -> Self {
-        Self(value)
+        Self::number(value.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::number(value)
     }
 }
 
 impl From<u16> for Value {
     fn from(value: u16) -> Self {
-        Self(value.into())
+        Self::number(value.into())
     }
 }
 
-impl Neg for Value {
-    type Output = Self;
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::boolean(value)
+    }
+}
 
-    fn neg(self) -> Self::Output {
-        Self(-self.0)
+impl From<Obj> for Value {
+    fn from(value: Obj) -> Self {
+        Self::obj(Rc::new(value))
     }
 }
 
-impl Add for Value {
-    type Output = Self;
+impl From<Userdata> for Value {
+    fn from(value: Userdata) -> Self {
+        Self::from(Obj::Userdata(value))
+    }
+}
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self(self.0 + rhs.0)
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::from(Obj::String(value.to_string()))
     }
 }
 
-impl Sub for Value {
-    type Output = Self;
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::from(Obj::String(value))
+    }
+}
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        Self(self.0 - rhs.0)
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(value: Vec<T>) -> Self {
+        Self::from(Obj::List(value.into_iter().map(Into::into).collect()))
     }
 }
 
-impl Mul for Value {
-    type Output = Self;
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or_else(Self::nil, Into::into)
+    }
+}
+
+/// Conversion from a `Value` into a Rust type, used to read values produced by scripts
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, ConversionError>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        value
+            .as_number()
+            .ok_or_else(|| ConversionError::new("number", value))
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        value
+            .as_bool()
+            .ok_or_else(|| ConversionError::new("bool", value))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| ConversionError::new("string", value))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        match value.as_obj() {
+            Some(Obj::List(values)) => values.iter().map(T::from_value).collect(),
+            _ => Err(ConversionError::new("list", value)),
+        }
+    }
+}
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        Self(self.0 * rhs.0)
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self, ConversionError> {
+        // `nil` stands for the absence of a value
+        if value.is_nil() {
+            Ok(None)
+        } else {
+            T::from_value(value).map(Some)
+        }
     }
 }
 
-impl Div for Value {
+/// A `Value` does not have the type a Rust conversion asked for
+#[derive(Debug)]
+pub struct ConversionError {
+    // Type the conversion expected
+    expected: &'static str,
+    // Type of the value that was found instead
+    found: &'static str,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, found: &Value) -> Self {
+        Self {
+            expected,
+            found: found.type_name(),
+        }
+    }
+
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+
+    pub fn found(&self) -> &'static str {
+        self.found
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Expected a {} but found a {}.",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+// Lets natives convert their arguments with `?`
+impl From<ConversionError> for RuntimeError {
+    fn from(value: ConversionError) -> Self {
+        RuntimeError::new(value.to_string())
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.view(), other.view()) {
+            (ValueRef::Nil, ValueRef::Nil) => true,
+            (ValueRef::Bool(left), ValueRef::Bool(right)) => left == right,
+            (ValueRef::Number(left), ValueRef::Number(right)) => left == right,
+            // Strings compare by their contents, every other object by identity
+            (ValueRef::Obj(left), ValueRef::Obj(right)) => match (left, right) {
+                (Obj::String(left), Obj::String(right)) => left == right,
+                _ => std::ptr::eq(left, right),
+            },
+            _ => false,
+        }
+    }
+}
+
+// The arithmetic operators below are only defined for numbers. The VM type checks the operands
+// before applying any of them, such that any other combination produces `nil`.
+
+impl Neg for Value {
     type Output = Self;
 
-    fn div(self, rhs: Self) -> Self::Output {
-        Self(self.0 / rhs.0)
+    fn neg(self) -> Self::Output {
+        match self.as_number() {
+            Some(value) => Self::number(-value),
+            None => Self::nil(),
+        }
     }
 }
 
+macro_rules! impl_number_op {
+    ($trait:ident, $method:ident, $operator:tt) => {
+        impl $trait for Value {
+            type Output = Self;
+
+            fn $method(self, rhs: Self) -> Self::Output {
+                match (self.as_number(), rhs.as_number()) {
+                    (Some(left), Some(right)) => Self::number(left $operator right),
+                    _ => Self::nil(),
+                }
+            }
+        }
+    };
+}
+
+impl_number_op!(Add, add, +);
+impl_number_op!(Sub, sub, -);
+impl_number_op!(Mul, mul, *);
+impl_number_op!(Div, div, /);
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "{}", self.0)
+        match self.view() {
+            ValueRef::Nil => write!(f, "nil"),
+            ValueRef::Bool(value) => write!(f, "{}", value),
+            ValueRef::Number(value) => write!(f, "{}", value),
+            ValueRef::Obj(obj) => write!(f, "{}", obj),
+        }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Debug, Clone)]
 pub struct ValueVec(pub Vec<Value>);
 
 impl ValueVec {
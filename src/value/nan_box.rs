@@ -0,0 +1,112 @@
+//! NaN-boxed representation of values, taking 8 bytes. Numbers are stored as is, and every other
+//! value hides in the payload of a quiet NaN no arithmetic produces: `nil` and booleans as small
+//! tags, and objects as the address of their reference counted allocation, along with the sign
+//! bit.
+use super::ValueRef;
+use crate::object::Obj;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+// Bits set in every quiet NaN we use to hold something other than a number
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+// Set along with `QNAN` for objects
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+/// A dynamically typed value manipulated by the VM. Anything that does not fit in a machine word
+/// lives on the heap as an `Obj` and is shared by reference counting.
+pub struct Value {
+    bits: u64,
+    // Values holding an object own one strong reference to it, which keeps them from being sent
+    // to other threads
+    _obj: PhantomData<Rc<Obj>>,
+}
+
+impl Value {
+    pub fn nil() -> Self {
+        Self::from_bits(QNAN | TAG_NIL)
+    }
+
+    pub(super) fn boolean(value: bool) -> Self {
+        Self::from_bits(QNAN | if value { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub(super) fn number(value: f64) -> Self {
+        let bits = value.to_bits();
+        // The few NaNs whose payload would read as another value become the canonical NaN, which
+        // keeps their sign
+        if bits & QNAN == QNAN {
+            Self::from_bits(f64::NAN.copysign(value).to_bits())
+        } else {
+            Self::from_bits(bits)
+        }
+    }
+
+    pub(super) fn obj(obj: Rc<Obj>) -> Self {
+        let address = Rc::into_raw(obj) as u64;
+        // Addresses handed out on the platforms we run on fit in 48 bits
+        assert_eq!(
+            address & (SIGN_BIT | QNAN),
+            0,
+            "Address too large to be boxed"
+        );
+        Self::from_bits(SIGN_BIT | QNAN | address)
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            bits,
+            _obj: PhantomData,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+
+    // Address of the object the value holds, if it is one
+    fn obj_ptr(&self) -> Option<*const Obj> {
+        (self.bits & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN)
+            .then_some((self.bits & !(SIGN_BIT | QNAN)) as *const Obj)
+    }
+
+    /// Borrows what the value holds
+    pub fn view(&self) -> ValueRef<'_> {
+        if self.is_number() {
+            return ValueRef::Number(f64::from_bits(self.bits));
+        }
+        if let Some(ptr) = self.obj_ptr() {
+            // SAFETY: the value owns a strong reference to the object, which outlives the borrow
+            return ValueRef::Obj(unsafe { &*ptr });
+        }
+        match self.bits & !QNAN {
+            TAG_NIL => ValueRef::Nil,
+            TAG_FALSE => ValueRef::Bool(false),
+            TAG_TRUE => ValueRef::Bool(true),
+            _ => unreachable!("Invalid NaN-boxed value {:#x}", self.bits),
+        }
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if let Some(ptr) = self.obj_ptr() {
+            // SAFETY: the pointer came from `Rc::into_raw`, and the value still owns a reference
+            unsafe { Rc::increment_strong_count(ptr) };
+        }
+        Self::from_bits(self.bits)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.obj_ptr() {
+            // SAFETY: the pointer came from `Rc::into_raw`, and the reference the value owns is
+            // released exactly once
+            unsafe { Rc::decrement_strong_count(ptr) };
+        }
+    }
+}
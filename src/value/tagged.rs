@@ -0,0 +1,45 @@
+//! Default representation of values: a tagged union, taking 16 bytes
+use super::ValueRef;
+use crate::object::Obj;
+use std::rc::Rc;
+
+/// A dynamically typed value manipulated by the VM. Anything that does not fit in a machine word
+/// lives on the heap as an `Obj` and is shared by reference counting.
+#[derive(Clone)]
+pub struct Value(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(Rc<Obj>),
+}
+
+impl Value {
+    pub fn nil() -> Self {
+        Self(Repr::Nil)
+    }
+
+    pub(super) fn boolean(value: bool) -> Self {
+        Self(Repr::Bool(value))
+    }
+
+    pub(super) fn number(value: f64) -> Self {
+        Self(Repr::Number(value))
+    }
+
+    pub(super) fn obj(obj: Rc<Obj>) -> Self {
+        Self(Repr::Obj(obj))
+    }
+
+    /// Borrows what the value holds
+    pub fn view(&self) -> ValueRef<'_> {
+        match &self.0 {
+            Repr::Nil => ValueRef::Nil,
+            Repr::Bool(value) => ValueRef::Bool(*value),
+            Repr::Number(value) => ValueRef::Number(*value),
+            Repr::Obj(obj) => ValueRef::Obj(obj),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Checks that bytecode is well formed before the VM runs it. The compiler only produces valid
+//! bytecode, but sequences read from compiled files or built by hand could make the VM read out
+//! of bounds or corrupt its stack.
+use crate::object::{Function, Obj};
+use crate::{OpCode, Sequence};
+use std::{error::Error, fmt};
+
+impl Sequence {
+    /// Verifies the sequence as the top level code of a script, along with the functions it
+    /// declares
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify(self, 0, None)
+    }
+}
+
+impl Function {
+    /// Verifies the body of the function, along with the functions it declares
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify(self.sequence(), self.arity(), self.name())
+    }
+}
+
+fn verify(sequence: &Sequence, arity: usize, name: Option<&str>) -> Result<(), VerifyError> {
+    let error = |offset, kind| VerifyError {
+        function: name.map(str::to_string),
+        offset,
+        kind,
+    };
+    let code = sequence.code();
+    let constants = sequence.constants();
+
+    // Decode every instruction, checking it is complete and that its constants exist
+    let mut instructions = Vec::new();
+    // Whether an instruction starts at each offset
+    let mut boundaries = vec![false; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::from(code[offset]);
+        if let OpCode::Unknown(byte) = opcode {
+            return Err(error(offset, VerifyErrorKind::UnknownOpcode(byte)));
+        }
+        let Some(operands) = code.get(offset + 1..offset + 1 + opcode.operand_len()) else {
+            return Err(error(offset, VerifyErrorKind::Truncated));
+        };
+
+        let constant = match opcode {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::Invoke
+            | OpCode::AddConstant
+            | OpCode::IncrementLocal
+            | OpCode::JumpIfNotLessLocal => Some(usize::from(operands[0])),
+            OpCode::ConstantLong => {
+                Some(u32::from_le_bytes([operands[0], operands[1], operands[2], 0]) as usize)
+            }
+            _ => None,
+        };
+        if let Some(idx) = constant {
+            let Some(value) = constants.get(idx) else {
+                return Err(error(offset, VerifyErrorKind::ConstantOutOfRange(idx)));
+            };
+            // Variables and methods are looked up by a name
+            let is_name = matches!(
+                opcode,
+                OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal | OpCode::Invoke
+            );
+            if is_name && value.as_str().is_none() {
+                return Err(error(offset, VerifyErrorKind::NotAName(idx)));
+            }
+        }
+
+        boundaries[offset] = true;
+        instructions.push((offset, opcode));
+        offset += 1 + opcode.operand_len();
+    }
+
+    // Walk every path through the code, tracking how many values the call has on the stack. The
+    // first slot holds the callee, followed by the arguments.
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, 1 + arity)];
+    while let Some((offset, depth)) = pending.pop() {
+        // Running past the end of the code returns `nil`, whatever is on the stack
+        if offset == code.len() {
+            continue;
+        }
+        match depths[offset] {
+            Some(expected) if expected != depth => {
+                return Err(error(
+                    offset,
+                    VerifyErrorKind::StackMismatch {
+                        expected,
+                        found: depth,
+                    },
+                ));
+            }
+            Some(_) => continue,
+            None => depths[offset] = Some(depth),
+        }
+
+        let opcode = OpCode::from(code[offset]);
+        let operand = |idx: usize| code[offset + 1 + idx];
+        // Number of values the instruction pops, and then pushes
+        let (pops, pushes) = match opcode {
+            OpCode::Return => (1, 0),
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetLocal
+            | OpCode::GetLocal0
+            | OpCode::GetLocal1
+            | OpCode::GetLocal2
+            | OpCode::GetLocal3
+            | OpCode::IncrementLocal
+            | OpCode::JumpIfNotLessLocal => (0, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::SetGlobal
+            | OpCode::SetLocal
+            | OpCode::AddConstant => (1, 1),
+            OpCode::JumpIfFalse => (1, 1),
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less => (2, 1),
+            OpCode::Pop | OpCode::DefineGlobal | OpCode::Print => (1, 0),
+            // The callee, or the receiver, sits below the arguments
+            OpCode::Call => (usize::from(operand(0)) + 1, 1),
+            OpCode::Invoke => (usize::from(operand(1)) + 1, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Unknown(_) => unreachable!("Unknown opcodes were rejected while decoding"),
+        };
+        // Popping the callee's slot would leave the call frame without a base
+        if depth < pops + 1 {
+            return Err(error(offset, VerifyErrorKind::StackUnderflow));
+        }
+        let slot = match opcode {
+            OpCode::GetLocal | OpCode::SetLocal => Some(operand(0)),
+            OpCode::IncrementLocal | OpCode::JumpIfNotLessLocal => Some(operand(1)),
+            OpCode::GetLocal0 => Some(0),
+            OpCode::GetLocal1 => Some(1),
+            OpCode::GetLocal2 => Some(2),
+            OpCode::GetLocal3 => Some(3),
+            _ => None,
+        };
+        if let Some(slot) = slot {
+            if usize::from(slot) >= depth {
+                return Err(error(offset, VerifyErrorKind::LocalOutOfRange(slot)));
+            }
+        }
+        let depth = depth - pops + pushes;
+
+        let next = offset + 1 + opcode.operand_len();
+        if opcode.is_jump() {
+            let jump = usize::from(sequence.read_u16(next - 2));
+            let target = match opcode {
+                OpCode::Loop => next.checked_sub(jump),
+                _ => next.checked_add(jump),
+            };
+            let target = match target {
+                Some(target) if target <= code.len() => target,
+                _ => return Err(error(offset, VerifyErrorKind::JumpOutOfBounds)),
+            };
+            if target < code.len() && !boundaries[target] {
+                return Err(error(offset, VerifyErrorKind::JumpIntoInstruction(target)));
+            }
+            pending.push((target, depth));
+        }
+        if !matches!(opcode, OpCode::Return | OpCode::Jump | OpCode::Loop) {
+            pending.push((next, depth));
+        }
+    }
+
+    // Nested functions are verified on their own, as they run in their own call frames. They
+    // keep the code they run, such that calling them does not verify them again
+    for constant in constants {
+        if let Some(Obj::Function(function)) = constant.as_obj() {
+            function.runnable_code()?;
+        }
+    }
+    Ok(())
+}
+
+/// Malformed bytecode, found by `Sequence::verify`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    // Name of the function holding the bytecode, `None` for the top level code of a script
+    function: Option<String>,
+    // Offset of the faulty instruction
+    offset: usize,
+    kind: VerifyErrorKind,
+}
+
+impl VerifyError {
+    pub fn function(&self) -> Option<&str> {
+        self.function.as_deref()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn kind(&self) -> &VerifyErrorKind {
+        &self.kind
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    // The byte does not encode any instruction
+    UnknownOpcode(u8),
+    // The code ends before the instruction's operands
+    Truncated,
+    // The instruction refers to a constant which does not exist
+    ConstantOutOfRange(usize),
+    // The instruction expects the constant to be the name of a variable or a method
+    NotAName(usize),
+    // The instruction refers to a stack slot above the top of the stack
+    LocalOutOfRange(u8),
+    // The jump lands outside of the code
+    JumpOutOfBounds,
+    // The jump lands in the middle of an instruction
+    JumpIntoInstruction(usize),
+    // The instruction pops more values than the stack holds
+    StackUnderflow,
+    // Paths reach the instruction with different numbers of values on the stack
+    StackMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match &self.function {
+            Some(name) => write!(f, "In <fn {name}> at offset {}: ", self.offset)?,
+            None => write!(f, "In <script> at offset {}: ", self.offset)?,
+        }
+        match &self.kind {
+            VerifyErrorKind::UnknownOpcode(byte) => write!(f, "Unknown opcode {byte}."),
+            VerifyErrorKind::Truncated => write!(f, "Instruction is missing its operands."),
+            VerifyErrorKind::ConstantOutOfRange(idx) => write!(f, "No constant at index {idx}."),
+            VerifyErrorKind::NotAName(idx) => write!(f, "Constant {idx} is not a name."),
+            VerifyErrorKind::LocalOutOfRange(slot) => {
+                write!(f, "Local slot {slot} is above the top of the stack.")
+            }
+            VerifyErrorKind::JumpOutOfBounds => write!(f, "Jump lands outside of the code."),
+            VerifyErrorKind::JumpIntoInstruction(target) => {
+                write!(f, "Jump lands inside the instruction at {target}.")
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "Stack underflow."),
+            VerifyErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "Reached with {found} values on the stack, but {expected} on another path."
+            ),
+        }
+    }
+}
+
+impl Error for VerifyError {}
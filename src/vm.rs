@@ -1,100 +1,357 @@
+use crate::object::{Function, Native, NativeFn, Obj};
 use crate::InterpretError;
-use crate::{Disassembler, OpCode, Sequence, Value};
-use std::collections::LinkedList;
-
-// Flag enabling/disabling VM execution tracing for debugging
-const DEBUG_TRACE_EXECUTION: bool = false;
-
-pub struct VM<'vm> {
-    // Sequence of bytecode that the VM executes
-    sequence: &'vm Sequence,
-    // Offset to the byte opcode that needs executing.
-    // Note: This type of variable is desired to be kept in a local variable. This is because it
-    // gets modified so often during execution that we want the compiler to store it in a register.
-    // Note: Allegedly in C, it would be faster to dereference a pointer than look up an element in
-    // an array by index. For the Rust case, the compiler makes use of instructions that do pointer
-    // math and dereferencing in 1 or 2 cycles (like LEA on x86) so this claim does not hold
-    offset: usize,
+use crate::{native, OpCode, Sequence, TraceStep, Tracer, Value, WriteTracer};
+use std::{collections::HashMap, fmt, io::Write, rc::Rc};
+
+// Maximum number of nested calls, after which we report a stack overflow
+const FRAMES_MAX: usize = 64;
+
+// A function call in progress
+pub(crate) struct CallFrame {
+    // Function being executed
+    pub(crate) function: Rc<Function>,
+    // Offset to the byte opcode that needs executing. The innermost call keeps it in a raw pointer
+    // local to the dispatch loop instead, which the compiler can keep in a register. It is only
+    // written back here while the call waits for a callee to return
+    pub(crate) offset: usize,
+    // Index of the first stack slot the call can use. It holds the callee, followed by the
+    // arguments and then by the rest of the function's locals
+    pub(crate) base: usize,
+}
+
+pub struct VM {
+    // Calls in progress, the innermost being the last one
+    frames: Vec<CallFrame>,
     // Stack that holds the operators needed to perform any of the VM's operations.
-    stack: LinkedList<Value>,
+    stack: Vec<Value>,
+    // Global variables, referred to by name
+    globals: HashMap<String, Value>,
+    // Receives each instruction before it is executed, if tracing is enabled
+    tracer: Option<Box<dyn Tracer>>,
+    // Receives what scripts print, instead of the standard output
+    output: Option<Box<dyn Write>>,
+    // Whether to count the instructions executed, and how many were so far
+    count_instructions: bool,
+    instruction_count: u64,
 }
 
-impl<'vm> VM<'vm> {
-    pub fn new(sequence: &'vm Sequence) -> Self {
-        Self {
-            sequence,
-            offset: 0,
-            stack: LinkedList::new(),
+impl VM {
+    pub fn new() -> Self {
+        let mut vm = Self {
+            frames: Vec::new(),
+            stack: Vec::new(),
+            globals: HashMap::new(),
+            tracer: None,
+            output: None,
+            count_instructions: false,
+            instruction_count: 0,
+        };
+        vm.define_builtins();
+        vm
+    }
+
+    // Every VM comes with the builtin natives
+    fn define_builtins(&mut self) {
+        for (name, arity, function) in native::BUILTINS {
+            self.define_native(name, arity, function);
+        }
+    }
+
+    /// Brings the VM back to its initial state, dropping every global and registered native.
+    /// Fails while a call is in progress, as when natives try it, since the calls would be left
+    /// without their stack
+    pub fn reset(&mut self) -> Result<(), RuntimeError> {
+        if !self.frames.is_empty() {
+            return Err(RuntimeError::new(
+                "Cannot reset the VM while a script is running.",
+            ));
+        }
+        self.reset_stack();
+        self.globals.clear();
+        self.define_builtins();
+        Ok(())
+    }
+
+    /// Enables or disables writing the stack and each instruction to the standard error before
+    /// executing it. Enabling it replaces any other tracer
+    pub fn set_trace_execution(&mut self, trace_execution: bool) {
+        if trace_execution {
+            self.set_tracer(WriteTracer::stderr());
+        } else {
+            self.tracer = None;
+        }
+    }
+
+    /// Hands each instruction to `tracer` before executing it, from the next time the VM starts
+    /// running code on
+    pub fn set_tracer<T: Tracer + 'static>(&mut self, tracer: T) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Stops tracing, handing the tracer back
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    /// Writes what scripts print to `output`, instead of the standard output
+    pub fn set_output<W: Write + 'static>(&mut self, output: W) {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Prints to the standard output again, handing the previous output back
+    pub fn take_output(&mut self) -> Option<Box<dyn Write>> {
+        self.output.take()
+    }
+
+    /// Enables or disables counting the instructions executed, which `instruction_count` reports.
+    /// Enabling it resets the count
+    pub fn set_count_instructions(&mut self, count_instructions: bool) {
+        self.count_instructions = count_instructions;
+        self.instruction_count = 0;
+    }
+
+    /// Number of instructions executed since counting was enabled
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Registers `function` as a global called `name`, such that scripts can call it with exactly
+    /// `arity` arguments
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.define_global(
+            name,
+            Value::from(Obj::Native(Native::new(name, arity, function))),
+        );
+    }
+
+    /// Defines or overwrites the global variable `name`
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Returns the value of the global variable `name`, if it is defined
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Iterates over the defined global variables, in no particular order
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Interprets the sequence of bytes passed to the VM. The sequence is copied and verified on
+    /// every call, so code run more than once is better kept as a `Function` given to
+    /// `VM::run_function`, which verifies it the first time only
+    pub fn interpret(&mut self, sequence: &Sequence) -> Result<(), InterpretError> {
+        // The sequence is verified before running, along with the functions it declares
+        let script = Function::new(None, 0, sequence.clone());
+        self.run_function(Rc::new(script))?;
+        Ok(())
+    }
+
+    /// Runs the top level code of a script, returning the value it evaluates to
+    pub fn run_function(&mut self, function: Rc<Function>) -> Result<Value, InterpretError> {
+        self.call(Value::from(Obj::Function(function)), &[])
+    }
+
+    /// Calls `callee` with `args` and waits for it to return. Natives may use this to call back
+    /// into script functions.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, InterpretError> {
+        // Remember where the call starts, such that we can unwind back to it on errors
+        let depth = self.frames.len();
+        let stack_len = self.stack.len();
+
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+        let result = self.call_value(args.len()).and_then(|is_native| {
+            // Natives are done by now, while script functions still need to run
+            if is_native {
+                Ok(())
+            } else {
+                self.run_until(depth)
+            }
+        });
+
+        if let Err(err) = result {
+            self.frames.truncate(depth);
+            self.stack.truncate(stack_len);
+            return Err(err);
         }
+        // The result is left on top of the stack
+        self.pop_stack()
     }
 
-    // Interprets the sequence of bytes passed to the VM
-    pub fn interpret(&mut self, sequence: &'vm Sequence) -> Result<(), InterpretError> {
-        self.sequence = sequence;
+    // Executes instructions until the call frames drop back to `depth`. Tracing and counting
+    // instructions have their own copy of the loop, such that the usual one does not check for
+    // them at every instruction
+    fn run_until(&mut self, depth: usize) -> Result<(), InterpretError> {
+        if self.tracer.is_some() || self.count_instructions {
+            self.run::<true>(depth)
+        } else {
+            self.run::<false>(depth)
+        }
+    }
+
+    fn run<const INSTRUMENTED: bool>(&mut self, depth: usize) -> Result<(), InterpretError> {
+        // The innermost call is run from local variables, which the compiler can keep in
+        // registers. They are loaded again whenever another call becomes the innermost one
+        let mut function;
+        // Start of the code being run, and cursor pointing to the next byte to execute
+        let mut code: *const u8;
+        let mut ip: *const u8;
+        // Index of the first stack slot of the call
+        let mut base;
+        // Start of the instruction being executed, for error reporting
+        let mut start: *const u8;
+
+        macro_rules! load_frame {
+            () => {
+                let frame = self.frame()?;
+                function = frame.function.clone();
+                base = frame.base;
+                code = function
+                    .runnable_code()
+                    .expect("Calls only run verified functions")
+                    .as_ptr();
+                // SAFETY: frames only stop at instructions of their code
+                ip = unsafe { code.add(frame.offset) };
+            };
+        }
+
+        // The reads below go unchecked, as the verifier made sure that instructions are complete,
+        // that their constants exist and that jumps land on instructions. The code ends with an
+        // implicit return, which keeps the cursor within it.
+        macro_rules! read_byte {
+            () => {
+                // SAFETY: see above
+                unsafe {
+                    let byte = *ip;
+                    ip = ip.add(1);
+                    byte
+                }
+            };
+        }
+
+        macro_rules! read_u16 {
+            () => {
+                u16::from_le_bytes([read_byte!(), read_byte!()])
+            };
+        }
+
+        macro_rules! read_constant {
+            () => {{
+                let idx = usize::from(read_byte!());
+                // SAFETY: see above
+                unsafe { function.sequence().constants().get_unchecked(idx) }
+            }};
+        }
+
+        // Names of globals and methods are strings, which the verifier made sure of
+        macro_rules! read_name {
+            () => {
+                read_constant!()
+                    .as_str()
+                    .expect("Verified names are strings")
+            };
+        }
+
+        macro_rules! offset {
+            ($ptr:expr) => {
+                // SAFETY: both pointers are within the same code
+                unsafe { $ptr.offset_from(code) as usize }
+            };
+        }
+
+        // Unwraps the result of a fallible operation, returning errors along with the line of the
+        // instruction that failed
+        macro_rules! throw {
+            ($err:expr) => {
+                return Err(locate($err.into(), &function, offset!(start)))
+            };
+        }
 
-        macro_rules! expr {
-            // Evaluates a given expression. This is used in conjunction with the below rule
-            ($e:expr) => {
-                $e
+        macro_rules! tri {
+            ($result:expr) => {
+                match $result {
+                    Ok(value) => value,
+                    Err(err) => throw!(err),
+                }
             };
         }
 
         macro_rules! binary_op {
             // This macro pops the top 2 elements from the VM's stack and applies the `operator`
-            // between them. The result is then pushed on the stack
+            // between them. The result replaces them on the stack. Both operands must be numbers
             ($operator:tt) => {
-                let right = self.pop_stack()?;
-                let left = self.pop_stack()?;
-                let result = expr!(left $operator right);
-                self.stack.push_back(result);
-            }
+                let right = tri!(self.pop_stack());
+                let top = tri!(self.peek_stack_mut());
+                let (Some(left), Some(right)) = (top.as_number(), right.as_number()) else {
+                    throw!(numbers_error());
+                };
+                *top = Value::from(left $operator right);
+            };
         }
 
+        load_frame!();
         loop {
-            // If we reached the end of the sequence, break
-            if self.offset == self.sequence.code().len() {
-                break;
-            }
-            // If we want to trace the debugging
-            if DEBUG_TRACE_EXECUTION {
-                // Headline for the stack
-                println!("== Stack conttents ==");
-                // Print the stack contents
-                for value in self.stack.iter() {
-                    println!("[{}]", value);
-                }
-                println!("== Current instruction ==");
-                // We disassemble the instruction at the current point
-                Disassembler::dis_instruction(sequence, self.offset);
+            start = ip;
+            if INSTRUMENTED {
+                let offset = offset!(ip);
+                // The implicit return ending the code is not an instruction of the sequence
+                if offset < function.sequence().code().len() {
+                    self.instruction_count += u64::from(self.count_instructions);
+                    if let Some(tracer) = &mut self.tracer {
+                        let step = TraceStep::new(
+                            &function,
+                            offset,
+                            &self.stack,
+                            &self.frames,
+                            &self.globals,
+                        );
+                        tracer.trace(&step);
+                        if tracer.interrupted() {
+                            return Err(InterpretError::Interrupted);
+                        }
+                    }
+                }
             }
-            // Get the instruction opcode
-            let instruction = self.sequence.code()[self.offset].into();
-            // Get past the opcode
-            self.offset += 1;
-            // Dispatch the instruction
-            match instruction {
+            // SAFETY: instructions start with known opcodes, which the verifier made sure of
+            let opcode = match OpCode::from(read_byte!()) {
+                OpCode::Unknown(_) => unsafe { std::hint::unreachable_unchecked() },
+                opcode => opcode,
+            };
+            match opcode {
                 OpCode::Return => {
-                    // Print the top value from the stack
-                    let value = self.pop_stack()?;
-                    println!("{value}");
-                    return Ok(());
+                    if tri!(self.return_from_call(depth)) {
+                        return Ok(());
+                    }
+                    load_frame!();
                 }
                 OpCode::Constant => {
-                    // Get the constant from the sequence storage
-                    let constant = self.sequence.read_constant(self.offset);
-                    // Push the value's index to the stack to enable the constant in this scope
-                    self.stack.push_back(constant.clone());
-                    // Go past the constant
-                    self.offset += 1;
+                    let constant = read_constant!().clone();
+                    self.stack.push(constant);
+                }
+                OpCode::ConstantLong => {
+                    // Same as above, only the index spans 3 bytes
+                    let idx = u32::from_le_bytes([read_byte!(), read_byte!(), read_byte!(), 0]);
+                    // SAFETY: the verifier made sure the constant exists
+                    let constant =
+                        unsafe { function.sequence().constants().get_unchecked(idx as usize) };
+                    self.stack.push(constant.clone());
                 }
                 OpCode::Negate => {
-                    // Get the top value from the stack and negate it
-                    let value = -self.pop_stack()?;
-                    // Push the new value on the stack
-                    self.stack.push_back(value);
+                    let value = tri!(self.peek_stack_mut());
+                    let Some(number) = value.as_number() else {
+                        throw!(runtime_error("Operand must be a number."));
+                    };
+                    *value = Value::from(-number);
                 }
                 OpCode::Add => {
-                    binary_op!(+);
+                    let right = tri!(self.pop_stack());
+                    let left = tri!(self.peek_stack_mut());
+                    *left = tri!(add(left, &right));
                 }
                 OpCode::Sub => {
                     binary_op!(-);
@@ -105,19 +362,368 @@ impl<'vm> VM<'vm> {
                 OpCode::Div => {
                     binary_op!(/);
                 }
-                _ => todo!(),
+                OpCode::Nil => self.stack.push(Value::nil()),
+                OpCode::True => self.stack.push(Value::from(true)),
+                OpCode::False => self.stack.push(Value::from(false)),
+                OpCode::Pop => {
+                    tri!(self.pop_stack());
+                }
+                OpCode::GetGlobal => {
+                    let name = read_name!();
+                    let Some(value) = self.globals.get(name) else {
+                        throw!(RuntimeError::undefined_variable(name));
+                    };
+                    self.stack.push(value.clone());
+                }
+                OpCode::DefineGlobal => {
+                    let name = read_name!();
+                    let value = tri!(self.pop_stack());
+                    self.globals.insert(name.to_string(), value);
+                }
+                OpCode::SetGlobal => {
+                    let name = read_name!();
+                    // Assignment is an expression, so the value stays on the stack
+                    let value = tri!(self.peek_stack(0)).clone();
+                    let Some(global) = self.globals.get_mut(name) else {
+                        throw!(RuntimeError::undefined_variable(name));
+                    };
+                    *global = value;
+                }
+                OpCode::Equal => {
+                    let right = tri!(self.pop_stack());
+                    let left = tri!(self.peek_stack_mut());
+                    *left = Value::from(*left == right);
+                }
+                OpCode::Greater => {
+                    binary_op!(>);
+                }
+                OpCode::Less => {
+                    binary_op!(<);
+                }
+                OpCode::Not => {
+                    let value = tri!(self.peek_stack_mut());
+                    *value = Value::from(value.is_falsey());
+                }
+                OpCode::Print => {
+                    let value = tri!(self.pop_stack());
+                    match &mut self.output {
+                        // Scripts keep running whether or not what they print could be written
+                        Some(output) => {
+                            let _ = writeln!(output, "{value}");
+                        }
+                        None => println!("{value}"),
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = read_byte!();
+                    // Remember where to resume once the callee returns
+                    tri!(self.frame_mut()).offset = offset!(ip);
+                    if !tri!(self.call_value(usize::from(arg_count))) {
+                        load_frame!();
+                    }
+                }
+                OpCode::GetLocal => {
+                    let slot = base + usize::from(read_byte!());
+                    tri!(self.push_local(slot));
+                }
+                OpCode::SetLocal => {
+                    let slot = base + usize::from(read_byte!());
+                    // Assignment is an expression, so the value stays on the stack
+                    let value = tri!(self.peek_stack(0)).clone();
+                    *tri!(self.local_mut(slot)) = value;
+                }
+                OpCode::JumpIfFalse => {
+                    let jump = read_u16!();
+                    if tri!(self.peek_stack(0)).is_falsey() {
+                        // SAFETY: the verifier made sure jumps land within the code
+                        ip = unsafe { ip.add(usize::from(jump)) };
+                    }
+                }
+                OpCode::Jump => {
+                    let jump = read_u16!();
+                    // SAFETY: the verifier made sure jumps land within the code
+                    ip = unsafe { ip.add(usize::from(jump)) };
+                }
+                OpCode::Loop => {
+                    let jump = read_u16!();
+                    // SAFETY: the verifier made sure jumps land within the code
+                    ip = unsafe { ip.sub(usize::from(jump)) };
+                }
+                OpCode::Invoke => {
+                    let name = read_name!();
+                    let arg_count = read_byte!();
+                    // Methods may call back into script functions, which trace their callers
+                    tri!(self.frame_mut()).offset = offset!(ip);
+                    tri!(self.invoke(name, usize::from(arg_count)));
+                }
+                OpCode::AddConstant => {
+                    let right = read_constant!();
+                    let left = tri!(self.peek_stack_mut());
+                    *left = tri!(add(left, right));
+                }
+                OpCode::GetLocal0 => tri!(self.push_local(base)),
+                OpCode::GetLocal1 => tri!(self.push_local(base + 1)),
+                OpCode::GetLocal2 => tri!(self.push_local(base + 2)),
+                OpCode::GetLocal3 => tri!(self.push_local(base + 3)),
+                OpCode::IncrementLocal => {
+                    let right = read_constant!();
+                    let slot = base + usize::from(read_byte!());
+                    let local = tri!(self.local_mut(slot));
+                    *local = tri!(add(local, right));
+                    let result = local.clone();
+                    self.stack.push(result);
+                }
+                OpCode::JumpIfNotLessLocal => {
+                    let right = read_constant!().as_number();
+                    let slot = base + usize::from(read_byte!());
+                    let jump = read_u16!();
+                    let left = tri!(self.local_mut(slot)).as_number();
+                    let (Some(left), Some(right)) = (left, right) else {
+                        throw!(numbers_error());
+                    };
+                    // The result stays on the stack, as it would after `Less`
+                    let less = left < right;
+                    self.stack.push(Value::from(less));
+                    if !less {
+                        // SAFETY: the verifier made sure jumps land within the code
+                        ip = unsafe { ip.add(usize::from(jump)) };
+                    }
+                }
+                OpCode::Unknown(_) => unreachable!("Unknown opcodes were ruled out above"),
+            }
+        }
+    }
+
+    // Pops the innermost call frame, replacing its stack window with the returned value found on
+    // top of the stack. Returns whether this was the last frame we had to run, at `depth`.
+    fn return_from_call(&mut self, depth: usize) -> Result<bool, InterpretError> {
+        let result = self.pop_stack()?;
+        let frame = self.frames.pop().ok_or(InterpretError::StackEmpty)?;
+        // Discard the callee, its arguments and its locals
+        self.stack.truncate(frame.base);
+        self.stack.push(result);
+        Ok(self.frames.len() == depth)
+    }
+
+    // Calls the value sitting below the `arg_count` arguments on top of the stack. Natives are
+    // run straight away, replacing both the callee and the arguments with their result, while
+    // script functions get a new call frame. Returns whether the callee was a native.
+    fn call_value(&mut self, arg_count: usize) -> Result<bool, InterpretError> {
+        let callee_idx = self
+            .stack
+            .len()
+            .checked_sub(arg_count + 1)
+            .ok_or(InterpretError::StackEmpty)?;
+        match self.stack[callee_idx].as_obj() {
+            Some(Obj::Native(native)) => {
+                let native = native.clone();
+                check_arity(native.arity(), arg_count)?;
+                // Take the arguments and the callee off the stack before handing the VM to the
+                // native
+                let args = self.stack.split_off(callee_idx + 1);
+                self.stack.pop();
+                let result = (native.function())(self, &args)?;
+                self.stack.push(result);
+                Ok(true)
+            }
+            Some(Obj::Function(function)) => {
+                check_arity(function.arity(), arg_count)?;
+                // Functions read from compiled files or built by hand could be anything, so the
+                // VM only runs them once they are verified
+                function.runnable_code()?;
+                if self.frames.len() == FRAMES_MAX {
+                    return Err(RuntimeError::new("Stack overflow.").into());
+                }
+                self.frames.push(CallFrame {
+                    function: function.clone(),
+                    offset: 0,
+                    base: callee_idx,
+                });
+                Ok(false)
             }
+            _ => Err(RuntimeError::new("Can only call functions.").into()),
         }
+    }
+
+    // Invokes the method `name` on the receiver sitting below the `arg_count` arguments on top of
+    // the stack, replacing both the receiver and the arguments with the method's result
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver_idx = self
+            .stack
+            .len()
+            .checked_sub(arg_count + 1)
+            .ok_or(InterpretError::StackEmpty)?;
+        let receiver = self.stack[receiver_idx].clone();
+        let Some(Obj::Userdata(userdata)) = receiver.as_obj() else {
+            return Err(RuntimeError::new("Only userdata have methods.").into());
+        };
+        let Some((arity, method)) = userdata.class().get_method(name) else {
+            return Err(RuntimeError::new(format!(
+                "Undefined method '{}' on {}.",
+                name,
+                userdata.class().name()
+            ))
+            .into());
+        };
+        check_arity(arity, arg_count)?;
+        // Take the arguments and the receiver off the stack before handing the VM to the method.
+        // The receiver is kept alive by our own reference for the duration of the call
+        let args = self.stack.split_off(receiver_idx + 1);
+        self.stack.pop();
+        let result = method(self, userdata, &args)?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    // Innermost call in progress
+    fn frame(&self) -> Result<&CallFrame, InterpretError> {
+        self.frames.last().ok_or(InterpretError::StackEmpty)
+    }
+
+    fn frame_mut(&mut self) -> Result<&mut CallFrame, InterpretError> {
+        self.frames.last_mut().ok_or(InterpretError::StackEmpty)
+    }
+
+    // Pushes the value of the local variable found in stack slot `slot`
+    fn push_local(&mut self, slot: usize) -> Result<(), InterpretError> {
+        let value = self.stack.get(slot).ok_or(InterpretError::StackEmpty)?;
+        self.stack.push(value.clone());
         Ok(())
     }
 
+    fn local_mut(&mut self, slot: usize) -> Result<&mut Value, InterpretError> {
+        self.stack.get_mut(slot).ok_or(InterpretError::StackEmpty)
+    }
+
     pub fn pop_stack(&mut self) -> Result<Value, InterpretError> {
-        self.stack.pop_back().ok_or(InterpretError::StackEmpty)
+        self.stack.pop().ok_or(InterpretError::StackEmpty)
+    }
+
+    // Returns the value found `distance` slots down from the top of the stack
+    fn peek_stack(&self, distance: usize) -> Result<&Value, InterpretError> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .and_then(|idx| self.stack.get(idx))
+            .ok_or(InterpretError::StackEmpty)
+    }
+
+    fn peek_stack_mut(&mut self) -> Result<&mut Value, InterpretError> {
+        self.stack.last_mut().ok_or(InterpretError::StackEmpty)
     }
 
     // Empties the VM's stack
-    pub fn reset_stack(&mut self) {
-        // Pop elements from the stack until is is empty
-        while self.stack.pop_back().is_some() {}
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
     }
 }
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Adds two numbers, or concatenates two strings
+fn add(left: &Value, right: &Value) -> Result<Value, InterpretError> {
+    match (left.as_number(), right.as_number()) {
+        (Some(left), Some(right)) => Ok(Value::from(left + right)),
+        _ => concatenate(left, right),
+    }
+}
+
+#[cold]
+fn concatenate(left: &Value, right: &Value) -> Result<Value, InterpretError> {
+    match (left.as_str(), right.as_str()) {
+        (Some(left), Some(right)) => Ok(Value::from(format!("{left}{right}"))),
+        _ => Err(runtime_error(
+            "Operands must be two numbers or two strings.",
+        )),
+    }
+}
+
+// Errors are rare, so building them is kept out of the way of the instructions raising them
+
+#[cold]
+#[inline(never)]
+fn runtime_error(message: &'static str) -> InterpretError {
+    RuntimeError::new(message).into()
+}
+
+#[cold]
+#[inline(never)]
+fn numbers_error() -> InterpretError {
+    runtime_error("Operands must be numbers.")
+}
+
+// Attaches the line of the instruction at `offset` in `function` to runtime errors
+#[cold]
+#[inline(never)]
+fn locate(err: InterpretError, function: &Function, offset: usize) -> InterpretError {
+    match err {
+        InterpretError::RuntimeError(err) => {
+            InterpretError::RuntimeError(err.at_line(function.sequence().line(offset)))
+        }
+        err => err,
+    }
+}
+
+// Makes sure a function taking `arity` parameters is called with as many arguments
+fn check_arity(arity: usize, arg_count: usize) -> Result<(), RuntimeError> {
+    if arity != arg_count {
+        return Err(RuntimeError::new(format!(
+            "Expected {} arguments but got {}.",
+            arity, arg_count
+        )));
+    }
+    Ok(())
+}
+
+/// An error raised while executing bytecode, either by the VM itself or by a native function
+#[derive(Debug)]
+pub struct RuntimeError {
+    // Description of what went wrong
+    message: String,
+    // Source line of the instruction that raised the error. Natives do not know where they are
+    // called from, so the VM fills this in as the error propagates
+    line: Option<u32>,
+}
+
+impl RuntimeError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            line: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    // Attaches `line` to the error, unless it already knows where it comes from
+    fn at_line(mut self, line: u32) -> Self {
+        self.line.get_or_insert(line);
+        self
+    }
+
+    fn undefined_variable(name: &str) -> Self {
+        Self::new(format!("Undefined variable '{name}'."))
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.line {
+            Some(line) => write!(f, "[line {line}] {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -0,0 +1,41 @@
+//! Checks that `malis` exits with the `sysexits.h` code matching each kind of failure.
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Writes `source` to a script named `name` in the scratch directory of the tests
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, source).expect("Scripts can be written");
+    path
+}
+
+// Exit code of `malis` run with `args`
+fn exit_code<const N: usize>(args: [&str; N]) -> Option<i32> {
+    let output = Command::new(env!("CARGO_BIN_EXE_malis"))
+        .args(args)
+        .output()
+        .expect("malis runs");
+    output.status.code()
+}
+
+#[test]
+fn exit_codes() {
+    let valid = script("exit_codes_valid.ms", "print 1;");
+    let invalid = script("exit_codes_invalid.ms", "print 1 +;");
+    let failing = script("exit_codes_failing.ms", "print -nil;");
+    let valid = valid.to_str().unwrap();
+
+    assert_eq!(exit_code(["run", valid]), Some(0));
+    assert_eq!(exit_code(["compile"]), Some(64));
+    assert_eq!(exit_code(["run", invalid.to_str().unwrap()]), Some(65));
+    assert_eq!(exit_code(["run", failing.to_str().unwrap()]), Some(70));
+    let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("missing/valid.msc");
+    let output = output.to_str().unwrap();
+    assert_eq!(exit_code(["compile", valid, "-o", output]), Some(73));
+    assert_eq!(
+        exit_code(["--trace=/does/not/exist/trace", valid]),
+        Some(73)
+    );
+    assert_eq!(exit_code(["run", "does/not/exist.ms"]), Some(74));
+}